@@ -0,0 +1,332 @@
+use std::array;
+use std::ops::{Add, Div, Mul, Rem, Sub};
+
+use crate::math::number::Number;
+use crate::math::vector::{Vector, VectorView};
+
+/// A value along with its derivative in one direction.
+/// Evaluating any `Number` code on duals computes the derivative exactly, in forward mode.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dual {
+    pub value: f32,
+    pub derivative: f32,
+}
+
+/// A value along with its derivatives in `LANES` directions, so that several partial derivatives
+/// come out of one evaluation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Duals<const LANES: usize> {
+    pub value: f32,
+    pub derivatives: [f32; LANES],
+}
+
+impl<const LANES: usize> Duals<LANES> {
+    /// The variable whose derivative is tracked in lane `lane`
+    pub fn variable(value: f32, lane: usize) -> Self {
+        let mut derivatives = [0.0; LANES];
+        derivatives[lane] = 1.0;
+        Self { value, derivatives }
+    }
+
+    fn with_derivatives(value: f32, derivative: impl FnMut(usize) -> f32) -> Self {
+        Self {
+            value,
+            derivatives: array::from_fn(derivative),
+        }
+    }
+}
+
+impl From<f32> for Dual {
+    fn from(value: f32) -> Self {
+        Self {
+            value,
+            derivative: 0.0,
+        }
+    }
+}
+
+impl<const LANES: usize> From<f32> for Duals<LANES> {
+    fn from(value: f32) -> Self {
+        Self {
+            value,
+            derivatives: [0.0; LANES],
+        }
+    }
+}
+
+impl Number for Dual {
+    fn if_positive(self, consequent: Self, alternative: Self) -> Self {
+        if self.value >= 0.0 {
+            consequent
+        } else {
+            alternative
+        }
+    }
+}
+
+impl<const LANES: usize> Number for Duals<LANES> {
+    fn if_positive(self, consequent: Self, alternative: Self) -> Self {
+        if self.value >= 0.0 {
+            consequent
+        } else {
+            alternative
+        }
+    }
+}
+
+impl Add<Dual> for Dual {
+    type Output = Dual;
+
+    fn add(self, rhs: Dual) -> Self::Output {
+        Dual {
+            value: self.value + rhs.value,
+            derivative: self.derivative + rhs.derivative,
+        }
+    }
+}
+
+impl Sub<Dual> for Dual {
+    type Output = Dual;
+
+    fn sub(self, rhs: Dual) -> Self::Output {
+        Dual {
+            value: self.value - rhs.value,
+            derivative: self.derivative - rhs.derivative,
+        }
+    }
+}
+
+impl Mul<Dual> for Dual {
+    type Output = Dual;
+
+    fn mul(self, rhs: Dual) -> Self::Output {
+        Dual {
+            value: self.value * rhs.value,
+            derivative: self.derivative * rhs.value + self.value * rhs.derivative,
+        }
+    }
+}
+
+impl Div<Dual> for Dual {
+    type Output = Dual;
+
+    fn div(self, rhs: Dual) -> Self::Output {
+        let value = self.value / rhs.value;
+        Dual {
+            value,
+            derivative: (self.derivative - value * rhs.derivative) / rhs.value,
+        }
+    }
+}
+
+impl Rem<Dual> for Dual {
+    type Output = Dual;
+
+    /// `a % b = a - trunc(a / b) * b`, where the truncated quotient is locally constant
+    fn rem(self, rhs: Dual) -> Self::Output {
+        let quotient = (self.value / rhs.value).trunc();
+        Dual {
+            value: self.value % rhs.value,
+            derivative: self.derivative - quotient * rhs.derivative,
+        }
+    }
+}
+
+impl<const LANES: usize> Add<Duals<LANES>> for Duals<LANES> {
+    type Output = Duals<LANES>;
+
+    fn add(self, rhs: Duals<LANES>) -> Self::Output {
+        Duals::with_derivatives(self.value + rhs.value, |lane| {
+            self.derivatives[lane] + rhs.derivatives[lane]
+        })
+    }
+}
+
+impl<const LANES: usize> Sub<Duals<LANES>> for Duals<LANES> {
+    type Output = Duals<LANES>;
+
+    fn sub(self, rhs: Duals<LANES>) -> Self::Output {
+        Duals::with_derivatives(self.value - rhs.value, |lane| {
+            self.derivatives[lane] - rhs.derivatives[lane]
+        })
+    }
+}
+
+impl<const LANES: usize> Mul<Duals<LANES>> for Duals<LANES> {
+    type Output = Duals<LANES>;
+
+    // the product rule
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn mul(self, rhs: Duals<LANES>) -> Self::Output {
+        Duals::with_derivatives(self.value * rhs.value, |lane| {
+            self.derivatives[lane] * rhs.value + self.value * rhs.derivatives[lane]
+        })
+    }
+}
+
+impl<const LANES: usize> Div<Duals<LANES>> for Duals<LANES> {
+    type Output = Duals<LANES>;
+
+    // the quotient rule
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, rhs: Duals<LANES>) -> Self::Output {
+        let value = self.value / rhs.value;
+        Duals::with_derivatives(value, |lane| {
+            (self.derivatives[lane] - value * rhs.derivatives[lane]) / rhs.value
+        })
+    }
+}
+
+impl<const LANES: usize> Rem<Duals<LANES>> for Duals<LANES> {
+    type Output = Duals<LANES>;
+
+    /// `a % b = a - trunc(a / b) * b`, where the truncated quotient is locally constant
+    fn rem(self, rhs: Duals<LANES>) -> Self::Output {
+        let quotient = (self.value / rhs.value).trunc();
+        Duals::with_derivatives(self.value % rhs.value, |lane| {
+            self.derivatives[lane] - quotient * rhs.derivatives[lane]
+        })
+    }
+}
+
+/// The number of partial derivatives `gradient` computes per evaluation
+pub const LANES: usize = 8;
+
+/// The gradient of `function` at `point`, evaluating it once for every `LANES` variables
+pub fn gradient(
+    point: VectorView<f32>,
+    function: impl Fn(VectorView<Duals<LANES>>) -> Duals<LANES>,
+) -> Vector<f32> {
+    let mut gradient = Vec::with_capacity(point.len());
+
+    for start in (0..point.len()).step_by(LANES) {
+        let variables: Vec<_> = point
+            .iter()
+            .enumerate()
+            .map(|(index, value)| match index.checked_sub(start) {
+                Some(lane) if lane < LANES => Duals::variable(*value, lane),
+                _ => Duals::from(*value),
+            })
+            .collect();
+
+        let result = function(&variables);
+        let lanes = LANES.min(point.len() - start);
+        gradient.extend_from_slice(&result.derivatives[..lanes]);
+    }
+
+    gradient
+}
+
+/// The derivative of `function` at `point` in the direction `direction`
+pub fn directional_derivative(
+    point: VectorView<f32>,
+    direction: VectorView<f32>,
+    function: impl Fn(VectorView<Dual>) -> Dual,
+) -> f32 {
+    let variables: Vec<_> = point
+        .iter()
+        .zip(direction)
+        .map(|(value, derivative)| Dual {
+            value: *value,
+            derivative: *derivative,
+        })
+        .collect();
+
+    function(&variables).derivative
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::harness::generate::spread_values;
+    use crate::math::vector::{squared_mag, sub};
+    use crate::neurons::{learning::layer, neuron::Neuron};
+
+    fn numeric_gradient(
+        point: VectorView<f32>,
+        function: impl Fn(VectorView<f32>) -> f32,
+    ) -> Vec<f32> {
+        const EPSILON: f32 = 0.001;
+        (0..point.len())
+            .map(|index| {
+                let mut above = point.to_vec();
+                let mut below = point.to_vec();
+                above[index] += EPSILON;
+                below[index] -= EPSILON;
+                (function(&above) - function(&below)) / (2.0 * EPSILON)
+            })
+            .collect()
+    }
+
+    /// A variable that is being differentiated with respect to
+    fn variable(value: f32) -> Dual {
+        Dual {
+            value,
+            derivative: 1.0,
+        }
+    }
+
+    fn error<T: Number>(neuron: &impl Neuron, data: VectorView<T>) -> T {
+        let input = [T::from(0.5), T::from(-0.25)];
+        let target = [T::from(0.75)];
+        squared_mag(&sub(&neuron.evaluate(&input, data), &target))
+    }
+
+    #[test]
+    fn arithmetic() {
+        let x = variable(3.0);
+        let y = Dual::from(2.0);
+
+        // d/dx (x * y + x / y - x % y) = y + 1 / y - 1
+        let result = x * y + x / y - x % y;
+        assert_eq!(result.value, 6.0 + 1.5 - 1.0);
+        assert_eq!(result.derivative, 2.0 + 0.5 - 1.0);
+
+        // the branch that is taken is the one that's differentiated
+        assert_eq!(x.if_positive(x * x, y).derivative, 6.0);
+        assert_eq!((y - x).if_positive(x * x, x / y).derivative, 0.5);
+    }
+
+    #[test]
+    fn lanes_match_single_derivatives() {
+        let point = [0.5, -1.5, 2.0];
+        let polynomial = |x: &[Duals<3>]| x[0] * x[1] * x[1] - x[2] / x[0];
+        let single = |x: &[Dual]| x[0] * x[1] * x[1] - x[2] / x[0];
+
+        let variables: Vec<_> = point
+            .iter()
+            .enumerate()
+            .map(|(lane, value)| Duals::variable(*value, lane))
+            .collect();
+        let batched = polynomial(&variables);
+
+        for lane in 0..3 {
+            let mut direction = [0.0; 3];
+            direction[lane] = 1.0;
+            assert_eq!(
+                batched.derivatives[lane],
+                directional_derivative(&point, &direction, single)
+            );
+        }
+    }
+
+    #[test]
+    fn matches_numeric_gradient() {
+        let neuron = layer(2, 4).compose(layer(4, 3)).compose(layer(3, 1));
+        let data = spread_values(neuron.size().data, 0);
+
+        let exact = gradient(&data, |data| error(&neuron, data));
+        let numeric = numeric_gradient(&data, |data| error(&neuron, data));
+
+        assert_eq!(exact.len(), data.len());
+        for (parameter, (exact, numeric)) in exact.iter().zip(&numeric).enumerate() {
+            assert!(
+                (exact - numeric).abs() <= 0.01 * exact.abs().max(1.0),
+                "parameter {}: {} by finite differences, {} by dual numbers",
+                parameter,
+                numeric,
+                exact
+            );
+        }
+    }
+}
@@ -0,0 +1,2 @@
+pub mod dual;
+pub mod reverse;
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::expr::{Expr, Identity, Operator};
+use crate::math::number::Number;
+
+/// Every distinct node reachable from `expr`, each after all of the nodes it uses
+fn postorder<'a>(expr: &'a Expr, visited: &mut HashSet<Identity>, order: &mut Vec<&'a Expr>) {
+    let Some(key) = expr.identity() else {
+        return;
+    };
+    if !visited.insert(key) {
+        return;
+    }
+
+    match expr {
+        Expr::Operation { operands, .. } => {
+            postorder(&operands[0], visited, order);
+            postorder(&operands[1], visited, order);
+        }
+        Expr::IfPositive(if_positive) => {
+            postorder(&if_positive.predicate, visited, order);
+            postorder(&if_positive.consequent, visited, order);
+            postorder(&if_positive.alternative, visited, order);
+        }
+        Expr::Variable(_) | Expr::Number(_) => {}
+    }
+
+    order.push(expr);
+}
+
+/// `first * second`, leaving out multiplications by 1, which never change their other operand
+fn product(first: &Expr, second: &Expr) -> Expr {
+    match (first, second) {
+        (Expr::Number(one), other) | (other, Expr::Number(one)) if *one == 1.0 => other.clone(),
+        _ => first.clone() * second.clone(),
+    }
+}
+
+#[derive(Default)]
+struct Adjoints {
+    adjoints: HashMap<Identity, Expr>,
+}
+
+impl Adjoints {
+    fn get(&self, expr: &Expr) -> Option<&Expr> {
+        expr.identity().and_then(|key| self.adjoints.get(&key))
+    }
+
+    fn add(&mut self, expr: &Expr, term: Expr) {
+        if let Some(key) = expr.identity() {
+            let adjoint = match self.adjoints.remove(&key) {
+                Some(adjoint) => adjoint + term,
+                None => term,
+            };
+            self.adjoints.insert(key, adjoint);
+        }
+    }
+
+    fn subtract(&mut self, expr: &Expr, term: Expr) {
+        if let Some(key) = expr.identity() {
+            let adjoint = self.adjoints.remove(&key).unwrap_or(Expr::Number(0.0)) - term;
+            self.adjoints.insert(key, adjoint);
+        }
+    }
+}
+
+/// The partial derivatives of `expr` with respect to each of `variables`.
+///
+/// The gradient is built in reverse mode: adjoints flow from the output back to the variables,
+/// visiting every shared subexpression once. The resulting expressions share subexpressions with
+/// `expr` and with each other through `Rc`, so their total number of distinct nodes is linear in
+/// the number of distinct nodes of `expr`.
+///
+/// `IfPositive` is treated as a selection, so its derivative is the derivative of the branch it
+/// takes, and nothing flows to the predicate.
+pub fn gradient(expr: &Expr, variables: &[usize]) -> Vec<Expr> {
+    let mut order = Vec::new();
+    postorder(expr, &mut HashSet::new(), &mut order);
+
+    let mut adjoints = Adjoints::default();
+    adjoints.add(expr, Expr::Number(1.0));
+
+    // every node comes before the nodes it uses, so its adjoint is complete when it is visited
+    for expr in order.into_iter().rev() {
+        let Some(adjoint) = adjoints.get(expr).cloned() else {
+            continue;
+        };
+
+        match expr {
+            Expr::Operation { operator, operands } => {
+                let [first, second] = &**operands;
+                match operator {
+                    Operator::Add => {
+                        adjoints.add(first, adjoint.clone());
+                        adjoints.add(second, adjoint);
+                    }
+                    Operator::Subtract => {
+                        adjoints.add(first, adjoint.clone());
+                        adjoints.subtract(second, adjoint);
+                    }
+                    Operator::Multiply => {
+                        adjoints.add(first, product(&adjoint, second));
+                        adjoints.add(second, product(&adjoint, first));
+                    }
+                    Operator::Divide => {
+                        // d(a / b) = da / b - (a / b) db / b, reusing the quotient itself
+                        adjoints.add(first, adjoint.clone() / second.clone());
+                        adjoints.subtract(second, product(&adjoint, expr) / second.clone());
+                    }
+                }
+            }
+            Expr::IfPositive(if_positive) => {
+                let predicate = &if_positive.predicate;
+                adjoints.add(
+                    &if_positive.consequent,
+                    predicate
+                        .clone()
+                        .if_positive(adjoint.clone(), Expr::Number(0.0)),
+                );
+                adjoints.add(
+                    &if_positive.alternative,
+                    predicate.clone().if_positive(Expr::Number(0.0), adjoint),
+                );
+            }
+            Expr::Variable(_) | Expr::Number(_) => {}
+        }
+    }
+
+    variables
+        .iter()
+        .map(|variable| {
+            adjoints
+                .adjoints
+                .get(&Identity::Variable(*variable))
+                .cloned()
+                .unwrap_or(Expr::Number(0.0))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval;
+    use crate::harness::differential::check;
+    use crate::harness::generate::{spread_values, symbolic_outputs, Case};
+    use crate::math::vector::{squared_mag, sub};
+    use crate::neurons::{learning::layer, neuron::Neuron};
+
+    fn evaluate(expr: &Expr, values: &[f32]) -> f32 {
+        eval::expr::evaluate(expr, &values.iter().copied().enumerate().collect())
+    }
+
+    /// The number of distinct nodes reachable from any of `exprs`
+    fn distinct_nodes(exprs: &[Expr]) -> usize {
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+        for expr in exprs {
+            postorder(expr, &mut visited, &mut order);
+        }
+        order.len()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let x = || Expr::Variable(0);
+        let y = || Expr::Variable(1);
+        let expr = x() * y() + x() / y() - y();
+        let gradient = gradient(&expr, &[0, 1]);
+
+        // d/dx = y + 1 / y, d/dy = x - x / y^2 - 1
+        assert_eq!(evaluate(&gradient[0], &[3.0, 2.0]), 2.5);
+        assert_eq!(evaluate(&gradient[1], &[3.0, 2.0]), 1.25);
+    }
+
+    #[test]
+    fn if_positive() {
+        let x = || Expr::Variable(0);
+        let expr = x().if_positive(x() * x(), x() / Expr::Number(2.0));
+        let gradient = gradient(&expr, &[0, 1]);
+
+        assert_eq!(evaluate(&gradient[0], &[3.0]), 6.0);
+        assert_eq!(evaluate(&gradient[0], &[-3.0]), 0.5);
+        // a variable that doesn't appear has a derivative of 0
+        assert_eq!(evaluate(&gradient[1], &[3.0]), 0.0);
+    }
+
+    /// The squared error of `neuron`, with its data followed by its input and target as variables
+    fn network_error(neuron: impl Neuron) -> (Expr, usize, usize) {
+        let (output, variables) = symbolic_outputs(&neuron);
+        let target = [Expr::Variable(variables)];
+        let error = squared_mag(&sub(&output, &target));
+        (error, neuron.size().data, variables + 1)
+    }
+
+    #[test]
+    fn matches_finite_differences() {
+        let (error, parameters, variables) =
+            network_error(layer(2, 3).compose(layer(3, 3)).compose(layer(3, 1)));
+        let values = spread_values(variables, 0);
+        let gradient = gradient(&error, &(0..parameters).collect::<Vec<_>>());
+
+        const EPSILON: f32 = 0.001;
+        for (parameter, derivative) in gradient.iter().enumerate() {
+            let mut above = values.clone();
+            let mut below = values.clone();
+            above[parameter] += EPSILON;
+            below[parameter] -= EPSILON;
+            let estimate = (evaluate(&error, &above) - evaluate(&error, &below)) / (2.0 * EPSILON);
+            let exact = evaluate(derivative, &values);
+
+            assert!(
+                (estimate - exact).abs() <= 0.01 * exact.abs().max(1.0),
+                "parameter {}: {} by finite differences, {} by differentiation",
+                parameter,
+                estimate,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn gradient_shares_forward() {
+        let (error, parameters, _) =
+            network_error(layer(2, 3).compose(layer(3, 3)).compose(layer(3, 1)));
+        let gradient = gradient(&error, &(0..parameters).collect::<Vec<_>>());
+
+        let forward = distinct_nodes(std::slice::from_ref(&error));
+        let mut everything = gradient.clone();
+        everything.push(error);
+
+        assert!(
+            distinct_nodes(&everything) <= 8 * forward,
+            "{} distinct nodes in the gradient of {}",
+            distinct_nodes(&everything),
+            forward
+        );
+    }
+
+    #[test]
+    fn gradient_compiles() {
+        let (error, parameters, variables) =
+            network_error(layer(2, 3).compose(layer(3, 3)).compose(layer(3, 1)));
+        let values: Vec<f32> = (0..variables)
+            .map(|i| ((i * 5) % 13) as f32 / 6.5 - 1.0)
+            .collect();
+
+        // the derivatives share the forward pass, so they are compiled together
+        let case = Case {
+            exprs: gradient(&error, &(0..parameters).collect::<Vec<_>>()),
+            input: values,
+        };
+        if let Err(mismatch) = check(&case) {
+            panic!("evaluation paths disagree on\n{:?}", mismatch);
+        }
+    }
+}
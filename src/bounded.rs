@@ -1,21 +1,21 @@
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Bounded<const Low: i64, const High: i64> {
+pub struct Bounded<const LOW: i64, const HIGH: i64> {
     value: i64,
 }
 
 #[derive(Debug)]
-pub enum OutOfBounds<const Low: i64, const High: i64> {
+pub enum OutOfBounds<const LOW: i64, const HIGH: i64> {
     TooHigh,
     TooLow,
 }
 
-impl<const Low: i64, const High: i64> TryFrom<i64> for Bounded<Low, High> {
-    type Error = OutOfBounds<Low, High>;
+impl<const LOW: i64, const HIGH: i64> TryFrom<i64> for Bounded<LOW, HIGH> {
+    type Error = OutOfBounds<LOW, HIGH>;
 
     fn try_from(value: i64) -> Result<Self, Self::Error> {
-        if value > High {
+        if value > HIGH {
             Err(Self::Error::TooHigh)
-        } else if value < Low {
+        } else if value < LOW {
             Err(Self::Error::TooLow)
         } else {
             Ok(Bounded { value })
@@ -23,8 +23,8 @@ impl<const Low: i64, const High: i64> TryFrom<i64> for Bounded<Low, High> {
     }
 }
 
-impl<const Low: i64, const High: i64> Into<i64> for Bounded<Low, High> {
-    fn into(self) -> i64 {
-        self.value
+impl<const LOW: i64, const HIGH: i64> From<Bounded<LOW, HIGH>> for i64 {
+    fn from(bounded: Bounded<LOW, HIGH>) -> Self {
+        bounded.value
     }
 }
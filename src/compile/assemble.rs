@@ -1,5 +1,13 @@
-use crate::ir::asm::{Arithmetic, Compare, Instruction, Jump, Move, Program};
-use crate::ir::bytes::{Bytes, InstructionBuilder};
+use crate::ir::asm::{
+    Arithmetic, Base, Compare, Condition, FloatAssign, FloatOperation, FusedMultiplyAdd, Index,
+    Instruction, IntegerAssign, Jump, Logical, LogicalAssign, LogicalOperation, Memory, Move,
+    Operand, Program, ScaleFactor, Stack, Xmm, R,
+};
+use crate::ir::bytes::{Bytes, ImpliedPrefix, InstructionBuilder, OpcodeMap};
+use crate::ir::expr::Operator;
+use crate::ir::target::Target;
+
+use super::layout::{layout, JumpEncoding};
 
 pub trait Assemblable {
     fn assemble(&self, bytes: &mut Bytes);
@@ -7,8 +15,34 @@ pub trait Assemblable {
 
 impl Assemblable for Program {
     fn assemble(&self, bytes: &mut Bytes) {
-        for instruction in &self.instructions {
-            instruction.assemble(bytes);
+        let layout = layout(self);
+        let start = bytes.len();
+        bytes.constants_at(start + layout.constants);
+
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            assert!(
+                self.target.allows(instruction),
+                "{:?} needs {:?}, but the program targets {:?}",
+                instruction,
+                Target::required(instruction),
+                self.target
+            );
+            match instruction {
+                Instruction::Jump(jump) => {
+                    let displacement = layout.displacement(index, jump);
+                    jump.assemble_with_displacement(layout.jumps[&index], displacement, bytes);
+                }
+                instruction => instruction.assemble(bytes),
+            }
+        }
+
+        if !self.constants.is_empty() {
+            // pads with int3 up to the constant pool
+            let padding = start + layout.constants - bytes.len();
+            bytes.data(std::iter::repeat_n(0xcc, padding));
+            for constant in &self.constants {
+                bytes.data(constant.to_le_bytes());
+            }
         }
     }
 }
@@ -19,31 +53,1238 @@ impl Assemblable for Instruction {
             Instruction::Move(mov) => mov.assemble(bytes),
             Instruction::ArithmeticOperation(arithmetic) => arithmetic.assemble(bytes),
             Instruction::Compare(compare) => compare.assemble(bytes),
-            Instruction::Jump(jump) => jump.assemble(bytes),
+            Instruction::Stack(stack) => stack.assemble(bytes),
+            Instruction::Return => {
+                bytes.push(InstructionBuilder::new([0xc3]));
+            }
+            Instruction::ZeroUpper => {
+                let mut builder = InstructionBuilder::new([0x77]);
+                builder.vex(OpcodeMap::Escape, ImpliedPrefix::None, 0, false);
+                bytes.push(builder);
+            }
+            Instruction::Label(_) => {}
+            Instruction::Jump(_) => {
+                panic!("jumps are relative to labels, assemble them as part of a `Program`")
+            }
+        }
+    }
+}
+
+fn xmm_number(xmm: Xmm) -> u8 {
+    i64::from(xmm) as u8
+}
+
+fn is_high(number: u8) -> bool {
+    number >= 8
+}
+
+fn low_bits(number: u8) -> u8 {
+    number & 0b111
+}
+
+/// Encodes the ModR/M byte for two registers, `reg` in the reg field and `rm` in the rm field.
+fn register_operands(builder: &mut InstructionBuilder, w: bool, reg: u8, rm: u8) {
+    builder
+        .rex(w, is_high(reg), false, is_high(rm))
+        .mod_reg_rm(0b11, low_bits(reg), low_bits(rm));
+}
+
+/// Encodes the ModR/M byte, and if necessary the SIB byte and displacement, for a register and a
+/// memory operand.
+fn memory_operands(builder: &mut InstructionBuilder, w: bool, reg: u8, memory: &Memory) {
+    let base = match memory.base {
+        Base::R(base) => base.number(),
+        Base::Constant(constant) => {
+            assert!(
+                memory.index.is_none(),
+                "%rip relative addresses can't have an index"
+            );
+            // mod 00 with an rm of 101 means a 32 bit displacement from %rip
+            builder
+                .rex(w, is_high(reg), false, false)
+                .mod_reg_rm(0b00, low_bits(reg), 0b101)
+                .constant(
+                    (constant * 4)
+                        .checked_add_signed(memory.displacement as isize)
+                        .unwrap(),
+                );
+            return;
         }
+    };
+    let index = memory.index.as_ref().map(|Index { index, scale }| {
+        assert!(
+            !matches!(index, R::Rsp),
+            "%rsp cannot be used as an index register"
+        );
+        (index.number(), scale_bits(*scale))
+    });
+
+    // a base of %rbp or %r13 with mod 00 means "no base", so they always need a displacement
+    let mod_ = if memory.displacement == 0 && low_bits(base) != 0b101 {
+        0b00
+    } else if i8::try_from(memory.displacement).is_ok() {
+        0b01
+    } else {
+        0b10
+    };
+
+    builder.rex(
+        w,
+        is_high(reg),
+        index.is_some_and(|(index, _)| is_high(index)),
+        is_high(base),
+    );
+
+    match index {
+        Some((index, scale)) => {
+            builder.mod_reg_rm(mod_, low_bits(reg), 0b100).sib(
+                scale,
+                low_bits(index),
+                low_bits(base),
+            );
+        }
+        // an rm of 100 means a SIB byte follows, so %rsp and %r12 need one with no index
+        None if low_bits(base) == 0b100 => {
+            builder
+                .mod_reg_rm(mod_, low_bits(reg), 0b100)
+                .sib(0b00, 0b100, 0b100);
+        }
+        None => {
+            builder.mod_reg_rm(mod_, low_bits(reg), low_bits(base));
+        }
+    }
+
+    match mod_ {
+        0b01 => {
+            builder.displacement((memory.displacement as i8).to_le_bytes());
+        }
+        0b10 => {
+            builder.displacement(memory.displacement.to_le_bytes());
+        }
+        _ => {}
+    }
+}
+
+fn scale_bits(scale: ScaleFactor) -> u8 {
+    match scale {
+        ScaleFactor::S1 => 0b00,
+        ScaleFactor::S2 => 0b01,
+        ScaleFactor::S4 => 0b10,
+        ScaleFactor::S8 => 0b11,
     }
 }
 
+/// An SSE instruction operating on single precision scalars, with an `F3` prefix.
+fn scalar_single(opcode: u8) -> InstructionBuilder {
+    let mut builder = InstructionBuilder::new([0x0f, opcode]);
+    builder.legacy_prefix([0xf3]);
+    builder
+}
+
+/// An SSE instruction operating on packed single precision floats, which has no prefix.
+fn packed_single(opcode: u8) -> InstructionBuilder {
+    InstructionBuilder::new([0x0f, opcode])
+}
+
 impl Assemblable for Move {
     fn assemble(&self, bytes: &mut Bytes) {
-        todo!()
+        let builder = match self {
+            Move::FloatFromMemory { dest, src } => {
+                let mut builder = scalar_single(0x10);
+                memory_operands(&mut builder, false, xmm_number(*dest), src);
+                builder
+            }
+            Move::FloatToMemory { dest, src } => {
+                let mut builder = scalar_single(0x11);
+                memory_operands(&mut builder, false, xmm_number(*src), dest);
+                builder
+            }
+            Move::FloatToFloat { dest, src } => {
+                let mut builder = scalar_single(0x10);
+                register_operands(&mut builder, false, xmm_number(*dest), xmm_number(*src));
+                builder
+            }
+            Move::IntegerFromConstant { dest, src } => {
+                let number = dest.number();
+                let mut builder = InstructionBuilder::new([0xb8 + low_bits(number)]);
+                builder
+                    .rex(false, false, false, is_high(number))
+                    .immediate(src.to_le_bytes());
+                builder
+            }
+            Move::FloatFromInteger { dest, src } => {
+                let mut builder = InstructionBuilder::new([0x0f, 0x6e]);
+                builder.legacy_prefix([0x66]);
+                register_operands(&mut builder, false, xmm_number(*dest), src.number());
+                builder
+            }
+            Move::IntegerToMemory { dest, src } => {
+                let mut builder = InstructionBuilder::new([0x89]);
+                memory_operands(&mut builder, false, src.number(), dest);
+                builder
+            }
+            Move::BlendWithMask { dest, src } => {
+                let mut builder = InstructionBuilder::new([0x0f, 0x38, 0x14]);
+                builder.legacy_prefix([0x66]);
+                register_operands(&mut builder, false, xmm_number(*dest), xmm_number(*src));
+                builder
+            }
+            Move::PackedFromMemory { dest, src } => {
+                let mut builder = packed_single(0x10);
+                memory_operands(&mut builder, false, xmm_number(*dest), src);
+                builder
+            }
+            Move::PackedToMemory { dest, src } => {
+                let mut builder = packed_single(0x11);
+                memory_operands(&mut builder, false, xmm_number(*src), dest);
+                builder
+            }
+            Move::PackedToPacked { dest, src } => packed_registers(0x28, *dest, *src),
+            Move::Shuffle {
+                dest,
+                src,
+                selector,
+            } => {
+                let mut builder = packed_registers(0xc6, *dest, *src);
+                builder.immediate([*selector]);
+                builder
+            }
+            Move::InterleaveLow { dest, src } => packed_registers(0x14, *dest, *src),
+            Move::LowToHigh { dest, src } => packed_registers(0x16, *dest, *src),
+            Move::WideFromMemory { dest, src } => {
+                let mut builder = wide(0x10, OpcodeMap::Escape, ImpliedPrefix::None, 0);
+                memory_operands(&mut builder, false, xmm_number(*dest), src);
+                builder
+            }
+            Move::WideToMemory { dest, src } => {
+                let mut builder = wide(0x11, OpcodeMap::Escape, ImpliedPrefix::None, 0);
+                memory_operands(&mut builder, false, xmm_number(*src), dest);
+                builder
+            }
+            Move::WideToWide { dest, src } => {
+                let [dest, src] = [*dest, *src].map(xmm_number);
+                // the 2 byte VEX prefix can only extend the reg field, so a high source is put
+                // there with the opcode that stores to the rm field instead, like GNU as does
+                let (opcode, reg, rm) = if is_high(src) && !is_high(dest) {
+                    (0x29, src, dest)
+                } else {
+                    (0x28, dest, src)
+                };
+                let mut builder = wide(opcode, OpcodeMap::Escape, ImpliedPrefix::None, 0);
+                register_operands(&mut builder, false, reg, rm);
+                builder
+            }
+            Move::BroadcastFromMemory { dest, src } => {
+                let mut builder = wide(0x18, OpcodeMap::Escape38, ImpliedPrefix::OperandSize, 0);
+                memory_operands(&mut builder, false, xmm_number(*dest), src);
+                builder
+            }
+            Move::InsertFromMemory {
+                dest,
+                first,
+                src,
+                lane,
+            } => {
+                assert!(*lane < 4, "an xmm register only has 4 lanes");
+                let mut builder = InstructionBuilder::new([0x21]);
+                builder.vex(
+                    OpcodeMap::Escape3A,
+                    ImpliedPrefix::OperandSize,
+                    xmm_number(*first),
+                    false,
+                );
+                memory_operands(&mut builder, false, xmm_number(*dest), src);
+                // the destination lane is in bits 4 and 5 of the immediate
+                builder.immediate([*lane << 4]);
+                builder
+            }
+            Move::XmmToHigh { dest, first, src } => {
+                let mut builder = vex_operands(
+                    0x18,
+                    OpcodeMap::Escape3A,
+                    ImpliedPrefix::OperandSize,
+                    true,
+                    *dest,
+                    *first,
+                    &Operand::Xmm(*src),
+                );
+                builder.immediate([1]);
+                builder
+            }
+            Move::WideBlendWithMask {
+                dest,
+                first,
+                src,
+                mask,
+            } => {
+                let mut builder = vex_operands(
+                    0x4a,
+                    OpcodeMap::Escape3A,
+                    ImpliedPrefix::OperandSize,
+                    true,
+                    *dest,
+                    *first,
+                    &Operand::Xmm(*src),
+                );
+                // the mask register is in the high 4 bits of the immediate
+                builder.immediate([xmm_number(*mask) << 4]);
+                builder
+            }
+            Move::HighToXmm { dest, src } => {
+                let mut builder = wide(0x19, OpcodeMap::Escape3A, ImpliedPrefix::OperandSize, 0);
+                register_operands(&mut builder, false, xmm_number(*src), xmm_number(*dest));
+                builder.immediate([1]);
+                builder
+            }
+            Move::LaneToMemory { dest, src, lane } => {
+                assert!(*lane < 4, "an xmm register only has 4 lanes");
+                let mut builder = InstructionBuilder::new([0x17]);
+                builder.vex(OpcodeMap::Escape3A, ImpliedPrefix::OperandSize, 0, false);
+                memory_operands(&mut builder, false, xmm_number(*src), dest);
+                builder.immediate([*lane]);
+                builder
+            }
+        };
+        bytes.push(builder);
+    }
+}
+
+/// A packed instruction on two xmm registers, `dest` in the reg field and `src` in the rm field
+fn packed_registers(opcode: u8, dest: Xmm, src: Xmm) -> InstructionBuilder {
+    let mut builder = packed_single(opcode);
+    register_operands(&mut builder, false, xmm_number(dest), xmm_number(src));
+    builder
+}
+
+/// A VEX encoded instruction on ymm registers, with `vvvv` as its extra source register
+fn wide(opcode: u8, map: OpcodeMap, prefix: ImpliedPrefix, vvvv: u8) -> InstructionBuilder {
+    let mut builder = InstructionBuilder::new([opcode]);
+    builder.vex(map, prefix, vvvv, true);
+    builder
+}
+
+/// A VEX encoded instruction with `dest` in the reg field, `first` in the vvvv field and `value`
+/// in the rm field, on ymm registers if `wide` is set and on scalars otherwise
+fn vex_operands(
+    opcode: u8,
+    map: OpcodeMap,
+    prefix: ImpliedPrefix,
+    wide: bool,
+    dest: Xmm,
+    first: Xmm,
+    value: &Operand,
+) -> InstructionBuilder {
+    let mut builder = InstructionBuilder::new([opcode]);
+    builder.vex(map, prefix, xmm_number(first), wide);
+    match value {
+        Operand::Xmm(value) => {
+            register_operands(&mut builder, false, xmm_number(dest), xmm_number(*value))
+        }
+        Operand::Memory(value) => memory_operands(&mut builder, false, xmm_number(dest), value),
+    }
+    builder
+}
+
+fn operator_opcode(operator: Operator) -> u8 {
+    match operator {
+        Operator::Add => 0x58,
+        Operator::Subtract => 0x5c,
+        Operator::Multiply => 0x59,
+        Operator::Divide => 0x5e,
+    }
+}
+
+/// `addss` and friends if `packed` is false, otherwise `addps` and friends
+fn float_assign(
+    FloatAssign {
+        operator,
+        dest,
+        value,
+    }: &FloatAssign,
+    packed: bool,
+) -> InstructionBuilder {
+    let opcode = operator_opcode(*operator);
+    let mut builder = if packed {
+        packed_single(opcode)
+    } else {
+        scalar_single(opcode)
+    };
+    match value {
+        Operand::Xmm(value) => {
+            register_operands(&mut builder, false, xmm_number(*dest), xmm_number(*value))
+        }
+        Operand::Memory(value) => memory_operands(&mut builder, false, xmm_number(*dest), value),
     }
+    builder
 }
 
 impl Assemblable for Arithmetic {
     fn assemble(&self, bytes: &mut Bytes) {
-        todo!()
+        let builder = match self {
+            Arithmetic::FloatAssign(assign) => float_assign(assign, false),
+            Arithmetic::PackedAssign(assign) => float_assign(assign, true),
+            Arithmetic::FloatOperation(FloatOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => vex_operands(
+                operator_opcode(*operator),
+                OpcodeMap::Escape,
+                ImpliedPrefix::Repeat,
+                false,
+                *dest,
+                *first,
+                value,
+            ),
+            Arithmetic::FusedMultiplyAdd(FusedMultiplyAdd { dest, first, value }) => vex_operands(
+                0xb9,
+                OpcodeMap::Escape38,
+                ImpliedPrefix::OperandSize,
+                false,
+                *dest,
+                *first,
+                value,
+            ),
+            Arithmetic::WideOperation(FloatOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => vex_operands(
+                operator_opcode(*operator),
+                OpcodeMap::Escape,
+                ImpliedPrefix::None,
+                true,
+                *dest,
+                *first,
+                value,
+            ),
+            Arithmetic::WideLogical(LogicalOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => vex_operands(
+                logical_opcode(*operator),
+                OpcodeMap::Escape,
+                ImpliedPrefix::None,
+                true,
+                *dest,
+                *first,
+                &Operand::Xmm(*value),
+            ),
+            Arithmetic::LogicalAssign(LogicalAssign {
+                operator,
+                dest,
+                value,
+            }) => {
+                let mut builder = InstructionBuilder::new([0x0f, logical_opcode(*operator)]);
+                register_operands(&mut builder, false, xmm_number(*dest), xmm_number(*value));
+                builder
+            }
+            Arithmetic::IntegerAddAssign(assign) => integer_assign(0, assign),
+            Arithmetic::IntegerSubAssign(assign) => integer_assign(5, assign),
+        };
+        bytes.push(builder);
     }
 }
 
+fn logical_opcode(operator: Logical) -> u8 {
+    match operator {
+        Logical::And => 0x54,
+        Logical::AndNot => 0x55,
+        Logical::Or => 0x56,
+    }
+}
+
+/// `extension` is the opcode extension stored in the reg field of the `81`/`83` group.
+fn integer_assign(
+    extension: u8,
+    IntegerAssign { dest, value }: &IntegerAssign,
+) -> InstructionBuilder {
+    let value = i32::try_from(*value).expect("immediate does not fit in a sign extended imm32");
+
+    let mut builder = match i8::try_from(value) {
+        Ok(small) => {
+            let mut builder = InstructionBuilder::new([0x83]);
+            builder.immediate(small.to_le_bytes());
+            builder
+        }
+        Err(_) => {
+            let mut builder = InstructionBuilder::new([0x81]);
+            builder.immediate(value.to_le_bytes());
+            builder
+        }
+    };
+    register_operands(&mut builder, true, extension, dest.number());
+    builder
+}
+
 impl Assemblable for Compare {
     fn assemble(&self, bytes: &mut Bytes) {
-        todo!()
+        match self {
+            Compare::CompareFloats { first, second } => {
+                let mut builder = InstructionBuilder::new([0x0f, 0x2f]);
+                register_operands(&mut builder, false, xmm_number(*first), xmm_number(*second));
+                bytes.push(builder);
+            }
+            Compare::CompareToMask {
+                condition,
+                dest,
+                value,
+            } => {
+                let mut builder = scalar_single(0xc2);
+                register_operands(&mut builder, false, xmm_number(*dest), xmm_number(*value));
+                builder.immediate([condition_bits(*condition)]);
+                bytes.push(builder);
+            }
+            Compare::PackedCompareToMask {
+                condition,
+                dest,
+                value,
+            } => {
+                let mut builder = packed_registers(0xc2, *dest, *value);
+                builder.immediate([condition_bits(*condition)]);
+                bytes.push(builder);
+            }
+            Compare::WideCompareToMask {
+                condition,
+                dest,
+                first,
+                value,
+            } => {
+                let mut builder = vex_operands(
+                    0xc2,
+                    OpcodeMap::Escape,
+                    ImpliedPrefix::None,
+                    true,
+                    *dest,
+                    *first,
+                    &Operand::Xmm(*value),
+                );
+                builder.immediate([condition_bits(*condition)]);
+                bytes.push(builder);
+            }
+        }
+    }
+}
+
+fn condition_bits(condition: Condition) -> u8 {
+    match condition {
+        Condition::Equal => 0,
+        Condition::LessThan => 1,
+        Condition::LessEqual => 2,
+        Condition::Unordered => 3,
+        Condition::NotEqual => 4,
+        Condition::NotLessThan => 5,
+        Condition::NotLessEqual => 6,
+        Condition::Ordered => 7,
     }
 }
 
-impl Assemblable for Jump {
+impl Assemblable for Stack {
     fn assemble(&self, bytes: &mut Bytes) {
-        todo!()
+        let (opcode, register) = match self {
+            Stack::Push(register) => (0x50, register),
+            Stack::Pop(register) => (0x58, register),
+        };
+        let number = register.number();
+        let mut builder = InstructionBuilder::new([opcode + low_bits(number)]);
+        builder.rex(false, false, false, is_high(number));
+        bytes.push(builder);
+    }
+}
+
+impl Jump {
+    /// `displacement` is measured in bytes from the end of the jump
+    fn assemble_with_displacement(
+        &self,
+        encoding: JumpEncoding,
+        displacement: i64,
+        bytes: &mut Bytes,
+    ) {
+        let mut builder = match (self, encoding) {
+            (Jump::Unconditional { .. }, JumpEncoding::Short) => InstructionBuilder::new([0xeb]),
+            (Jump::AboveEqual { .. }, JumpEncoding::Short) => InstructionBuilder::new([0x73]),
+            (Jump::Unconditional { .. }, JumpEncoding::Near) => InstructionBuilder::new([0xe9]),
+            (Jump::AboveEqual { .. }, JumpEncoding::Near) => InstructionBuilder::new([0x0f, 0x83]),
+        };
+        match encoding {
+            JumpEncoding::Short => {
+                builder.immediate(i8::try_from(displacement).unwrap().to_le_bytes());
+            }
+            JumpEncoding::Near => {
+                builder.immediate(i32::try_from(displacement).unwrap().to_le_bytes());
+            }
+        }
+        bytes.push(builder);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bounded::Bounded;
+    use crate::ir::asm::Label;
+
+    fn xmm(index: i64) -> Xmm {
+        index.try_into().unwrap()
+    }
+
+    fn r(index: i64) -> R {
+        if index < 12 {
+            R::RLow(Bounded::try_from(index).unwrap())
+        } else {
+            R::RHigh(Bounded::try_from(index).unwrap())
+        }
+    }
+
+    fn memory(base: R, displacement: i32) -> Memory {
+        Memory {
+            displacement,
+            base: Base::R(base),
+            index: None,
+        }
+    }
+
+    fn assert_encodes(instruction: Instruction, expected: &[u8]) {
+        let mut bytes = Bytes::new();
+        instruction.assemble(&mut bytes);
+        assert_eq!(
+            bytes.as_slice(),
+            expected,
+            "{:?} assembled incorrectly",
+            instruction
+        );
+    }
+
+    fn mov(mov: Move) -> Instruction {
+        Instruction::Move(mov)
+    }
+
+    #[test]
+    fn float_from_memory() {
+        let load = |dest, src| {
+            mov(Move::FloatFromMemory {
+                dest: xmm(dest),
+                src,
+            })
+        };
+
+        assert_encodes(load(0, memory(R::Rsp, 0)), &[0xf3, 0x0f, 0x10, 0x04, 0x24]);
+        assert_encodes(
+            load(1, memory(R::Rsp, 4)),
+            &[0xf3, 0x0f, 0x10, 0x4c, 0x24, 0x04],
+        );
+        assert_encodes(
+            load(9, memory(R::Rsp, 0x200)),
+            &[0xf3, 0x44, 0x0f, 0x10, 0x8c, 0x24, 0x00, 0x02, 0x00, 0x00],
+        );
+        assert_encodes(load(2, memory(R::Rbp, 0)), &[0xf3, 0x0f, 0x10, 0x55, 0x00]);
+        assert_encodes(
+            load(3, memory(r(13), 8)),
+            &[0xf3, 0x41, 0x0f, 0x10, 0x5d, 0x08],
+        );
+        assert_encodes(
+            load(4, memory(r(12), 0)),
+            &[0xf3, 0x41, 0x0f, 0x10, 0x24, 0x24],
+        );
+        assert_encodes(
+            load(
+                5,
+                Memory {
+                    displacement: 16,
+                    base: Base::R(R::Rax),
+                    index: Some(Index {
+                        index: R::Rcx,
+                        scale: ScaleFactor::S4,
+                    }),
+                },
+            ),
+            &[0xf3, 0x0f, 0x10, 0x6c, 0x88, 0x10],
+        );
+        assert_encodes(
+            load(
+                6,
+                Memory {
+                    displacement: 0,
+                    base: Base::R(r(8)),
+                    index: Some(Index {
+                        index: r(9),
+                        scale: ScaleFactor::S8,
+                    }),
+                },
+            ),
+            &[0xf3, 0x43, 0x0f, 0x10, 0x34, 0xc8],
+        );
+    }
+
+    #[test]
+    fn float_to_memory() {
+        assert_encodes(
+            mov(Move::FloatToMemory {
+                dest: memory(R::Rsp, 8),
+                src: xmm(0),
+            }),
+            &[0xf3, 0x0f, 0x11, 0x44, 0x24, 0x08],
+        );
+        assert_encodes(
+            mov(Move::FloatToMemory {
+                dest: memory(r(11), 0),
+                src: xmm(15),
+            }),
+            &[0xf3, 0x45, 0x0f, 0x11, 0x3b],
+        );
+    }
+
+    #[test]
+    fn float_to_float() {
+        let copy = |dest, src| {
+            mov(Move::FloatToFloat {
+                dest: xmm(dest),
+                src: xmm(src),
+            })
+        };
+
+        assert_encodes(copy(1, 2), &[0xf3, 0x0f, 0x10, 0xca]);
+        assert_encodes(copy(8, 3), &[0xf3, 0x44, 0x0f, 0x10, 0xc3]);
+        assert_encodes(copy(3, 8), &[0xf3, 0x41, 0x0f, 0x10, 0xd8]);
+    }
+
+    #[test]
+    fn integers() {
+        assert_encodes(
+            mov(Move::IntegerFromConstant {
+                dest: R::Rax,
+                src: 0x3f800000,
+            }),
+            &[0xb8, 0x00, 0x00, 0x80, 0x3f],
+        );
+        assert_encodes(
+            mov(Move::IntegerFromConstant {
+                dest: R::Rbx,
+                src: 1,
+            }),
+            &[0xbb, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert_encodes(
+            mov(Move::IntegerFromConstant {
+                dest: r(10),
+                src: -1,
+            }),
+            &[0x41, 0xba, 0xff, 0xff, 0xff, 0xff],
+        );
+        assert_encodes(
+            mov(Move::FloatFromInteger {
+                dest: xmm(0),
+                src: R::Rax,
+            }),
+            &[0x66, 0x0f, 0x6e, 0xc0],
+        );
+        assert_encodes(
+            mov(Move::FloatFromInteger {
+                dest: xmm(10),
+                src: r(9),
+            }),
+            &[0x66, 0x45, 0x0f, 0x6e, 0xd1],
+        );
+        assert_encodes(
+            mov(Move::IntegerToMemory {
+                dest: memory(R::Rsp, 4),
+                src: R::Rbx,
+            }),
+            &[0x89, 0x5c, 0x24, 0x04],
+        );
+        assert_encodes(
+            mov(Move::IntegerToMemory {
+                dest: memory(r(12), 4),
+                src: r(15),
+            }),
+            &[0x45, 0x89, 0x7c, 0x24, 0x04],
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        let float = |operator, dest, value| {
+            Instruction::ArithmeticOperation(Arithmetic::FloatAssign(FloatAssign {
+                operator,
+                dest: xmm(dest),
+                value: Operand::Xmm(xmm(value)),
+            }))
+        };
+        let float_memory = |operator, dest, value| {
+            Instruction::ArithmeticOperation(Arithmetic::FloatAssign(FloatAssign {
+                operator,
+                dest: xmm(dest),
+                value: Operand::Memory(value),
+            }))
+        };
+
+        assert_encodes(float(Operator::Add, 0, 1), &[0xf3, 0x0f, 0x58, 0xc1]);
+        assert_encodes(
+            float_memory(Operator::Add, 0, memory(R::Rsp, 4)),
+            &[0xf3, 0x0f, 0x58, 0x44, 0x24, 0x04],
+        );
+        assert_encodes(
+            float_memory(Operator::Divide, 12, memory(r(9), -8)),
+            &[0xf3, 0x45, 0x0f, 0x5e, 0x61, 0xf8],
+        );
+        assert_encodes(float(Operator::Subtract, 2, 3), &[0xf3, 0x0f, 0x5c, 0xd3]);
+        assert_encodes(
+            float(Operator::Multiply, 9, 1),
+            &[0xf3, 0x44, 0x0f, 0x59, 0xc9],
+        );
+        assert_encodes(
+            float(Operator::Divide, 4, 12),
+            &[0xf3, 0x41, 0x0f, 0x5e, 0xe4],
+        );
+
+        let sub = |dest, value| {
+            Instruction::ArithmeticOperation(Arithmetic::IntegerSubAssign(IntegerAssign {
+                dest,
+                value,
+            }))
+        };
+        let add = |dest, value| {
+            Instruction::ArithmeticOperation(Arithmetic::IntegerAddAssign(IntegerAssign {
+                dest,
+                value,
+            }))
+        };
+
+        assert_encodes(sub(R::Rsp, 16), &[0x48, 0x83, 0xec, 0x10]);
+        assert_encodes(add(R::Rsp, 16), &[0x48, 0x83, 0xc4, 0x10]);
+        assert_encodes(
+            sub(R::Rsp, 1024),
+            &[0x48, 0x81, 0xec, 0x00, 0x04, 0x00, 0x00],
+        );
+        assert_encodes(
+            add(r(12), 1024),
+            &[0x49, 0x81, 0xc4, 0x00, 0x04, 0x00, 0x00],
+        );
+    }
+
+    #[test]
+    fn compare() {
+        let compare = |first, second| {
+            Instruction::Compare(Compare::CompareFloats {
+                first: xmm(first),
+                second: xmm(second),
+            })
+        };
+
+        assert_encodes(compare(0, 1), &[0x0f, 0x2f, 0xc1]);
+        assert_encodes(compare(8, 15), &[0x45, 0x0f, 0x2f, 0xc7]);
+    }
+
+    #[test]
+    fn masks() {
+        let compare = |condition, dest, value| {
+            Instruction::Compare(Compare::CompareToMask {
+                condition,
+                dest: xmm(dest),
+                value: xmm(value),
+            })
+        };
+        let logical = |operator, dest, value| {
+            Instruction::ArithmeticOperation(Arithmetic::LogicalAssign(LogicalAssign {
+                operator,
+                dest: xmm(dest),
+                value: xmm(value),
+            }))
+        };
+        let blend = |dest, src| {
+            mov(Move::BlendWithMask {
+                dest: xmm(dest),
+                src: xmm(src),
+            })
+        };
+
+        assert_encodes(
+            compare(Condition::LessEqual, 0, 1),
+            &[0xf3, 0x0f, 0xc2, 0xc1, 0x02],
+        );
+        assert_encodes(
+            compare(Condition::LessThan, 9, 3),
+            &[0xf3, 0x44, 0x0f, 0xc2, 0xcb, 0x01],
+        );
+        assert_encodes(logical(Logical::And, 2, 0), &[0x0f, 0x54, 0xd0]);
+        assert_encodes(logical(Logical::AndNot, 0, 11), &[0x41, 0x0f, 0x55, 0xc3]);
+        assert_encodes(logical(Logical::Or, 12, 13), &[0x45, 0x0f, 0x56, 0xe5]);
+        assert_encodes(blend(2, 3), &[0x66, 0x0f, 0x38, 0x14, 0xd3]);
+        assert_encodes(blend(10, 3), &[0x66, 0x44, 0x0f, 0x38, 0x14, 0xd3]);
+    }
+
+    #[test]
+    fn packed() {
+        let packed = |operator, dest, value| {
+            Instruction::ArithmeticOperation(Arithmetic::PackedAssign(FloatAssign {
+                operator,
+                dest: xmm(dest),
+                value: Operand::Xmm(xmm(value)),
+            }))
+        };
+        let registers =
+            |mov: fn(Xmm, Xmm) -> Move, dest, src| Instruction::Move(mov(xmm(dest), xmm(src)));
+
+        assert_encodes(
+            mov(Move::PackedFromMemory {
+                dest: xmm(3),
+                src: memory(R::Rdi, 16),
+            }),
+            &[0x0f, 0x10, 0x5f, 0x10],
+        );
+        assert_encodes(
+            mov(Move::PackedToMemory {
+                dest: memory(R::Rsp, 0),
+                src: xmm(12),
+            }),
+            &[0x44, 0x0f, 0x11, 0x24, 0x24],
+        );
+        assert_encodes(
+            registers(|dest, src| Move::PackedToPacked { dest, src }, 1, 9),
+            &[0x41, 0x0f, 0x28, 0xc9],
+        );
+        assert_encodes(
+            mov(Move::Shuffle {
+                dest: xmm(4),
+                src: xmm(4),
+                selector: 0,
+            }),
+            &[0x0f, 0xc6, 0xe4, 0x00],
+        );
+        assert_encodes(
+            registers(|dest, src| Move::InterleaveLow { dest, src }, 3, 1),
+            &[0x0f, 0x14, 0xd9],
+        );
+        assert_encodes(
+            registers(|dest, src| Move::LowToHigh { dest, src }, 10, 2),
+            &[0x44, 0x0f, 0x16, 0xd2],
+        );
+
+        assert_encodes(packed(Operator::Add, 0, 1), &[0x0f, 0x58, 0xc1]);
+        assert_encodes(packed(Operator::Subtract, 2, 3), &[0x0f, 0x5c, 0xd3]);
+        assert_encodes(packed(Operator::Multiply, 9, 1), &[0x44, 0x0f, 0x59, 0xc9]);
+        assert_encodes(packed(Operator::Divide, 4, 12), &[0x41, 0x0f, 0x5e, 0xe4]);
+        assert_encodes(
+            Instruction::Compare(Compare::PackedCompareToMask {
+                condition: Condition::LessEqual,
+                dest: xmm(0),
+                value: xmm(11),
+            }),
+            &[0x41, 0x0f, 0xc2, 0xc3, 0x02],
+        );
+    }
+
+    #[test]
+    fn vex() {
+        let operation = |operator, dest, first, value| {
+            Instruction::ArithmeticOperation(Arithmetic::FloatOperation(FloatOperation {
+                operator,
+                dest: xmm(dest),
+                first: xmm(first),
+                value,
+            }))
+        };
+        let fma = |dest, first, value| {
+            Instruction::ArithmeticOperation(Arithmetic::FusedMultiplyAdd(FusedMultiplyAdd {
+                dest: xmm(dest),
+                first: xmm(first),
+                value,
+            }))
+        };
+        let register = |index| Operand::Xmm(xmm(index));
+
+        // the 2 byte form, which can only extend the reg field
+        assert_encodes(
+            operation(Operator::Add, 1, 2, register(3)),
+            &[0xc5, 0xea, 0x58, 0xcb],
+        );
+        assert_encodes(
+            operation(Operator::Subtract, 12, 15, register(4)),
+            &[0xc5, 0x02, 0x5c, 0xe4],
+        );
+        assert_encodes(
+            operation(Operator::Multiply, 0, 1, Operand::Memory(memory(R::Rsp, 4))),
+            &[0xc5, 0xf2, 0x59, 0x44, 0x24, 0x04],
+        );
+        // the 3 byte form, for an extended rm field or the 0F 38 map
+        assert_encodes(
+            operation(Operator::Add, 9, 10, register(11)),
+            &[0xc4, 0x41, 0x2a, 0x58, 0xcb],
+        );
+        assert_encodes(
+            operation(Operator::Divide, 3, 4, Operand::Memory(memory(r(9), -8))),
+            &[0xc4, 0xc1, 0x5a, 0x5e, 0x59, 0xf8],
+        );
+        assert_encodes(fma(1, 2, register(3)), &[0xc4, 0xe2, 0x69, 0xb9, 0xcb]);
+        assert_encodes(fma(8, 13, register(14)), &[0xc4, 0x42, 0x11, 0xb9, 0xc6]);
+    }
+
+    #[test]
+    fn ymm() {
+        let operation = |operator, dest, first, value| {
+            Instruction::ArithmeticOperation(Arithmetic::WideOperation(FloatOperation {
+                operator,
+                dest: xmm(dest),
+                first: xmm(first),
+                value,
+            }))
+        };
+        let logical = |operator, dest, first, value| {
+            Instruction::ArithmeticOperation(Arithmetic::WideLogical(LogicalOperation {
+                operator,
+                dest: xmm(dest),
+                first: xmm(first),
+                value: xmm(value),
+            }))
+        };
+        let copy = |dest, src| {
+            mov(Move::WideToWide {
+                dest: xmm(dest),
+                src: xmm(src),
+            })
+        };
+        let broadcast = |dest, src| {
+            mov(Move::BroadcastFromMemory {
+                dest: xmm(dest),
+                src,
+            })
+        };
+        let blend = |dest, first, src, mask| {
+            mov(Move::WideBlendWithMask {
+                dest: xmm(dest),
+                first: xmm(first),
+                src: xmm(src),
+                mask: xmm(mask),
+            })
+        };
+        let insert = |dest, first, src, lane| {
+            mov(Move::InsertFromMemory {
+                dest: xmm(dest),
+                first: xmm(first),
+                src,
+                lane,
+            })
+        };
+        let to_high = |dest, first, src| {
+            mov(Move::XmmToHigh {
+                dest: xmm(dest),
+                first: xmm(first),
+                src: xmm(src),
+            })
+        };
+        let lane = |dest, src, lane| {
+            mov(Move::LaneToMemory {
+                dest,
+                src: xmm(src),
+                lane,
+            })
+        };
+
+        assert_encodes(
+            mov(Move::WideFromMemory {
+                dest: xmm(3),
+                src: memory(R::Rdi, 16),
+            }),
+            &[0xc5, 0xfc, 0x10, 0x5f, 0x10],
+        );
+        assert_encodes(
+            mov(Move::WideToMemory {
+                dest: memory(R::Rsp, 0),
+                src: xmm(12),
+            }),
+            &[0xc5, 0x7c, 0x11, 0x24, 0x24],
+        );
+        // a high source is put in the reg field, so the 2 byte VEX prefix can extend it
+        assert_encodes(copy(1, 9), &[0xc5, 0x7c, 0x29, 0xc9]);
+        assert_encodes(copy(9, 1), &[0xc5, 0x7c, 0x28, 0xc9]);
+        assert_encodes(copy(10, 9), &[0xc4, 0x41, 0x7c, 0x28, 0xd1]);
+        assert_encodes(
+            broadcast(2, memory(R::Rsp, 4)),
+            &[0xc4, 0xe2, 0x7d, 0x18, 0x54, 0x24, 0x04],
+        );
+        assert_encodes(
+            broadcast(10, memory(r(9), 0)),
+            &[0xc4, 0x42, 0x7d, 0x18, 0x11],
+        );
+        assert_encodes(
+            insert(3, 3, memory(R::Rdi, 4), 1),
+            &[0xc4, 0xe3, 0x61, 0x21, 0x5f, 0x04, 0x10],
+        );
+        assert_encodes(
+            insert(2, 10, memory(r(9), 0), 3),
+            &[0xc4, 0xc3, 0x29, 0x21, 0x11, 0x30],
+        );
+        assert_encodes(to_high(3, 2, 1), &[0xc4, 0xe3, 0x6d, 0x18, 0xd9, 0x01]);
+        assert_encodes(to_high(10, 10, 9), &[0xc4, 0x43, 0x2d, 0x18, 0xd1, 0x01]);
+        // the mask register is in the high 4 bits of the immediate
+        assert_encodes(blend(5, 4, 11, 0), &[0xc4, 0xc3, 0x5d, 0x4a, 0xeb, 0x00]);
+        assert_encodes(blend(3, 2, 1, 12), &[0xc4, 0xe3, 0x6d, 0x4a, 0xd9, 0xc0]);
+        assert_encodes(
+            mov(Move::HighToXmm {
+                dest: xmm(1),
+                src: xmm(9),
+            }),
+            &[0xc4, 0x63, 0x7d, 0x19, 0xc9, 0x01],
+        );
+        assert_encodes(
+            lane(memory(R::Rsp, 8), 2, 3),
+            &[0xc4, 0xe3, 0x79, 0x17, 0x54, 0x24, 0x08, 0x03],
+        );
+        assert_encodes(
+            lane(memory(r(11), 0), 10, 0),
+            &[0xc4, 0x43, 0x79, 0x17, 0x13, 0x00],
+        );
+
+        assert_encodes(
+            operation(Operator::Add, 1, 2, Operand::Xmm(xmm(3))),
+            &[0xc5, 0xec, 0x58, 0xcb],
+        );
+        assert_encodes(
+            operation(Operator::Multiply, 9, 10, Operand::Xmm(xmm(11))),
+            &[0xc4, 0x41, 0x2c, 0x59, 0xcb],
+        );
+        assert_encodes(
+            operation(Operator::Divide, 3, 4, Operand::Memory(memory(r(9), -8))),
+            &[0xc4, 0xc1, 0x5c, 0x5e, 0x59, 0xf8],
+        );
+        assert_encodes(
+            logical(Logical::AndNot, 3, 3, 14),
+            &[0xc4, 0xc1, 0x64, 0x55, 0xde],
+        );
+        assert_encodes(logical(Logical::Or, 0, 12, 1), &[0xc5, 0x9c, 0x56, 0xc1]);
+        assert_encodes(
+            Instruction::Compare(Compare::WideCompareToMask {
+                condition: Condition::LessEqual,
+                dest: xmm(0),
+                first: xmm(0),
+                value: xmm(11),
+            }),
+            &[0xc4, 0xc1, 0x7c, 0xc2, 0xc3, 0x02],
+        );
+        assert_encodes(Instruction::ZeroUpper, &[0xc5, 0xf8, 0x77]);
+    }
+
+    #[test]
+    fn stack() {
+        assert_encodes(Instruction::Stack(Stack::Push(R::Rbx)), &[0x53]);
+        assert_encodes(Instruction::Stack(Stack::Pop(R::Rbx)), &[0x5b]);
+        assert_encodes(Instruction::Stack(Stack::Push(r(12))), &[0x41, 0x54]);
+        assert_encodes(Instruction::Stack(Stack::Pop(r(15))), &[0x41, 0x5f]);
+        assert_encodes(Instruction::Return, &[0xc3]);
+    }
+
+    #[test]
+    fn jumps() {
+        let start = Label { index: 0 };
+        let end = Label { index: 1 };
+        let program = Program {
+            instructions: vec![
+                Instruction::Label(start),
+                Instruction::Jump(Jump::AboveEqual { target: end }),
+                mov(Move::FloatToFloat {
+                    dest: xmm(1),
+                    src: xmm(2),
+                }),
+                Instruction::Label(end),
+                Instruction::Jump(Jump::Unconditional { target: start }),
+            ],
+            constants: Vec::new(),
+            target: Target::default(),
+        };
+
+        let mut bytes = Bytes::new();
+        program.assemble(&mut bytes);
+
+        assert_eq!(
+            bytes.as_slice(),
+            &[
+                0x73, 0x04, // jae over the movss
+                0xf3, 0x0f, 0x10, 0xca, // movss %xmm2, %xmm1
+                0xeb, 0xf8, // jmp back to the jae
+            ]
+        );
+    }
+
+    #[test]
+    fn constants() {
+        let constant = |index| Memory {
+            displacement: 0,
+            base: Base::Constant(index),
+            index: None,
+        };
+        let program = Program {
+            instructions: vec![
+                mov(Move::FloatFromMemory {
+                    dest: xmm(9),
+                    src: constant(1),
+                }),
+                mov(Move::FloatFromMemory {
+                    dest: xmm(0),
+                    src: constant(0),
+                }),
+                Instruction::Return,
+            ],
+            constants: vec![1.0, 2.0],
+            target: Target::default(),
+        };
+
+        let mut bytes = Bytes::new();
+        program.assemble(&mut bytes);
+
+        assert_eq!(
+            bytes.as_slice(),
+            &[
+                0xf3, 0x44, 0x0f, 0x10, 0x0d, 0x1b, 0x00, 0x00, 0x00, // movss 2.0, %xmm9
+                0xf3, 0x0f, 0x10, 0x05, 0x0f, 0x00, 0x00, 0x00, // movss 1.0, %xmm0
+                0xc3, // ret
+                0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc,
+                0x00, 0x00, 0x80, 0x3f, // 1.0
+                0x00, 0x00, 0x00, 0x40, // 2.0
+            ]
+        );
+    }
+
+    #[test]
+    fn jump_relaxation() {
+        let start = Label { index: 0 };
+        let end = Label { index: 1 };
+        let copy = || {
+            mov(Move::FloatToFloat {
+                dest: xmm(1),
+                src: xmm(2),
+            })
+        };
+
+        // with short jumps, the forward jump fits in 8 bits but the backward jump doesn't. relaxing
+        // the backward jump then pushes the forward jump out of range as well.
+        let mut instructions = vec![
+            Instruction::Label(start),
+            Instruction::Jump(Jump::AboveEqual { target: end }),
+        ];
+        instructions.extend((0..31).map(|_| copy()));
+        instructions.push(Instruction::Return);
+        instructions.push(Instruction::Jump(Jump::Unconditional { target: start }));
+        instructions.push(Instruction::Label(end));
+        let program = Program {
+            instructions,
+            constants: Vec::new(),
+            target: Target::default(),
+        };
+
+        let layout = layout(&program);
+        assert_eq!(layout.jumps[&1], JumpEncoding::Near);
+        assert_eq!(layout.jumps[&34], JumpEncoding::Near);
+
+        let mut bytes = Bytes::new();
+        program.assemble(&mut bytes);
+        let bytes = bytes.as_slice();
+
+        assert_eq!(bytes.len(), 6 + 31 * 4 + 1 + 5);
+        assert_eq!(&bytes[..6], &[0x0f, 0x83, 0x82, 0x00, 0x00, 0x00]);
+        assert_eq!(&bytes[131..], &[0xe9, 0x78, 0xff, 0xff, 0xff]);
     }
 }
@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::ir::asm::Xmm;
+use crate::ir::register::{Expr, Program, Register, Value};
+
+use super::register_alloc::{self, Allocator, Assignment, Location};
+
+/// Chaitin–Briggs graph colouring: registers that are live at the same time interfere, and each
+/// register is given an xmm register that none of its neighbours have. Registers that can't be
+/// coloured live on the stack.
+#[derive(Copy, Clone, Debug)]
+pub struct GraphColouring {
+    /// Merge the source and destination of a `Move` when that can't make the graph uncolourable,
+    /// so that the move copies a register onto itself and disappears.
+    pub coalesce: bool,
+}
+
+impl Default for GraphColouring {
+    fn default() -> Self {
+        Self { coalesce: true }
+    }
+}
+
+/// Which registers can't share an xmm register
+#[derive(Default)]
+struct Graph {
+    neighbours: BTreeMap<Register, BTreeSet<Register>>,
+}
+
+impl Graph {
+    fn add_node(&mut self, register: Register) {
+        self.neighbours.entry(register).or_default();
+    }
+
+    fn add_edge(&mut self, first: Register, second: Register) {
+        if first != second {
+            self.neighbours.entry(first).or_default().insert(second);
+            self.neighbours.entry(second).or_default().insert(first);
+        }
+    }
+
+    fn interferes(&self, first: Register, second: Register) -> bool {
+        self.neighbours[&first].contains(&second)
+    }
+
+    fn degree(&self, register: Register) -> usize {
+        self.neighbours[&register].len()
+    }
+
+    /// Replaces `merged` with `into`, which inherits all of its neighbours
+    fn merge(&mut self, merged: Register, into: Register) {
+        for neighbour in self.neighbours.remove(&merged).unwrap() {
+            self.neighbours.get_mut(&neighbour).unwrap().remove(&merged);
+            self.add_edge(neighbour, into);
+        }
+    }
+}
+
+fn registers(values: impl IntoIterator<Item = Value>) -> impl Iterator<Item = Register> {
+    values.into_iter().filter_map(|value| match value {
+        Value::Register(register) => Some(register),
+        Value::Number(_) => None,
+    })
+}
+
+/// The interference graph of `program`, built by walking backwards from the outputs and making
+/// every register written interfere with every register live after the write.
+fn interference(program: &Program) -> Graph {
+    let mut graph = Graph::default();
+
+    // inputs are written when they are loaded, just before the first statement to read them
+    let mut loads = vec![Vec::new(); program.statements.len() + 1];
+    for (input, load) in program
+        .input
+        .iter()
+        .zip(register_alloc::input_loads(program))
+    {
+        if let Some(index) = load {
+            loads[index].push(*input);
+        }
+    }
+    let load = |graph: &mut Graph, live: &mut BTreeSet<Register>, inputs: &[Register]| {
+        for input in inputs {
+            graph.add_node(*input);
+            for register in live.iter() {
+                graph.add_edge(*input, *register);
+            }
+        }
+        for input in inputs {
+            live.remove(input);
+        }
+    };
+
+    let mut live: BTreeSet<_> = registers(program.output.iter().copied()).collect();
+    load(&mut graph, &mut live, &loads[program.statements.len()]);
+
+    for (index, statement) in program.statements.iter().enumerate().rev() {
+        let destination = statement.destination;
+        graph.add_node(destination);
+
+        // a move's source holds the same value as its destination, so they may share a register
+        let copied = match statement.expr {
+            Expr::Move(Value::Register(source)) => Some(source),
+            _ => None,
+        };
+        for register in &live {
+            if Some(*register) != copied {
+                graph.add_edge(destination, *register);
+            }
+        }
+
+        match statement.expr {
+            Expr::Operation { .. } | Expr::MultiplyAdd { .. } => {
+                // the operands are read along with the destination, so they can't share its
+                // register even if this is their last read
+                for register in registers(statement.expr.operands()) {
+                    graph.add_edge(destination, register);
+                }
+                live.insert(destination);
+            }
+            Expr::Move(_) | Expr::IfPositive { .. } => {
+                live.remove(&destination);
+            }
+        }
+        for register in registers(statement.expr.operands()) {
+            graph.add_node(register);
+            live.insert(register);
+        }
+
+        load(&mut graph, &mut live, &loads[index]);
+    }
+
+    graph
+}
+
+/// The register `register` was merged into
+fn find(aliases: &HashMap<Register, Register>, mut register: Register) -> Register {
+    while let Some(alias) = aliases.get(&register) {
+        register = *alias;
+    }
+    register
+}
+
+/// Merges the ends of moves for as long as the merged register has fewer than `colours`
+/// neighbours of degree `colours` or more, so that it can always be coloured.
+fn coalesce(program: &Program, graph: &mut Graph, colours: usize) -> HashMap<Register, Register> {
+    let mut aliases = HashMap::new();
+    let moves: Vec<_> = program
+        .statements
+        .iter()
+        .filter_map(|statement| match statement.expr {
+            Expr::Move(Value::Register(source)) => Some((statement.destination, source)),
+            _ => None,
+        })
+        .collect();
+
+    let mut merged = true;
+    while merged {
+        merged = false;
+        for (destination, source) in &moves {
+            let destination = find(&aliases, *destination);
+            let source = find(&aliases, *source);
+            if destination == source || graph.interferes(destination, source) {
+                continue;
+            }
+
+            let neighbours = &graph.neighbours[&destination] | &graph.neighbours[&source];
+            let significant = neighbours
+                .iter()
+                .filter(|neighbour| graph.degree(**neighbour) >= colours)
+                .count();
+            if significant < colours {
+                graph.merge(source, destination);
+                aliases.insert(source, destination);
+                merged = true;
+            }
+        }
+    }
+
+    aliases
+}
+
+impl Allocator for GraphColouring {
+    fn assign(&self, program: &Program, available: &[Xmm]) -> Assignment {
+        let colours = available.len();
+        let mut graph = interference(program);
+        let aliases = if self.coalesce {
+            coalesce(program, &mut graph, colours)
+        } else {
+            HashMap::new()
+        };
+
+        // the number of reads and writes of each register, merged registers included
+        let mut uses: HashMap<Register, usize> = HashMap::new();
+        for interval in register_alloc::intervals(program) {
+            *uses.entry(find(&aliases, interval.register)).or_default() += interval.uses.len();
+        }
+
+        // simplify: remove registers with fewer than `colours` neighbours, since they can always
+        // be coloured, and otherwise optimistically remove the one cheapest to spill
+        let mut degrees: BTreeMap<Register, usize> = graph
+            .neighbours
+            .iter()
+            .map(|(register, neighbours)| (*register, neighbours.len()))
+            .collect();
+        let mut removed = Vec::new();
+        while !degrees.is_empty() {
+            let register = degrees
+                .iter()
+                .find(|(_, degree)| **degree < colours)
+                .or_else(|| {
+                    degrees
+                        .iter()
+                        .min_by(|(first, first_degree), (second, second_degree)| {
+                            let cost = |register, degree: usize| {
+                                uses.get(register).copied().unwrap_or(0) as f32 / degree as f32
+                            };
+                            cost(first, **first_degree).total_cmp(&cost(second, **second_degree))
+                        })
+                })
+                .map(|(register, _)| *register)
+                .unwrap();
+
+            degrees.remove(&register);
+            for neighbour in &graph.neighbours[&register] {
+                if let Some(degree) = degrees.get_mut(neighbour) {
+                    *degree -= 1;
+                }
+            }
+            removed.push(register);
+        }
+
+        // select: colour registers in the reverse order they were removed, spilling those whose
+        // neighbours already use every colour
+        let mut colouring: HashMap<Register, Location> = HashMap::new();
+        let mut stack_slots = 0;
+        let mut spilled_uses = 0;
+        for register in removed.into_iter().rev() {
+            let taken: Vec<_> = graph.neighbours[&register]
+                .iter()
+                .filter_map(|neighbour| colouring.get(neighbour))
+                .collect();
+            let location = match available
+                .iter()
+                .find(|xmm| !taken.contains(&&Location::Xmm(**xmm)))
+            {
+                Some(xmm) => Location::Xmm(*xmm),
+                None => {
+                    stack_slots += 1;
+                    spilled_uses += uses.get(&register).copied().unwrap_or(0);
+                    Location::Stack(stack_slots - 1)
+                }
+            };
+            colouring.insert(register, location);
+        }
+
+        let mut locations = colouring.clone();
+        for register in aliases.keys() {
+            locations.insert(*register, colouring[&find(&aliases, *register)]);
+        }
+
+        Assignment {
+            locations,
+            stack_slots,
+            spilled_uses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::compile::assemble::Assemblable;
+    use crate::compile::emit::{self, Options};
+    use crate::compile::flatten;
+    use crate::compile::register_alloc::LinearScan;
+    use crate::eval;
+    use crate::harness::generate::{random_network, random_value};
+    use crate::ir::bytes::Bytes;
+    use crate::ir::expr::{self, Operator};
+    use crate::ir::register::Statement;
+    use crate::jit::executable::Function;
+    use crate::neurons::neuron::Neuron;
+
+    fn r(index: usize) -> Register {
+        Register { index }
+    }
+
+    #[test]
+    fn moves_coalesce() {
+        // %2 = %0; %2 += %1; %3 = %2; %3 *= %3
+        let statement = |destination, expr| Statement {
+            destination: r(destination),
+            expr,
+        };
+        let program = Program {
+            input: vec![r(0), r(1)],
+            statements: vec![
+                statement(2, Expr::Move(Value::Register(r(0)))),
+                statement(
+                    2,
+                    Expr::Operation {
+                        operator: Operator::Add,
+                        operand: Value::Register(r(1)),
+                    },
+                ),
+                statement(3, Expr::Move(Value::Register(r(2)))),
+                statement(
+                    3,
+                    Expr::Operation {
+                        operator: Operator::Multiply,
+                        operand: Value::Register(r(3)),
+                    },
+                ),
+            ],
+            output: vec![Value::Register(r(3))],
+        };
+
+        let assignment = GraphColouring::default().assign(&program, &emit::allocatable());
+        assert_eq!(assignment.registers(), 2, "{:?}", assignment);
+        for (first, second) in [(0, 2), (2, 3)] {
+            assert_eq!(
+                assignment.location(r(first)),
+                assignment.location(r(second))
+            );
+        }
+        assert_ne!(assignment.location(r(1)), assignment.location(r(2)));
+
+        let uncoalesced = GraphColouring { coalesce: false }.assign(&program, &emit::allocatable());
+        assert_eq!(uncoalesced.registers(), 2, "{:?}", uncoalesced);
+    }
+
+    #[test]
+    fn colours_at_most_as_many_as_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut totals = [0; 2];
+        for _ in 0..32 {
+            let network = random_network(&mut rng);
+            let size = network.size();
+            let data: Vec<_> = (0..size.data).map(expr::Expr::Variable).collect();
+            let input: Vec<_> = (0..size.input)
+                .map(|i| expr::Expr::Variable(i + size.data))
+                .collect();
+            let program = flatten::to_program(
+                &network.evaluate(&input, &data),
+                (0..data.len() + input.len()).map(r).collect(),
+            );
+
+            let all = emit::allocatable();
+            let colouring = GraphColouring::default().assign(&program, &all);
+            let linear = LinearScan.assign(&program, &all);
+            if colouring.stack_slots == 0 && linear.stack_slots == 0 {
+                totals[0] += colouring.registers();
+                totals[1] += linear.registers();
+            }
+
+            // a small budget forces spilling
+            let assignment = GraphColouring::default().assign(&program, &all[..3]);
+            let mut bytes = Bytes::new();
+            emit::emit_program(&program, &assignment, &Options::default()).assemble(&mut bytes);
+            let function =
+                unsafe { Function::new(&bytes, program.input.len(), program.output.len()) }
+                    .unwrap();
+            let values: Vec<_> = program
+                .input
+                .iter()
+                .map(|_| random_value(&mut rng))
+                .collect();
+            let env = program.input.iter().copied().zip(values.iter().copied());
+            let bits = |values: Vec<f32>| -> Vec<_> {
+                values.iter().map(|value| value.to_bits()).collect()
+            };
+            assert_eq!(
+                bits(function.call(&values)),
+                bits(eval::register::evaluate(&program, env.collect()))
+            );
+        }
+        assert!(totals[0] <= totals[1], "{:?}", totals);
+    }
+}
@@ -0,0 +1,1321 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::bounded::Bounded;
+use crate::ir::asm::{
+    Arithmetic, Base, Compare, Condition, FloatAssign, FloatOperation, FusedMultiplyAdd, Index,
+    Instruction, IntegerAssign, Jump, Label, Logical, LogicalAssign, LogicalOperation, Memory,
+    Move, Operand, Program, ScaleFactor, Stack, Xmm, R,
+};
+use crate::ir::bytes::{ImpliedPrefix, OpcodeMap};
+use crate::ir::expr::Operator;
+use crate::ir::target::Target;
+
+/// Why bytes couldn't be decoded
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The bytes end partway through the instruction at `offset`
+    Truncated { offset: usize },
+    /// The instruction at `offset` isn't one that `asm` models
+    Unsupported { offset: usize },
+}
+
+/// Reads the bytes of the instruction starting at `start`
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    start: usize,
+    offset: usize,
+}
+
+impl Cursor<'_> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .ok_or(Error::Truncated { offset: self.start })?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        for byte in &mut array {
+            *byte = self.byte()?;
+        }
+        Ok(array)
+    }
+
+    fn unsupported(&self) -> Error {
+        Error::Unsupported { offset: self.start }
+    }
+
+    /// The label of the instruction `displacement` bytes after the end of the current one, which
+    /// is indexed by its offset
+    fn target(&self, displacement: i64) -> Result<Label, Error> {
+        let index =
+            usize::try_from(self.offset as i64 + displacement).map_err(|_| self.unsupported())?;
+        Ok(Label { index })
+    }
+}
+
+/// The W, R, X and B bits of a REX or VEX prefix
+#[derive(Copy, Clone, Default)]
+struct Rex {
+    w: bool,
+    r: bool,
+    x: bool,
+    b: bool,
+}
+
+/// Everything before the last opcode byte
+struct Prefixes {
+    rex: Rex,
+    prefix: ImpliedPrefix,
+    /// The escape bytes before the opcode, if any
+    map: Option<OpcodeMap>,
+    /// The extra source register of a VEX encoded instruction
+    vvvv: Option<u8>,
+    /// Whether a VEX prefix selects 256 bit vectors
+    wide: bool,
+}
+
+fn prefixes(cursor: &mut Cursor) -> Result<(Prefixes, u8), Error> {
+    let mut byte = cursor.byte()?;
+    let prefix = match byte {
+        0x66 => ImpliedPrefix::OperandSize,
+        0xf3 => ImpliedPrefix::Repeat,
+        0xf2 => ImpliedPrefix::RepeatNotZero,
+        _ => ImpliedPrefix::None,
+    };
+    if prefix != ImpliedPrefix::None {
+        byte = cursor.byte()?;
+    }
+
+    let mut rex = Rex::default();
+    if byte & 0xf0 == 0x40 {
+        rex = Rex {
+            w: byte & 0b1000 != 0,
+            r: byte & 0b0100 != 0,
+            x: byte & 0b0010 != 0,
+            b: byte & 0b0001 != 0,
+        };
+        byte = cursor.byte()?;
+    }
+
+    match byte {
+        // VEX prefixes can't follow a legacy or REX prefix
+        0xc4 | 0xc5 if prefix == ImpliedPrefix::None && !rex.any() => vex(cursor, byte),
+        0x0f => {
+            let (map, opcode) = match cursor.byte()? {
+                0x38 => (OpcodeMap::Escape38, cursor.byte()?),
+                0x3a => (OpcodeMap::Escape3A, cursor.byte()?),
+                opcode => (OpcodeMap::Escape, opcode),
+            };
+            let prefixes = Prefixes {
+                rex,
+                prefix,
+                map: Some(map),
+                vvvv: None,
+                wide: false,
+            };
+            Ok((prefixes, opcode))
+        }
+        opcode => {
+            let prefixes = Prefixes {
+                rex,
+                prefix,
+                map: None,
+                vvvv: None,
+                wide: false,
+            };
+            Ok((prefixes, opcode))
+        }
+    }
+}
+
+impl Rex {
+    fn any(self) -> bool {
+        self.w || self.r || self.x || self.b
+    }
+}
+
+/// Reads the rest of a VEX prefix starting with `first`, and the opcode after it
+fn vex(cursor: &mut Cursor, first: u8) -> Result<(Prefixes, u8), Error> {
+    let bit = |byte: u8, index: u8| byte & (1 << index) != 0;
+    // R, X, B and vvvv are stored inverted
+    let (rex, map, last) = if first == 0xc5 {
+        let last = cursor.byte()?;
+        let rex = Rex {
+            r: !bit(last, 7),
+            ..Rex::default()
+        };
+        (rex, OpcodeMap::Escape, last)
+    } else {
+        let middle = cursor.byte()?;
+        let last = cursor.byte()?;
+        let map = match middle & 0b11111 {
+            0b00001 => OpcodeMap::Escape,
+            0b00010 => OpcodeMap::Escape38,
+            0b00011 => OpcodeMap::Escape3A,
+            _ => return Err(cursor.unsupported()),
+        };
+        let rex = Rex {
+            w: bit(last, 7),
+            r: !bit(middle, 7),
+            x: !bit(middle, 6),
+            b: !bit(middle, 5),
+        };
+        (rex, map, last)
+    };
+
+    let prefix = match last & 0b11 {
+        0b00 => ImpliedPrefix::None,
+        0b01 => ImpliedPrefix::OperandSize,
+        0b10 => ImpliedPrefix::Repeat,
+        _ => ImpliedPrefix::RepeatNotZero,
+    };
+    let prefixes = Prefixes {
+        rex,
+        prefix,
+        map: Some(map),
+        vvvv: Some(!(last >> 3) & 0b1111),
+        wide: bit(last, 2),
+    };
+    Ok((prefixes, cursor.byte()?))
+}
+
+/// The operand in the rm field of ModR/M
+enum Rm {
+    Register(u8),
+    Memory(Memory),
+}
+
+fn register(number: u8) -> R {
+    match number {
+        0 => R::Rax,
+        1 => R::Rcx,
+        2 => R::Rdx,
+        3 => R::Rbx,
+        4 => R::Rsp,
+        5 => R::Rbp,
+        6 => R::Rsi,
+        7 => R::Rdi,
+        8..=11 => R::RLow(Bounded::try_from(i64::from(number)).unwrap()),
+        _ => R::RHigh(Bounded::try_from(i64::from(number)).unwrap()),
+    }
+}
+
+fn xmm(number: u8) -> Xmm {
+    Xmm::try_from(i64::from(number)).unwrap()
+}
+
+/// Reads the ModR/M byte, and any SIB byte and displacement after it, returning the 4 bit reg
+/// field and the rm operand. A %rip relative displacement is resolved against the constant pool
+/// at `constants`, assuming the instruction ends with it and an `immediate` of that many bytes.
+fn mod_reg_rm(
+    cursor: &mut Cursor,
+    rex: Rex,
+    constants: usize,
+    immediate: usize,
+) -> Result<(u8, Rm), Error> {
+    let byte = cursor.byte()?;
+    let mod_ = byte >> 6;
+    let reg = ((byte >> 3) & 0b111) | (u8::from(rex.r) << 3);
+    let rm = byte & 0b111;
+
+    if mod_ == 0b11 {
+        return Ok((reg, Rm::Register(rm | (u8::from(rex.b) << 3))));
+    }
+
+    if mod_ == 0b00 && rm == 0b101 {
+        let displacement = i32::from_le_bytes(cursor.array()?);
+        let end = cursor.offset + immediate;
+        let offset = usize::try_from(end as i64 + displacement as i64 - constants as i64)
+            .map_err(|_| cursor.unsupported())?;
+        let memory = Memory {
+            displacement: (offset % 4) as i32,
+            base: Base::Constant(offset / 4),
+            index: None,
+        };
+        return Ok((reg, Rm::Memory(memory)));
+    }
+
+    let (base, index) = if rm == 0b100 {
+        let sib = cursor.byte()?;
+        let scale = match sib >> 6 {
+            0b00 => ScaleFactor::S1,
+            0b01 => ScaleFactor::S2,
+            0b10 => ScaleFactor::S4,
+            _ => ScaleFactor::S8,
+        };
+        let index = ((sib >> 3) & 0b111) | (u8::from(rex.x) << 3);
+        let base = sib & 0b111;
+        // a base of 101 with mod 00 means there is no base
+        if mod_ == 0b00 && base == 0b101 {
+            return Err(cursor.unsupported());
+        }
+        // an index of %rsp means there is no index
+        let index = match (index, scale) {
+            (0b0100, ScaleFactor::S1) => None,
+            (0b0100, _) => return Err(cursor.unsupported()),
+            (index, scale) => Some(Index {
+                index: register(index),
+                scale,
+            }),
+        };
+        (base, index)
+    } else {
+        (rm, None)
+    };
+
+    let displacement = match mod_ {
+        0b00 => 0,
+        0b01 => i32::from(i8::from_le_bytes(cursor.array()?)),
+        _ => i32::from_le_bytes(cursor.array()?),
+    };
+    let memory = Memory {
+        displacement,
+        base: Base::R(register(base | (u8::from(rex.b) << 3))),
+        index,
+    };
+    Ok((reg, Rm::Memory(memory)))
+}
+
+fn operator(opcode: u8) -> Option<Operator> {
+    match opcode {
+        0x58 => Some(Operator::Add),
+        0x5c => Some(Operator::Subtract),
+        0x59 => Some(Operator::Multiply),
+        0x5e => Some(Operator::Divide),
+        _ => None,
+    }
+}
+
+fn condition(cursor: &mut Cursor) -> Result<Condition, Error> {
+    Ok(match cursor.byte()? {
+        0 => Condition::Equal,
+        1 => Condition::LessThan,
+        2 => Condition::LessEqual,
+        3 => Condition::Unordered,
+        4 => Condition::NotEqual,
+        5 => Condition::NotLessThan,
+        6 => Condition::NotLessEqual,
+        7 => Condition::Ordered,
+        _ => return Err(cursor.unsupported()),
+    })
+}
+
+/// Decodes the instruction starting at `start` in `bytes`, whose constant pool starts at
+/// `constants`, returning it along with its length.
+///
+/// Jumps can't name the label they target, so they target a label whose index is the offset of
+/// the target instead.
+pub fn decode_instruction(
+    bytes: &[u8],
+    start: usize,
+    constants: usize,
+) -> Result<(Instruction, usize), Error> {
+    let mut cursor = Cursor {
+        bytes,
+        start,
+        offset: start,
+    };
+    let (prefixes, opcode) = prefixes(&mut cursor)?;
+    let instruction = match prefixes.vvvv {
+        Some(vvvv) => decode_vex(&mut cursor, &prefixes, vvvv, opcode, constants)?,
+        None => decode_legacy(&mut cursor, &prefixes, opcode, constants)?,
+    };
+    Ok((instruction, cursor.offset - start))
+}
+
+fn decode_vex(
+    cursor: &mut Cursor,
+    prefixes: &Prefixes,
+    vvvv: u8,
+    opcode: u8,
+    constants: usize,
+) -> Result<Instruction, Error> {
+    let Prefixes {
+        rex,
+        prefix,
+        map,
+        wide,
+        ..
+    } = *prefixes;
+    if rex.w {
+        return Err(cursor.unsupported());
+    }
+    let mod_reg_rm = |cursor: &mut Cursor, immediate| mod_reg_rm(cursor, rex, constants, immediate);
+    let operands = |cursor: &mut Cursor| -> Result<_, Error> {
+        let (reg, rm) = mod_reg_rm(cursor, 0)?;
+        let value = match rm {
+            Rm::Register(value) => Operand::Xmm(xmm(value)),
+            Rm::Memory(value) => Operand::Memory(value),
+        };
+        Ok((xmm(reg), xmm(vvvv), value))
+    };
+    let registers = |cursor: &mut Cursor, immediate| match mod_reg_rm(cursor, immediate)? {
+        (reg, Rm::Register(rm)) => Ok((xmm(reg), xmm(rm))),
+        (_, Rm::Memory(_)) => Err(cursor.unsupported()),
+    };
+    let memory = |cursor: &mut Cursor, immediate| match mod_reg_rm(cursor, immediate)? {
+        (reg, Rm::Memory(memory)) => Ok((xmm(reg), memory)),
+        (_, Rm::Register(_)) => Err(cursor.unsupported()),
+    };
+    // instructions without an extra source leave vvvv as 1111, which is 0 once inverted
+    if vvvv != 0
+        && matches!(
+            (wide, map, opcode),
+            (false, Some(OpcodeMap::Escape), 0x77)
+                | (false, Some(OpcodeMap::Escape3A), 0x17)
+                | (true, Some(OpcodeMap::Escape), 0x10 | 0x11 | 0x28 | 0x29)
+                | (true, Some(OpcodeMap::Escape38), 0x18)
+                | (true, Some(OpcodeMap::Escape3A), 0x19)
+        )
+    {
+        return Err(cursor.unsupported());
+    }
+
+    let instruction = match (wide, prefix, map, opcode) {
+        (false, ImpliedPrefix::Repeat, Some(OpcodeMap::Escape), _)
+            if operator(opcode).is_some() =>
+        {
+            let (dest, first, value) = operands(cursor)?;
+            Instruction::ArithmeticOperation(Arithmetic::FloatOperation(FloatOperation {
+                operator: operator(opcode).unwrap(),
+                dest,
+                first,
+                value,
+            }))
+        }
+        (false, ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape38), 0xb9) => {
+            let (dest, first, value) = operands(cursor)?;
+            Instruction::ArithmeticOperation(Arithmetic::FusedMultiplyAdd(FusedMultiplyAdd {
+                dest,
+                first,
+                value,
+            }))
+        }
+        (false, ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x77) => Instruction::ZeroUpper,
+        (false, ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape3A), 0x17) => {
+            let (src, dest) = memory(cursor, 1)?;
+            let lane = cursor.byte()?;
+            if lane >= 4 {
+                return Err(cursor.unsupported());
+            }
+            Instruction::Move(Move::LaneToMemory { dest, src, lane })
+        }
+        (true, ImpliedPrefix::None, Some(OpcodeMap::Escape), _) if operator(opcode).is_some() => {
+            let (dest, first, value) = operands(cursor)?;
+            Instruction::ArithmeticOperation(Arithmetic::WideOperation(FloatOperation {
+                operator: operator(opcode).unwrap(),
+                dest,
+                first,
+                value,
+            }))
+        }
+        (true, ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x10) => {
+            let (dest, src) = memory(cursor, 0)?;
+            Instruction::Move(Move::WideFromMemory { dest, src })
+        }
+        (true, ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x11) => {
+            let (src, dest) = memory(cursor, 0)?;
+            Instruction::Move(Move::WideToMemory { dest, src })
+        }
+        (true, ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x28) => {
+            let (dest, src) = registers(cursor, 0)?;
+            Instruction::Move(Move::WideToWide { dest, src })
+        }
+        (true, ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x29) => {
+            let (src, dest) = registers(cursor, 0)?;
+            Instruction::Move(Move::WideToWide { dest, src })
+        }
+        (true, ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x54..=0x56) => {
+            let (dest, value) = registers(cursor, 0)?;
+            let operator = match opcode {
+                0x54 => Logical::And,
+                0x55 => Logical::AndNot,
+                _ => Logical::Or,
+            };
+            Instruction::ArithmeticOperation(Arithmetic::WideLogical(LogicalOperation {
+                operator,
+                dest,
+                first: xmm(vvvv),
+                value,
+            }))
+        }
+        (true, ImpliedPrefix::None, Some(OpcodeMap::Escape), 0xc2) => {
+            let (dest, value) = registers(cursor, 1)?;
+            Instruction::Compare(Compare::WideCompareToMask {
+                condition: condition(cursor)?,
+                dest,
+                first: xmm(vvvv),
+                value,
+            })
+        }
+        (true, ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape38), 0x18) => {
+            let (dest, src) = memory(cursor, 0)?;
+            Instruction::Move(Move::BroadcastFromMemory { dest, src })
+        }
+        (false, ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape3A), 0x21) => {
+            let (dest, src) = memory(cursor, 1)?;
+            // the destination lane is in bits 4 and 5 of the immediate, and nothing else is set
+            let immediate = cursor.byte()?;
+            if immediate & 0b1100_1111 != 0 {
+                return Err(cursor.unsupported());
+            }
+            Instruction::Move(Move::InsertFromMemory {
+                dest,
+                first: xmm(vvvv),
+                src,
+                lane: immediate >> 4,
+            })
+        }
+        (true, ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape3A), 0x18) => {
+            let (dest, src) = registers(cursor, 1)?;
+            if cursor.byte()? != 1 {
+                return Err(cursor.unsupported());
+            }
+            Instruction::Move(Move::XmmToHigh {
+                dest,
+                first: xmm(vvvv),
+                src,
+            })
+        }
+        (true, ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape3A), 0x4a) => {
+            let (dest, src) = registers(cursor, 1)?;
+            // the mask register is in the high 4 bits of the immediate
+            let mask = cursor.byte()?;
+            if mask & 0b1111 != 0 {
+                return Err(cursor.unsupported());
+            }
+            Instruction::Move(Move::WideBlendWithMask {
+                dest,
+                first: xmm(vvvv),
+                src,
+                mask: xmm(mask >> 4),
+            })
+        }
+        (true, ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape3A), 0x19) => {
+            let (src, dest) = registers(cursor, 1)?;
+            if cursor.byte()? != 1 {
+                return Err(cursor.unsupported());
+            }
+            Instruction::Move(Move::HighToXmm { dest, src })
+        }
+        _ => return Err(cursor.unsupported()),
+    };
+    Ok(instruction)
+}
+
+fn decode_legacy(
+    cursor: &mut Cursor,
+    prefixes: &Prefixes,
+    opcode: u8,
+    constants: usize,
+) -> Result<Instruction, Error> {
+    let Prefixes {
+        rex, prefix, map, ..
+    } = *prefixes;
+    // only the integer arithmetic is done on 64 bit operands
+    if rex.w && !(map.is_none() && matches!(opcode, 0x81 | 0x83)) {
+        return Err(cursor.unsupported());
+    }
+    let opcode_register = |base: u8| register((opcode - base) | (u8::from(rex.b) << 3));
+    // none of the legacy instructions with a memory operand have an immediate
+    let mod_reg_rm = |cursor: &mut Cursor| mod_reg_rm(cursor, rex, constants, 0);
+    let xmm_registers = |cursor: &mut Cursor| match mod_reg_rm(cursor)? {
+        (reg, Rm::Register(rm)) => Ok((xmm(reg), xmm(rm))),
+        (_, Rm::Memory(_)) => Err(cursor.unsupported()),
+    };
+    let xmm_operand = |cursor: &mut Cursor| {
+        mod_reg_rm(cursor).map(|(reg, rm)| match rm {
+            Rm::Register(rm) => (xmm(reg), Operand::Xmm(xmm(rm))),
+            Rm::Memory(memory) => (xmm(reg), Operand::Memory(memory)),
+        })
+    };
+    let xmm_memory = |cursor: &mut Cursor| match mod_reg_rm(cursor)? {
+        (reg, Rm::Memory(memory)) => Ok((xmm(reg), memory)),
+        (_, Rm::Register(_)) => Err(cursor.unsupported()),
+    };
+
+    let instruction = match (prefix, map, opcode) {
+        (ImpliedPrefix::None, None, 0xc3) => Instruction::Return,
+        (ImpliedPrefix::None, None, 0x50..=0x57) => {
+            Instruction::Stack(Stack::Push(opcode_register(0x50)))
+        }
+        (ImpliedPrefix::None, None, 0x58..=0x5f) => {
+            Instruction::Stack(Stack::Pop(opcode_register(0x58)))
+        }
+        (ImpliedPrefix::None, None, 0xb8..=0xbf) => Instruction::Move(Move::IntegerFromConstant {
+            dest: opcode_register(0xb8),
+            src: i32::from_le_bytes(cursor.array()?),
+        }),
+        (ImpliedPrefix::None, None, 0x89) => match mod_reg_rm(cursor)? {
+            (src, Rm::Memory(dest)) => Instruction::Move(Move::IntegerToMemory {
+                dest,
+                src: register(src),
+            }),
+            (_, Rm::Register(_)) => return Err(cursor.unsupported()),
+        },
+        (ImpliedPrefix::None, None, 0x81 | 0x83) if rex.w => {
+            let (extension, Rm::Register(dest)) = mod_reg_rm(cursor)? else {
+                return Err(cursor.unsupported());
+            };
+            let value = if opcode == 0x83 {
+                i32::from(i8::from_le_bytes(cursor.array()?))
+            } else {
+                i32::from_le_bytes(cursor.array()?)
+            };
+            let assign = IntegerAssign {
+                dest: register(dest),
+                value: u32::try_from(value).map_err(|_| cursor.unsupported())?,
+            };
+            Instruction::ArithmeticOperation(match extension {
+                0 => Arithmetic::IntegerAddAssign(assign),
+                5 => Arithmetic::IntegerSubAssign(assign),
+                _ => return Err(cursor.unsupported()),
+            })
+        }
+        (ImpliedPrefix::None, None, 0xeb | 0x73) => {
+            let displacement = i8::from_le_bytes(cursor.array()?);
+            let target = cursor.target(i64::from(displacement))?;
+            Instruction::Jump(if opcode == 0xeb {
+                Jump::Unconditional { target }
+            } else {
+                Jump::AboveEqual { target }
+            })
+        }
+        (ImpliedPrefix::None, None, 0xe9) => {
+            let displacement = i32::from_le_bytes(cursor.array()?);
+            let target = cursor.target(i64::from(displacement))?;
+            Instruction::Jump(Jump::Unconditional { target })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x83) => {
+            let displacement = i32::from_le_bytes(cursor.array()?);
+            let target = cursor.target(i64::from(displacement))?;
+            Instruction::Jump(Jump::AboveEqual { target })
+        }
+        (ImpliedPrefix::Repeat, Some(OpcodeMap::Escape), 0x10) => match xmm_operand(cursor)? {
+            (dest, Operand::Xmm(src)) => Instruction::Move(Move::FloatToFloat { dest, src }),
+            (dest, Operand::Memory(src)) => Instruction::Move(Move::FloatFromMemory { dest, src }),
+        },
+        (ImpliedPrefix::Repeat, Some(OpcodeMap::Escape), 0x11) => {
+            let (src, dest) = xmm_memory(cursor)?;
+            Instruction::Move(Move::FloatToMemory { dest, src })
+        }
+        (ImpliedPrefix::Repeat, Some(OpcodeMap::Escape), 0xc2) => {
+            let (dest, value) = xmm_registers(cursor)?;
+            Instruction::Compare(Compare::CompareToMask {
+                condition: condition(cursor)?,
+                dest,
+                value,
+            })
+        }
+        (ImpliedPrefix::Repeat | ImpliedPrefix::None, Some(OpcodeMap::Escape), _)
+            if operator(opcode).is_some() =>
+        {
+            let (dest, value) = xmm_operand(cursor)?;
+            let assign = FloatAssign {
+                operator: operator(opcode).unwrap(),
+                dest,
+                value,
+            };
+            Instruction::ArithmeticOperation(if prefix == ImpliedPrefix::Repeat {
+                Arithmetic::FloatAssign(assign)
+            } else {
+                Arithmetic::PackedAssign(assign)
+            })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x10) => {
+            let (dest, src) = xmm_memory(cursor)?;
+            Instruction::Move(Move::PackedFromMemory { dest, src })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x11) => {
+            let (src, dest) = xmm_memory(cursor)?;
+            Instruction::Move(Move::PackedToMemory { dest, src })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x28) => {
+            let (dest, src) = xmm_registers(cursor)?;
+            Instruction::Move(Move::PackedToPacked { dest, src })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0xc6) => {
+            let (dest, src) = xmm_registers(cursor)?;
+            Instruction::Move(Move::Shuffle {
+                dest,
+                src,
+                selector: cursor.byte()?,
+            })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x14) => {
+            let (dest, src) = xmm_registers(cursor)?;
+            Instruction::Move(Move::InterleaveLow { dest, src })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x16) => {
+            let (dest, src) = xmm_registers(cursor)?;
+            Instruction::Move(Move::LowToHigh { dest, src })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0xc2) => {
+            let (dest, value) = xmm_registers(cursor)?;
+            Instruction::Compare(Compare::PackedCompareToMask {
+                condition: condition(cursor)?,
+                dest,
+                value,
+            })
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x54..=0x56) => {
+            let (dest, value) = xmm_registers(cursor)?;
+            let operator = match opcode {
+                0x54 => Logical::And,
+                0x55 => Logical::AndNot,
+                _ => Logical::Or,
+            };
+            Instruction::ArithmeticOperation(Arithmetic::LogicalAssign(LogicalAssign {
+                operator,
+                dest,
+                value,
+            }))
+        }
+        (ImpliedPrefix::None, Some(OpcodeMap::Escape), 0x2f) => {
+            let (first, second) = xmm_registers(cursor)?;
+            Instruction::Compare(Compare::CompareFloats { first, second })
+        }
+        (ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape), 0x6e) => match mod_reg_rm(cursor)? {
+            (dest, Rm::Register(src)) => Instruction::Move(Move::FloatFromInteger {
+                dest: xmm(dest),
+                src: register(src),
+            }),
+            (_, Rm::Memory(_)) => return Err(cursor.unsupported()),
+        },
+        (ImpliedPrefix::OperandSize, Some(OpcodeMap::Escape38), 0x14) => {
+            let (dest, src) = xmm_registers(cursor)?;
+            Instruction::Move(Move::BlendWithMask { dest, src })
+        }
+        _ => return Err(cursor.unsupported()),
+    };
+    Ok(instruction)
+}
+
+/// The offset the code of `bytes` ends at, before any int3 padding in front of the constant pool
+fn code_end(bytes: &[u8], constants: usize) -> usize {
+    let end = constants.min(bytes.len());
+    // int3 isn't modelled, so no instruction starts with it, but one could end with 0xcc
+    let mut offset = 0;
+    while offset < end && !bytes[offset..end].iter().all(|byte| *byte == 0xcc) {
+        match decode_instruction(&bytes[..end], offset, constants) {
+            Ok((_, length)) => offset += length,
+            Err(_) => return end,
+        }
+    }
+    offset
+}
+
+/// Decodes a program assembled into `bytes`, whose constant pool starts at `constants`, as
+/// `Bytes::constants` reports. Labels are numbered in the order they appear, and the target is
+/// the least capable one that has every instruction.
+pub fn decode_program(bytes: &[u8], constants: usize) -> Result<Program, Error> {
+    let end = code_end(bytes, constants);
+    let mut decoded = Vec::new();
+    let mut offset = 0;
+    while offset < end {
+        let (instruction, length) = decode_instruction(&bytes[..end], offset, constants)?;
+        decoded.push((offset, instruction));
+        offset += length;
+    }
+
+    // a jump has to land on the start of an instruction, or the end of the code
+    let starts: BTreeSet<usize> = decoded.iter().map(|(offset, _)| *offset).collect();
+    let mut targets = BTreeSet::new();
+    for (offset, instruction) in &decoded {
+        if let Instruction::Jump(jump) = instruction {
+            let target = jump.target().index;
+            if !starts.contains(&target) && target != end {
+                return Err(Error::Unsupported { offset: *offset });
+            }
+            targets.insert(target);
+        }
+    }
+    let labels: HashMap<usize, Label> = targets
+        .iter()
+        .enumerate()
+        .map(|(index, offset)| (*offset, Label { index }))
+        .collect();
+
+    let mut instructions = Vec::new();
+    for (offset, mut instruction) in decoded {
+        if let Some(label) = labels.get(&offset) {
+            instructions.push(Instruction::Label(*label));
+        }
+        if let Instruction::Jump(Jump::Unconditional { target } | Jump::AboveEqual { target }) =
+            &mut instruction
+        {
+            *target = labels[&target.index];
+        }
+        instructions.push(instruction);
+    }
+    if let Some(label) = labels.get(&end) {
+        instructions.push(Instruction::Label(*label));
+    }
+
+    let pool = bytes.get(constants..).unwrap_or_default();
+    Ok(Program {
+        target: instructions
+            .iter()
+            .map(Target::required)
+            .max()
+            .unwrap_or_default(),
+        instructions,
+        constants: pool
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+    })
+}
+
+/// A listing of `bytes`, whose constant pool starts at `constants`, with each instruction next to
+/// its offset and raw bytes, followed by the constants. Jumps show the offset they target as the
+/// index of their label. Decoding stops at the first instruction that isn't modelled.
+pub fn listing(bytes: &[u8], constants: usize) -> String {
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let mut listing = String::new();
+    let end = code_end(bytes, constants);
+
+    let mut offset = 0;
+    while offset < end {
+        match decode_instruction(&bytes[..end], offset, constants) {
+            Ok((instruction, length)) => {
+                let raw = hex(&bytes[offset..offset + length]);
+                writeln!(listing, "{:6x}: {:<32}{:?}", offset, raw, instruction).unwrap();
+                offset += length;
+            }
+            Err(error) => {
+                let raw = hex(&bytes[offset..end]);
+                writeln!(listing, "{:6x}: {:<32}{:?}", offset, raw, error).unwrap();
+                return listing;
+            }
+        }
+    }
+
+    let pool_start = constants.min(bytes.len());
+    if end < pool_start {
+        let raw = hex(&bytes[end..pool_start]);
+        writeln!(listing, "{:6x}: {:<32}padding", end, raw).unwrap();
+    }
+    for (index, chunk) in bytes[pool_start..].chunks(4).enumerate() {
+        let offset = pool_start + index * 4;
+        match <[u8; 4]>::try_from(chunk) {
+            Ok(constant) => writeln!(
+                listing,
+                "{:6x}: {:<32}{}",
+                offset,
+                hex(chunk),
+                f32::from_le_bytes(constant)
+            ),
+            Err(_) => writeln!(listing, "{:6x}: {}", offset, hex(chunk)),
+        }
+        .unwrap();
+    }
+    listing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compile::assemble::Assemblable;
+    use crate::compile::{emit, flatten, register_alloc, vectorise};
+    use crate::harness::generate::symbolic_outputs;
+    use crate::ir::bytes::Bytes;
+    use crate::ir::register;
+    use crate::neurons::{learning::layer, neuron::Neuron};
+
+    /// Where the constant pool is placed for single instructions
+    const CONSTANTS: usize = 0x100;
+
+    fn xmm(index: i64) -> Xmm {
+        index.try_into().unwrap()
+    }
+
+    fn r(index: u8) -> R {
+        register(index)
+    }
+
+    /// A memory operand for each way of encoding a base, index and displacement
+    fn memories() -> Vec<Memory> {
+        let memory = |base, displacement, index| Memory {
+            displacement,
+            base: Base::R(base),
+            index,
+        };
+        let index = |index, scale| Some(Index { index, scale });
+        vec![
+            memory(R::Rax, 0, None),
+            // %rsp and %r12 need a SIB byte
+            memory(R::Rsp, 4, None),
+            memory(r(12), 0, None),
+            // %rbp and %r13 always need a displacement
+            memory(R::Rbp, 0, None),
+            memory(r(13), -0x200, None),
+            memory(R::Rdi, 0x200, index(R::Rcx, ScaleFactor::S4)),
+            memory(r(9), -8, index(r(14), ScaleFactor::S8)),
+            memory(R::Rsp, 0, index(R::Rbp, ScaleFactor::S1)),
+            memory(R::Rbx, 0, index(r(10), ScaleFactor::S2)),
+            Memory {
+                displacement: 0,
+                base: Base::Constant(3),
+                index: None,
+            },
+        ]
+    }
+
+    fn assert_round_trips(instruction: Instruction) {
+        let mut bytes = Bytes::new();
+        bytes.constants_at(CONSTANTS);
+        instruction.assemble(&mut bytes);
+
+        let decoded = decode_instruction(bytes.as_slice(), 0, CONSTANTS);
+        assert_eq!(
+            decoded,
+            Ok((instruction, bytes.len())),
+            "{:02x?} decoded incorrectly",
+            bytes.as_slice()
+        );
+    }
+
+    #[test]
+    fn moves() {
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::FloatFromMemory {
+                dest: xmm(11),
+                src: memory,
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::FloatToMemory {
+                dest: memory,
+                src: xmm(2),
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::IntegerToMemory {
+                dest: memory,
+                src: r(15),
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::PackedFromMemory {
+                dest: xmm(0),
+                src: memory,
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::PackedToMemory {
+                dest: memory,
+                src: xmm(8),
+            }));
+        }
+
+        for (dest, src) in [(1, 2), (9, 3), (4, 15)] {
+            let registers = [
+                Move::FloatToFloat {
+                    dest: xmm(dest),
+                    src: xmm(src),
+                },
+                Move::BlendWithMask {
+                    dest: xmm(dest),
+                    src: xmm(src),
+                },
+                Move::PackedToPacked {
+                    dest: xmm(dest),
+                    src: xmm(src),
+                },
+                Move::Shuffle {
+                    dest: xmm(dest),
+                    src: xmm(src),
+                    selector: 0b1110_0100,
+                },
+                Move::InterleaveLow {
+                    dest: xmm(dest),
+                    src: xmm(src),
+                },
+                Move::LowToHigh {
+                    dest: xmm(dest),
+                    src: xmm(src),
+                },
+                Move::FloatFromInteger {
+                    dest: xmm(dest),
+                    src: r(src as u8),
+                },
+            ];
+            for mov in registers {
+                assert_round_trips(Instruction::Move(mov));
+            }
+        }
+
+        for (dest, src) in [(R::Rax, 0x3f80_0000), (r(10), -1), (r(15), 7)] {
+            assert_round_trips(Instruction::Move(Move::IntegerFromConstant { dest, src }));
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        let operators = [
+            Operator::Add,
+            Operator::Subtract,
+            Operator::Multiply,
+            Operator::Divide,
+        ];
+        let operands = || {
+            let registers = [0, 9].map(|index| Operand::Xmm(xmm(index)));
+            registers
+                .into_iter()
+                .chain(memories().into_iter().map(Operand::Memory))
+        };
+
+        for operator in operators {
+            for value in operands() {
+                assert_round_trips(Instruction::ArithmeticOperation(Arithmetic::FloatAssign(
+                    FloatAssign {
+                        operator,
+                        dest: xmm(10),
+                        value,
+                    },
+                )));
+            }
+            for value in operands() {
+                assert_round_trips(Instruction::ArithmeticOperation(Arithmetic::PackedAssign(
+                    FloatAssign {
+                        operator,
+                        dest: xmm(1),
+                        value,
+                    },
+                )));
+            }
+            for (dest, first) in [(1, 2), (12, 3), (4, 13)] {
+                for value in operands() {
+                    assert_round_trips(Instruction::ArithmeticOperation(
+                        Arithmetic::FloatOperation(FloatOperation {
+                            operator,
+                            dest: xmm(dest),
+                            first: xmm(first),
+                            value,
+                        }),
+                    ));
+                }
+            }
+        }
+
+        for value in operands() {
+            assert_round_trips(Instruction::ArithmeticOperation(
+                Arithmetic::FusedMultiplyAdd(FusedMultiplyAdd {
+                    dest: xmm(8),
+                    first: xmm(2),
+                    value,
+                }),
+            ));
+        }
+
+        for operator in [Logical::And, Logical::AndNot, Logical::Or] {
+            assert_round_trips(Instruction::ArithmeticOperation(Arithmetic::LogicalAssign(
+                LogicalAssign {
+                    operator,
+                    dest: xmm(3),
+                    value: xmm(14),
+                },
+            )));
+        }
+
+        for (dest, value) in [(R::Rsp, 16), (r(11), 1024)] {
+            assert_round_trips(Instruction::ArithmeticOperation(
+                Arithmetic::IntegerAddAssign(IntegerAssign { dest, value }),
+            ));
+            assert_round_trips(Instruction::ArithmeticOperation(
+                Arithmetic::IntegerSubAssign(IntegerAssign { dest, value }),
+            ));
+        }
+    }
+
+    #[test]
+    fn compares_and_stack() {
+        let conditions = [
+            Condition::Equal,
+            Condition::LessThan,
+            Condition::LessEqual,
+            Condition::Unordered,
+            Condition::NotEqual,
+            Condition::NotLessThan,
+            Condition::NotLessEqual,
+            Condition::Ordered,
+        ];
+        for condition in conditions {
+            assert_round_trips(Instruction::Compare(Compare::CompareToMask {
+                condition,
+                dest: xmm(0),
+                value: xmm(12),
+            }));
+            assert_round_trips(Instruction::Compare(Compare::PackedCompareToMask {
+                condition,
+                dest: xmm(9),
+                value: xmm(1),
+            }));
+        }
+        assert_round_trips(Instruction::Compare(Compare::CompareFloats {
+            first: xmm(15),
+            second: xmm(2),
+        }));
+
+        for register in [R::Rbx, R::Rbp, r(12), r(15)] {
+            assert_round_trips(Instruction::Stack(Stack::Push(register)));
+            assert_round_trips(Instruction::Stack(Stack::Pop(register)));
+        }
+        assert_round_trips(Instruction::Return);
+    }
+
+    #[test]
+    fn ymm() {
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::WideFromMemory {
+                dest: xmm(9),
+                src: memory,
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::WideToMemory {
+                dest: memory,
+                src: xmm(4),
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::BroadcastFromMemory {
+                dest: xmm(1),
+                src: memory,
+            }));
+        }
+        // the lane follows the displacement, which a %rip relative address has to account for
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::InsertFromMemory {
+                dest: xmm(3),
+                first: xmm(10),
+                src: memory,
+                lane: 2,
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::Move(Move::LaneToMemory {
+                dest: memory,
+                src: xmm(12),
+                lane: 3,
+            }));
+        }
+        for memory in memories() {
+            assert_round_trips(Instruction::ArithmeticOperation(Arithmetic::WideOperation(
+                FloatOperation {
+                    operator: Operator::Divide,
+                    dest: xmm(2),
+                    first: xmm(11),
+                    value: Operand::Memory(memory),
+                },
+            )));
+        }
+
+        for (dest, first, src) in [(1, 2, 3), (9, 3, 14), (4, 15, 0)] {
+            let [dest, first, src] = [dest, first, src].map(xmm);
+            let mut instructions = vec![
+                Instruction::Move(Move::WideToWide { dest, src }),
+                Instruction::Move(Move::XmmToHigh { dest, first, src }),
+                Instruction::Move(Move::WideBlendWithMask {
+                    dest,
+                    first,
+                    src,
+                    mask: xmm(13),
+                }),
+                Instruction::Move(Move::HighToXmm { dest, src }),
+                Instruction::Compare(Compare::WideCompareToMask {
+                    condition: Condition::LessEqual,
+                    dest,
+                    first,
+                    value: src,
+                }),
+            ];
+            for operator in [
+                Operator::Add,
+                Operator::Subtract,
+                Operator::Multiply,
+                Operator::Divide,
+            ] {
+                instructions.push(Instruction::ArithmeticOperation(Arithmetic::WideOperation(
+                    FloatOperation {
+                        operator,
+                        dest,
+                        first,
+                        value: Operand::Xmm(src),
+                    },
+                )));
+            }
+            for operator in [Logical::And, Logical::AndNot, Logical::Or] {
+                instructions.push(Instruction::ArithmeticOperation(Arithmetic::WideLogical(
+                    LogicalOperation {
+                        operator,
+                        dest,
+                        first,
+                        value: src,
+                    },
+                )));
+            }
+            for instruction in instructions {
+                assert_round_trips(instruction);
+            }
+        }
+        assert_round_trips(Instruction::ZeroUpper);
+    }
+
+    fn assert_program_round_trips(program: &Program) {
+        let mut bytes = Bytes::new();
+        program.assemble(&mut bytes);
+
+        let decoded = decode_program(bytes.as_slice(), bytes.constants()).unwrap();
+        let mut reassembled = Bytes::new();
+        decoded.assemble(&mut reassembled);
+        assert_eq!(
+            reassembled.as_slice(),
+            bytes.as_slice(),
+            "\n{}",
+            listing(bytes.as_slice(), bytes.constants())
+        );
+        assert!(decoded.target <= program.target);
+    }
+
+    #[test]
+    fn jumps() {
+        let start = Label { index: 0 };
+        let end = Label { index: 1 };
+        let copy = || {
+            Instruction::Move(Move::FloatToFloat {
+                dest: xmm(1),
+                src: xmm(2),
+            })
+        };
+        // enough copies that both jumps need 32 bit displacements
+        for copies in [1, 31] {
+            let mut instructions = vec![
+                Instruction::Label(start),
+                Instruction::Jump(Jump::AboveEqual { target: end }),
+            ];
+            instructions.extend((0..copies).map(|_| copy()));
+            instructions.push(Instruction::Return);
+            instructions.push(Instruction::Jump(Jump::Unconditional { target: start }));
+            instructions.push(Instruction::Label(end));
+            let program = Program {
+                instructions,
+                constants: vec![1.0, -2.5],
+                target: Target::default(),
+            };
+
+            let mut bytes = Bytes::new();
+            program.assemble(&mut bytes);
+            let decoded = decode_program(bytes.as_slice(), bytes.constants()).unwrap();
+            assert_eq!(decoded.instructions, program.instructions);
+            assert_eq!(decoded.constants, program.constants);
+        }
+    }
+
+    #[test]
+    fn emitted_programs() {
+        let (outputs, variables) = symbolic_outputs(&layer(3, 3).compose(layer(3, 2)));
+        let registers: Vec<_> = (0..variables)
+            .map(|index| register::Register { index })
+            .collect();
+
+        for fuse_multiply_add in [false, true] {
+            let options = flatten::Options { fuse_multiply_add };
+            let mut program = flatten::to_program_with(&outputs, registers.clone(), &options);
+            register_alloc::realloc(&mut program);
+
+            let all = emit::allocatable();
+            for available in [&all[..], &all[..0], &all[..3]] {
+                let assignment = register_alloc::assign(&program, available);
+                for options in emit::Options::supported(Target::Avx512) {
+                    assert_program_round_trips(&emit::emit_program(
+                        &program,
+                        &assignment,
+                        &options,
+                    ));
+                }
+            }
+        }
+
+        // wide enough to fill ymm registers, with trees left over for xmm registers
+        let (wide, _) = symbolic_outputs(&layer(3, 13));
+        for (outputs, lanes) in [
+            (&outputs, 1),
+            (&outputs, vectorise::LANES),
+            (&wide, vectorise::WIDE_LANES),
+        ] {
+            let options = vectorise::Options {
+                target: Target::Avx512,
+                lanes,
+                ..vectorise::Options::default()
+            };
+            assert_program_round_trips(&vectorise::emit_exprs(outputs, &options));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        // nop isn't modelled
+        assert_eq!(
+            decode_instruction(&[0xc3, 0x90], 1, 2),
+            Err(Error::Unsupported { offset: 1 })
+        );
+        // movss cut off before its ModR/M byte
+        assert_eq!(
+            decode_instruction(&[0xf3, 0x0f, 0x10], 0, 3),
+            Err(Error::Truncated { offset: 0 })
+        );
+        // vsqrtps on ymm registers
+        assert_eq!(
+            decode_instruction(&[0xc5, 0xfc, 0x51, 0xc1], 0, 4),
+            Err(Error::Unsupported { offset: 0 })
+        );
+        // a jump into the middle of the movss
+        assert_eq!(
+            decode_program(&[0xeb, 0x01, 0xf3, 0x0f, 0x10, 0xca], 6).map(|_| ()),
+            Err(Error::Unsupported { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn listings() {
+        let program = Program {
+            instructions: vec![
+                Instruction::Move(Move::FloatFromMemory {
+                    dest: xmm(0),
+                    src: Memory {
+                        displacement: 0,
+                        base: Base::Constant(0),
+                        index: None,
+                    },
+                }),
+                Instruction::Return,
+            ],
+            constants: vec![1.0],
+            target: Target::default(),
+        };
+        let mut bytes = Bytes::new();
+        program.assemble(&mut bytes);
+
+        let listing = listing(bytes.as_slice(), bytes.constants());
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", listing);
+        assert!(lines[0].starts_with("     0: f3 0f 10 05 08 00 00 00"));
+        assert!(lines[1].starts_with("     8: c3"));
+        assert!(lines[2].ends_with("padding"));
+        assert!(lines[3].starts_with("    10: 00 00 80 3f"));
+        assert!(lines[3].ends_with(" 1"));
+    }
+}
@@ -1,51 +1,356 @@
+use std::collections::HashMap;
+
+use crate::ir::target::Target;
 use crate::ir::{asm, register};
 
-fn register_access(register: register::Register) -> asm::Memory {
-    let displacement = TryInto::<i32>::try_into(register.index).unwrap() * 4;
+use super::register_alloc::{self, Assignment, Location};
+
+/// Choices about the shape of the emitted function.
+///
+/// Emitted programs follow the System V calling convention: `%rdi` points to the input vector,
+/// whose `i`th element is loaded into `program.input[i]`, and `%rsi` points to the output buffer,
+/// whose `i`th element is written with `program.output[i]`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    /// The cpu the function will run on, which must have the extensions the other options need
+    pub target: Target,
+    pub if_positive: IfPositiveLowering,
+    /// Combine a `Move` with the `Operation` on its destination that follows it into a single
+    /// three operand instruction, rather than copying the moved value first. Requires AVX.
+    pub three_operand: bool,
+}
+
+impl Options {
+    /// Every combination of options `target` can execute
+    pub fn supported(target: Target) -> Vec<Options> {
+        let mut three_operand = vec![false];
+        if target.has_avx() {
+            three_operand.push(true);
+        }
+        IfPositiveLowering::supported(target)
+            .into_iter()
+            .flat_map(|if_positive| {
+                three_operand.iter().map(move |three_operand| Options {
+                    target,
+                    if_positive,
+                    three_operand: *three_operand,
+                })
+            })
+            .collect()
+    }
+
+    /// The default options, except for making use of everything `target` has
+    pub fn for_target(target: Target) -> Options {
+        Options {
+            target,
+            three_operand: target.has_avx(),
+            ..Options::default()
+        }
+    }
+}
+
+/// How `register::Expr::IfPositive` is turned into instructions
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IfPositiveLowering {
+    /// `comiss` followed by a conditional jump over a `movss`
+    #[default]
+    Branch,
+    /// `cmpss` to build a mask, then `andps`, `andnps` and `orps` to select with it
+    Mask,
+    /// `cmpss` to build a mask, then `blendvps` to select with it. Requires SSE4.1.
+    Blend,
+}
+
+impl IfPositiveLowering {
+    /// The lowerings `target` can execute
+    pub fn supported(target: Target) -> Vec<IfPositiveLowering> {
+        let mut lowerings = vec![IfPositiveLowering::Branch, IfPositiveLowering::Mask];
+        if target.has_sse41() {
+            lowerings.push(IfPositiveLowering::Blend);
+        }
+        lowerings
+    }
+}
+
+#[derive(Default)]
+struct LabelSource {
+    unused: usize,
+}
+
+impl LabelSource {
+    fn fresh(&mut self) -> asm::Label {
+        let index = self.unused;
+        self.unused += 1;
+        asm::Label { index }
+    }
+}
+
+/// The scratch registers used to hold operands that aren't in registers, which must not be
+/// assigned to any register of the program.
+pub const SCRATCH: [i64; 3] = [0, 1, 2];
+
+/// The xmm registers available to the register allocator
+pub fn allocatable() -> Vec<asm::Xmm> {
+    (0..16)
+        .filter(|index| !SCRATCH.contains(index))
+        .map(|index| index.try_into().unwrap())
+        .collect()
+}
+
+pub fn scratch(index: usize) -> asm::Xmm {
+    SCRATCH[index].try_into().unwrap()
+}
+
+pub fn slot_access(slot: usize) -> asm::Memory {
+    let displacement = TryInto::<i32>::try_into(slot).unwrap() * 4;
 
     asm::Memory {
         displacement,
-        base: asm::R::Rsp,
+        base: asm::Base::R(asm::R::Rsp),
+        index: None,
+    }
+}
+
+pub fn input_access(index: usize) -> asm::Memory {
+    asm::Memory {
+        displacement: TryInto::<i32>::try_into(index).unwrap() * 4,
+        base: asm::Base::R(asm::R::Rdi),
+        index: None,
+    }
+}
+
+pub fn output_access(index: usize) -> asm::Memory {
+    asm::Memory {
+        displacement: TryInto::<i32>::try_into(index).unwrap() * 4,
+        base: asm::Base::R(asm::R::Rsi),
         index: None,
     }
 }
 
-fn load_constant(number: f32, register: asm::R) -> asm::Instruction {
-    asm::Instruction::Move(asm::Move::IntegerFromConstant {
-        dest: register,
-        src: i32::from_le_bytes(number.to_le_bytes()),
-    })
+/// Float literals of a program, deduplicated by their bit pattern
+#[derive(Default)]
+pub struct ConstantPool {
+    pub constants: Vec<f32>,
+    indices: HashMap<u32, usize>,
+}
+
+impl ConstantPool {
+    pub fn access(&mut self, number: f32) -> asm::Memory {
+        let index = *self.indices.entry(number.to_bits()).or_insert_with(|| {
+            self.constants.push(number);
+            self.constants.len() - 1
+        });
+
+        asm::Memory {
+            displacement: 0,
+            base: asm::Base::Constant(index),
+            index: None,
+        }
+    }
+}
+
+fn copy(dest: asm::Xmm, src: asm::Xmm) -> Option<asm::Instruction> {
+    (dest != src).then_some(asm::Instruction::Move(asm::Move::FloatToFloat {
+        dest,
+        src,
+    }))
 }
 
 fn load_value(
     value: register::Value,
     dest: asm::Xmm,
-    intermediate: asm::R,
+    assignment: &Assignment,
+    constants: &mut ConstantPool,
 ) -> Vec<asm::Instruction> {
     let mut instructions = Vec::new();
     match value {
-        register::Value::Register(register) => {
+        register::Value::Register(register) => match assignment.location(register) {
+            Location::Xmm(xmm) => instructions.extend(copy(dest, xmm)),
+            Location::Stack(slot) => {
+                instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
+                    dest,
+                    src: slot_access(slot),
+                }));
+            }
+        },
+        register::Value::Number(number) => {
             instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
                 dest,
-                src: register_access(register),
+                src: constants.access(number),
             }));
         }
-        register::Value::Number(number) => {
-            instructions.push(load_constant(number, intermediate));
-            instructions.push(asm::Instruction::Move(asm::Move::FloatFromInteger {
-                dest,
-                src: intermediate,
-            }));
+    }
+    instructions
+}
+
+/// Returns the xmm register holding `value`, loading it into `scratch` if it isn't already in one
+fn value_in_xmm(
+    value: register::Value,
+    scratch: asm::Xmm,
+    assignment: &Assignment,
+    constants: &mut ConstantPool,
+    instructions: &mut Vec<asm::Instruction>,
+) -> asm::Xmm {
+    if let register::Value::Register(register) = value {
+        if let Location::Xmm(xmm) = assignment.location(register) {
+            return xmm;
         }
     }
+    instructions.extend(load_value(value, scratch, assignment, constants));
+    scratch
+}
+
+/// Returns `value` as an operand of an SSE instruction, without loading it into a register
+fn operand_access(
+    value: register::Value,
+    assignment: &Assignment,
+    constants: &mut ConstantPool,
+) -> asm::Operand {
+    match value {
+        register::Value::Register(register) => match assignment.location(register) {
+            Location::Xmm(xmm) => asm::Operand::Xmm(xmm),
+            Location::Stack(slot) => asm::Operand::Memory(slot_access(slot)),
+        },
+        register::Value::Number(number) => asm::Operand::Memory(constants.access(number)),
+    }
+}
+
+fn store_register(
+    register: register::Register,
+    src: asm::Xmm,
+    assignment: &Assignment,
+) -> Option<asm::Instruction> {
+    match assignment.location(register) {
+        Location::Xmm(xmm) => copy(xmm, src),
+        Location::Stack(slot) => Some(asm::Instruction::Move(asm::Move::FloatToMemory {
+            dest: slot_access(slot),
+            src,
+        })),
+    }
+}
+
+/// Copies each input in `indices` into the register it is assigned, through %xmm0 if that register
+/// lives on the stack
+fn load_inputs(
+    program: &register::Program,
+    indices: &[usize],
+    assignment: &Assignment,
+) -> Vec<asm::Instruction> {
+    let mut instructions = Vec::new();
+    for index in indices {
+        match assignment.location(program.input[*index]) {
+            Location::Xmm(dest) => {
+                instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
+                    dest,
+                    src: input_access(*index),
+                }));
+            }
+            Location::Stack(slot) => {
+                let intermediate = scratch(0);
+                instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
+                    dest: intermediate,
+                    src: input_access(*index),
+                }));
+                instructions.push(asm::Instruction::Move(asm::Move::FloatToMemory {
+                    dest: slot_access(slot),
+                    src: intermediate,
+                }));
+            }
+        }
+    }
+    instructions
+}
+
+/// The integer register written by `instruction`, if any
+fn written_integer_register(instruction: &asm::Instruction) -> Option<asm::R> {
+    match instruction {
+        asm::Instruction::Move(asm::Move::IntegerFromConstant { dest, .. }) => Some(*dest),
+        asm::Instruction::ArithmeticOperation(
+            asm::Arithmetic::IntegerAddAssign(asm::IntegerAssign { dest, .. })
+            | asm::Arithmetic::IntegerSubAssign(asm::IntegerAssign { dest, .. }),
+        ) => Some(*dest),
+        _ => None,
+    }
+}
+
+/// Surrounds `body` with pushes and pops of the callee-saved registers it writes, followed by a
+/// `ret`. `%rsp` is excluded, as the body is expected to restore it itself.
+fn save_registers(body: Vec<asm::Instruction>) -> Vec<asm::Instruction> {
+    let mut saved = Vec::new();
+    for register in body.iter().filter_map(written_integer_register) {
+        if register.is_callee_saved() && register != asm::R::Rsp && !saved.contains(&register) {
+            saved.push(register);
+        }
+    }
+
+    let mut instructions: Vec<_> = saved
+        .iter()
+        .map(|register| asm::Instruction::Stack(asm::Stack::Push(*register)))
+        .collect();
+    instructions.extend(body);
+    instructions.extend(
+        saved
+            .iter()
+            .rev()
+            .map(|register| asm::Instruction::Stack(asm::Stack::Pop(*register))),
+    );
+    instructions.push(asm::Instruction::Return);
     instructions
 }
 
-// all "registers" in `program` are actually references to memory.
-pub fn emit_program(program: &register::Program, registers: u32) -> asm::Program {
+/// `%d = a; %d op= b` as `%d = a op b`, if `first` and `second` have that form
+fn fused_operation(
+    first: &register::Statement,
+    second: &register::Statement,
+    assignment: &Assignment,
+    constants: &mut ConstantPool,
+) -> Option<Vec<asm::Instruction>> {
+    let (&register::Expr::Move(moved), &register::Expr::Operation { operator, operand }) =
+        (&first.expr, &second.expr)
+    else {
+        return None;
+    };
+    if first.destination != second.destination {
+        return None;
+    }
+    // the destination hasn't been written yet, so it is read as the moved value
+    let operand = match operand {
+        register::Value::Register(register) if register == first.destination => moved,
+        operand => operand,
+    };
+
+    let mut instructions = Vec::new();
+    let dest = match assignment.location(first.destination) {
+        Location::Xmm(xmm) => xmm,
+        Location::Stack(_) => scratch(0),
+    };
+    let first_value = value_in_xmm(moved, scratch(1), assignment, constants, &mut instructions);
+    let value = operand_access(operand, assignment, constants);
+    instructions.push(asm::Instruction::ArithmeticOperation(
+        asm::Arithmetic::FloatOperation(asm::FloatOperation {
+            operator,
+            dest,
+            first: first_value,
+            value,
+        }),
+    ));
+    instructions.extend(store_register(first.destination, dest, assignment));
+    Some(instructions)
+}
+
+/// Registers of `program` live wherever `assignment` puts them, which must not be any of the
+/// [`SCRATCH`] registers. `MultiplyAdd` statements become `vfmadd231ss`, so `options.target` must
+/// have FMA if there are any.
+pub fn emit_program(
+    program: &register::Program,
+    assignment: &Assignment,
+    options: &Options,
+) -> asm::Program {
     let mut instructions = Vec::new();
+    let mut labels = LabelSource::default();
+    let mut constants = ConstantPool::default();
 
-    let stack_allocation = registers * 4;
+    let stack_allocation = TryInto::<u32>::try_into(assignment.stack_slots).unwrap() * 4;
 
     instructions.push(asm::Instruction::ArithmeticOperation(
         asm::Arithmetic::IntegerSubAssign(asm::IntegerAssign {
@@ -54,104 +359,254 @@ pub fn emit_program(program: &register::Program, registers: u32) -> asm::Program
         }),
     ));
 
-    for statement in &program.statements {
-        match statement.expr {
-            register::Expr::Move(register::Value::Register(reg)) => {
-                // loads `reg` into %xmm0, then loads %xmm0 into `statement.destination`
+    // the inputs loaded before each statement, and before the outputs are stored
+    let mut loads = vec![Vec::new(); program.statements.len() + 1];
+    for (index, load) in register_alloc::input_loads(program).into_iter().enumerate() {
+        if let Some(statement) = load {
+            loads[statement].push(index);
+        }
+    }
 
-                let intermediate = 0.try_into().unwrap();
-                instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
-                    dest: intermediate,
-                    src: register_access(reg),
-                }));
-                instructions.push(asm::Instruction::Move(asm::Move::FloatToMemory {
-                    dest: register_access(statement.destination),
-                    src: intermediate,
-                }));
-            }
-            register::Expr::Move(register::Value::Number(number)) => {
-                // loads `number` into %rbx, move %rbx into `statement.destination`
-                let intermediate_int = asm::R::Rbx;
-                instructions.push(load_constant(number, intermediate_int));
-                instructions.push(asm::Instruction::Move(asm::Move::IntegerToMemory {
-                    dest: register_access(statement.destination),
-                    src: intermediate_int,
-                }));
+    let mut statements = program.statements.iter().zip(&loads).peekable();
+    while let Some((statement, loads)) = statements.next() {
+        instructions.extend(load_inputs(program, loads, assignment));
+
+        if options.three_operand {
+            // an input loaded between the two statements could overwrite the moved value
+            if let Some((next, _)) = statements.peek().filter(|(_, loads)| loads.is_empty()) {
+                if let Some(fused) = fused_operation(statement, next, assignment, &mut constants) {
+                    instructions.extend(fused);
+                    statements.next();
+                    continue;
+                }
             }
-            register::Expr::Operation { operator, operand } => {
-                // loads `statement.destination` into %xmm0, `operand` into %xmm1, performs the operation, stores the result in `statement.destination`
-                let first = 0.try_into().unwrap();
-                let second = 1.try_into().unwrap();
-                let intermediate = asm::R::Rbx;
+        }
 
-                instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
-                    dest: first,
-                    src: register_access(statement.destination),
-                }));
+        let destination = assignment.location(statement.destination);
+        match statement.expr {
+            register::Expr::Move(value) => match (destination, value) {
+                (Location::Xmm(dest), value) => {
+                    instructions.extend(load_value(value, dest, assignment, &mut constants));
+                }
+                (Location::Stack(slot), value) => {
+                    // stores `value` from its register, or through %xmm0 if it isn't in one
+                    let src = value_in_xmm(
+                        value,
+                        scratch(0),
+                        assignment,
+                        &mut constants,
+                        &mut instructions,
+                    );
+                    instructions.push(asm::Instruction::Move(asm::Move::FloatToMemory {
+                        dest: slot_access(slot),
+                        src,
+                    }));
+                }
+            },
+            register::Expr::Operation { operator, operand } => {
+                // performs the operation on the register holding `statement.destination`, or on
+                // %xmm0 if it is on the stack. `operand` is read straight from memory if it isn't
+                // in a register.
+                let dest = match destination {
+                    Location::Xmm(xmm) => xmm,
+                    Location::Stack(slot) => {
+                        instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
+                            dest: scratch(0),
+                            src: slot_access(slot),
+                        }));
+                        scratch(0)
+                    }
+                };
 
-                load_value(operand, second, intermediate);
+                let value = operand_access(operand, assignment, &mut constants);
 
                 instructions.push(asm::Instruction::ArithmeticOperation(
                     asm::Arithmetic::FloatAssign(asm::FloatAssign {
                         operator,
-                        dest: first,
-                        value: second,
+                        dest,
+                        value,
                     }),
                 ));
 
-                instructions.push(asm::Instruction::Move(asm::Move::FloatToMemory {
-                    dest: register_access(statement.destination),
-                    src: first,
-                }));
+                instructions.extend(store_register(statement.destination, dest, assignment));
+            }
+            register::Expr::MultiplyAdd { first, second } => {
+                // like an `Operation`, but the first factor has to be in a register
+                let dest = match destination {
+                    Location::Xmm(xmm) => xmm,
+                    Location::Stack(slot) => {
+                        instructions.push(asm::Instruction::Move(asm::Move::FloatFromMemory {
+                            dest: scratch(0),
+                            src: slot_access(slot),
+                        }));
+                        scratch(0)
+                    }
+                };
+
+                let first = value_in_xmm(
+                    first,
+                    scratch(1),
+                    assignment,
+                    &mut constants,
+                    &mut instructions,
+                );
+                let value = operand_access(second, assignment, &mut constants);
+
+                instructions.push(asm::Instruction::ArithmeticOperation(
+                    asm::Arithmetic::FusedMultiplyAdd(asm::FusedMultiplyAdd { dest, first, value }),
+                ));
+
+                instructions.extend(store_register(statement.destination, dest, assignment));
             }
             register::Expr::IfPositive {
                 predicate,
                 consequent,
                 alternative,
-            } => {
-                // load `predicate`, `consequent` and `alternative` into %xmm0, %xmm1, %xmm2
-                // load 0 into %xmm3
-                // compare `predicate` to 0
-                // conditionally skip an instruction
-                // move alternative into consequent
-                // store the result
-
-                let predicate_xmm = 0.try_into().unwrap();
-                let consequent_xmm = 1.try_into().unwrap();
-                let alternative_xmm = 2.try_into().unwrap();
-                let zero_xmm = 3.try_into().unwrap();
-                let intermediate = asm::R::Rbx;
-
-                load_value(predicate, predicate_xmm, intermediate);
-                load_value(consequent, consequent_xmm, intermediate);
-                load_value(alternative, alternative_xmm, intermediate);
-                load_constant(0.0, intermediate);
-
-                instructions.push(asm::Instruction::Move(asm::Move::FloatFromInteger {
-                    dest: zero_xmm,
-                    src: intermediate,
-                }));
+            } => match options.if_positive {
+                IfPositiveLowering::Branch => {
+                    // copy `consequent` into %xmm1, and load 0 into %xmm2
+                    // compare `predicate` to 0
+                    // conditionally jump over the next instruction
+                    // move alternative into %xmm1
+                    // store the result
 
-                instructions.push(asm::Instruction::Compare(asm::Compare::CompareFloats {
-                    first: predicate_xmm,
-                    second: zero_xmm,
-                }));
+                    let result = scratch(1);
+                    let zero = scratch(2);
 
-                instructions.push(asm::Instruction::Jump(asm::Jump::AboveEqual { offset: 1 }));
+                    instructions.extend(load_value(consequent, result, assignment, &mut constants));
+                    instructions.extend(load_value(
+                        register::Value::Number(0.0),
+                        zero,
+                        assignment,
+                        &mut constants,
+                    ));
+                    let predicate = value_in_xmm(
+                        predicate,
+                        scratch(0),
+                        assignment,
+                        &mut constants,
+                        &mut instructions,
+                    );
 
-                instructions.push(asm::Instruction::Move(asm::Move::FloatToFloat {
-                    dest: consequent_xmm,
-                    src: alternative_xmm,
-                }));
+                    instructions.push(asm::Instruction::Compare(asm::Compare::CompareFloats {
+                        first: predicate,
+                        second: zero,
+                    }));
 
-                instructions.push(asm::Instruction::Move(asm::Move::FloatToMemory {
-                    dest: register_access(statement.destination),
-                    src: consequent_xmm,
-                }));
-            }
+                    let positive = labels.fresh();
+
+                    instructions.push(asm::Instruction::Jump(asm::Jump::AboveEqual {
+                        target: positive,
+                    }));
+
+                    instructions.extend(load_value(
+                        alternative,
+                        result,
+                        assignment,
+                        &mut constants,
+                    ));
+
+                    instructions.push(asm::Instruction::Label(positive));
+
+                    instructions.extend(store_register(statement.destination, result, assignment));
+                }
+                IfPositiveLowering::Mask | IfPositiveLowering::Blend => {
+                    // load 0 into %xmm0
+                    // set %xmm0 to a mask of `0 <= predicate`, which is false if `predicate` is NaN
+                    // select between `consequent` and `alternative` with the mask
+                    // store the result
+
+                    let mask = scratch(0);
+
+                    instructions.extend(load_value(
+                        register::Value::Number(0.0),
+                        mask,
+                        assignment,
+                        &mut constants,
+                    ));
+                    let predicate = value_in_xmm(
+                        predicate,
+                        scratch(1),
+                        assignment,
+                        &mut constants,
+                        &mut instructions,
+                    );
+
+                    instructions.push(asm::Instruction::Compare(asm::Compare::CompareToMask {
+                        condition: asm::Condition::LessEqual,
+                        dest: mask,
+                        value: predicate,
+                    }));
+
+                    let result = if let IfPositiveLowering::Blend = options.if_positive {
+                        // %xmm2 = mask ? `consequent` : `alternative`
+                        let result = scratch(2);
+                        instructions.extend(load_value(
+                            alternative,
+                            result,
+                            assignment,
+                            &mut constants,
+                        ));
+                        let consequent = value_in_xmm(
+                            consequent,
+                            scratch(1),
+                            assignment,
+                            &mut constants,
+                            &mut instructions,
+                        );
+                        instructions.push(asm::Instruction::Move(asm::Move::BlendWithMask {
+                            dest: result,
+                            src: consequent,
+                        }));
+                        result
+                    } else {
+                        // %xmm0 = (mask & `consequent`) | (!mask & `alternative`)
+                        let selected = scratch(1);
+                        instructions.extend(load_value(
+                            consequent,
+                            selected,
+                            assignment,
+                            &mut constants,
+                        ));
+                        instructions.push(logical(asm::Logical::And, selected, mask));
+                        let alternative = value_in_xmm(
+                            alternative,
+                            scratch(2),
+                            assignment,
+                            &mut constants,
+                            &mut instructions,
+                        );
+                        instructions.push(logical(asm::Logical::AndNot, mask, alternative));
+                        instructions.push(logical(asm::Logical::Or, mask, selected));
+                        mask
+                    };
+
+                    instructions.extend(store_register(statement.destination, result, assignment));
+                }
+            },
         }
     }
 
+    instructions.extend(load_inputs(
+        program,
+        &loads[program.statements.len()],
+        assignment,
+    ));
+    for (index, output) in program.output.iter().enumerate() {
+        // stores the `index`th output from its register, or through %xmm0 if it isn't in one
+        let src = value_in_xmm(
+            *output,
+            scratch(0),
+            assignment,
+            &mut constants,
+            &mut instructions,
+        );
+        instructions.push(asm::Instruction::Move(asm::Move::FloatToMemory {
+            dest: output_access(index),
+            src,
+        }));
+    }
+
     instructions.push(asm::Instruction::ArithmeticOperation(
         asm::Arithmetic::IntegerAddAssign(asm::IntegerAssign {
             dest: asm::R::Rsp,
@@ -159,5 +614,321 @@ pub fn emit_program(program: &register::Program, registers: u32) -> asm::Program
         }),
     ));
 
-    asm::Program { instructions }
+    asm::Program {
+        instructions: save_registers(instructions),
+        constants: constants.constants,
+        target: options.target,
+    }
+}
+
+pub fn logical(operator: asm::Logical, dest: asm::Xmm, value: asm::Xmm) -> asm::Instruction {
+    asm::Instruction::ArithmeticOperation(asm::Arithmetic::LogicalAssign(asm::LogicalAssign {
+        operator,
+        dest,
+        value,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compile::assemble::Assemblable;
+    use crate::compile::{flatten, register_alloc};
+    use crate::eval;
+    use crate::harness::generate::{spread_values, symbolic_outputs};
+    use crate::ir::bytes::Bytes;
+    use crate::ir::expr::Operator;
+    use crate::jit::executable::Function;
+    use crate::neurons::{learning::layer, neuron::Neuron};
+
+    /// The outputs of `neuron`, flattened with its data in the registers before its input
+    fn network_program(neuron: &impl Neuron, options: &flatten::Options) -> register::Program {
+        let (outputs, variables) = symbolic_outputs(neuron);
+        let registers = (0..variables)
+            .map(|index| register::Register { index })
+            .collect();
+        flatten::to_program_with(&outputs, registers, options)
+    }
+
+    fn compile(program: &register::Program, available: &[asm::Xmm], options: &Options) -> Function {
+        let assignment = register_alloc::assign(program, available);
+        let assembly = emit_program(program, &assignment, options);
+        let mut bytes = Bytes::new();
+        assembly.assemble(&mut bytes);
+        unsafe { Function::new(&bytes, program.input.len(), program.output.len()) }.unwrap()
+    }
+
+    fn assert_matches_interpreter(
+        function: &Function,
+        program: &register::Program,
+        values: &[f32],
+    ) {
+        let env = program
+            .input
+            .iter()
+            .zip(values)
+            .map(|(register, value)| (*register, *value))
+            .collect();
+        let bits =
+            |values: Vec<f32>| -> Vec<_> { values.iter().map(|value| value.to_bits()).collect() };
+
+        assert_eq!(
+            bits(function.call(values)),
+            bits(eval::register::evaluate(program, env)),
+            "{:?}",
+            values
+        );
+    }
+
+    #[test]
+    fn native_matches_interpreter() {
+        let mut program = network_program(
+            &layer(3, 2).compose(layer(2, 3)),
+            &flatten::Options::default(),
+        );
+        register_alloc::realloc(&mut program);
+
+        let all = allocatable();
+        // no registers puts everything on the stack, and a few registers forces spilling
+        for available in [&all[..], &all[..0], &all[..3]] {
+            for options in Options::supported(Target::detect()) {
+                let function = compile(&program, available, &options);
+
+                for seed in 0..16 {
+                    let values = spread_values(program.input.len(), seed);
+                    assert_matches_interpreter(&function, &program, &values);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn if_positive_edge_cases() {
+        let input = register::Register { index: 0 };
+        let output = register::Register { index: 1 };
+        // %1 = if %0 >= 0 then %0 else -2
+        let program = register::Program {
+            input: vec![input],
+            statements: vec![register::Statement {
+                destination: output,
+                expr: register::Expr::IfPositive {
+                    predicate: register::Value::Register(input),
+                    consequent: register::Value::Register(input),
+                    alternative: register::Value::Number(-2.0),
+                },
+            }],
+            output: vec![register::Value::Register(output)],
+        };
+
+        for options in Options::supported(Target::detect()) {
+            for available in [&allocatable()[..], &[]] {
+                let function = compile(&program, available, &options);
+
+                for value in [
+                    1.5,
+                    -1.5,
+                    0.0,
+                    -0.0,
+                    f32::NAN,
+                    f32::INFINITY,
+                    f32::NEG_INFINITY,
+                    f32::MIN_POSITIVE,
+                ] {
+                    assert_matches_interpreter(&function, &program, &[value]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn registers_avoid_memory() {
+        let program = network_program(
+            &layer(4, 4).compose(layer(4, 1)),
+            &flatten::Options::default(),
+        );
+
+        let memory_accesses = |available: &[asm::Xmm]| {
+            let assignment = register_alloc::assign(&program, available);
+            emit_program(&program, &assignment, &Options::default())
+                .instructions
+                .iter()
+                .filter(|instruction| {
+                    matches!(
+                        instruction,
+                        asm::Instruction::Move(
+                            asm::Move::FloatFromMemory { .. }
+                                | asm::Move::FloatToMemory { .. }
+                                | asm::Move::IntegerToMemory { .. }
+                        )
+                    )
+                })
+                .count()
+        };
+
+        assert!(memory_accesses(&allocatable()) * 2 < memory_accesses(&[]));
+    }
+
+    #[test]
+    fn targets_limit_instructions() {
+        let program = network_program(
+            &layer(3, 3).compose(layer(3, 1)),
+            &flatten::Options::default(),
+        );
+        let assignment = register_alloc::assign(&program, &allocatable());
+
+        // the lowest tier can only choose between branching and masking
+        let lowest = Options::supported(Target::Sse2);
+        assert_eq!(lowest.len(), 2);
+        assert!(lowest.contains(&Options::default()));
+        for target in Target::ALL {
+            for options in Options::supported(target) {
+                let assembly = emit_program(&program, &assignment, &options);
+                assert!(assembly
+                    .instructions
+                    .iter()
+                    .all(|instruction| target.allows(instruction)));
+            }
+        }
+        assert_eq!(
+            Options::supported(Target::Avx512).len(),
+            Options::supported(Target::Sse41).len() * 2
+        );
+    }
+
+    #[test]
+    fn multiply_adds_match_interpreter() {
+        let target = Target::detect();
+        if !target.has_fma() {
+            return;
+        }
+        let options = flatten::Options {
+            fuse_multiply_add: true,
+        };
+        let program = network_program(&layer(3, 3).compose(layer(3, 2)), &options);
+
+        let all = allocatable();
+        for available in [&all[..], &all[..0], &all[..3]] {
+            let function = compile(&program, available, &Options::for_target(target));
+            for seed in 0..16 {
+                // scaled so that their products aren't exact in single precision
+                let values: Vec<f32> = spread_values(program.input.len(), seed)
+                    .into_iter()
+                    .map(|value| value * 11.001)
+                    .collect();
+                assert_matches_interpreter(&function, &program, &values);
+            }
+        }
+    }
+
+    #[test]
+    fn three_operand_avoids_copies() {
+        let mut program = network_program(
+            &layer(4, 4).compose(layer(4, 2)),
+            &flatten::Options::default(),
+        );
+        register_alloc::realloc(&mut program);
+
+        let all = allocatable();
+        for available in [&all[..], &all[..0], &all[..3]] {
+            let assignment = register_alloc::assign(&program, available);
+            let length = |three_operand| {
+                let options = Options {
+                    target: Target::Avx,
+                    three_operand,
+                    ..Options::default()
+                };
+                emit_program(&program, &assignment, &options)
+                    .instructions
+                    .len()
+            };
+            assert!(length(true) < length(false));
+
+            if Target::detect().has_avx() {
+                let options = Options::for_target(Target::Avx);
+                let function = compile(&program, available, &options);
+                for seed in 0..16 {
+                    let values = spread_values(program.input.len(), seed);
+                    assert_matches_interpreter(&function, &program, &values);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn operands_are_folded() {
+        let program = network_program(&layer(4, 1), &flatten::Options::default());
+
+        // with every register on the stack, operands are read from memory by the arithmetic itself
+        let assignment = register_alloc::assign(&program, &[]);
+        let assembly = emit_program(&program, &assignment, &Options::default());
+        let arithmetic = assembly
+            .instructions
+            .iter()
+            .filter(|instruction| {
+                matches!(
+                    instruction,
+                    asm::Instruction::ArithmeticOperation(asm::Arithmetic::FloatAssign(
+                        asm::FloatAssign {
+                            value: asm::Operand::Memory(_),
+                            ..
+                        }
+                    ))
+                )
+            })
+            .count();
+        let operations = program
+            .statements
+            .iter()
+            .filter(|statement| matches!(statement.expr, register::Expr::Operation { .. }))
+            .count();
+        assert_eq!(arithmetic, operations);
+
+        let function = compile(&program, &[], &Options::default());
+        let input: Vec<_> = (0..program.input.len()).map(|i| i as f32 - 2.5).collect();
+        assert_matches_interpreter(&function, &program, &input);
+    }
+
+    #[test]
+    fn constants_are_deduplicated() {
+        let input = register::Register { index: 0 };
+        let output = register::Register { index: 1 };
+        let statement = |expr| register::Statement {
+            destination: output,
+            expr,
+        };
+        // %1 = 0.5; %1 *= %0; %1 += 0.5; %1 -= -0
+        let program = register::Program {
+            input: vec![input],
+            statements: vec![
+                statement(register::Expr::Move(register::Value::Number(0.5))),
+                statement(register::Expr::Operation {
+                    operator: Operator::Multiply,
+                    operand: register::Value::Register(input),
+                }),
+                statement(register::Expr::Operation {
+                    operator: Operator::Add,
+                    operand: register::Value::Number(0.5),
+                }),
+                statement(register::Expr::Operation {
+                    operator: Operator::Subtract,
+                    operand: register::Value::Number(-0.0),
+                }),
+            ],
+            output: vec![register::Value::Register(output)],
+        };
+
+        let assignment = register_alloc::assign(&program, &allocatable());
+        let assembly = emit_program(&program, &assignment, &Options::default());
+        assert_eq!(
+            assembly
+                .constants
+                .iter()
+                .map(|constant| constant.to_bits())
+                .collect::<Vec<_>>(),
+            vec![0.5f32.to_bits(), (-0.0f32).to_bits()]
+        );
+
+        let function = compile(&program, &allocatable(), &Options::default());
+        assert_matches_interpreter(&function, &program, &[3.0]);
+    }
 }
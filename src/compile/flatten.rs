@@ -1,7 +1,16 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+use crate::ir::dag::{Dag, Node, NodeId};
 use crate::ir::{expr, register};
 
+/// Choices about how expressions are flattened
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Turn a sum with a product as an operand into a `MultiplyAdd`, which only rounds once and so
+    /// may not produce the same bits as the expression. Emitting it requires FMA.
+    pub fuse_multiply_add: bool,
+}
+
 struct RegisterSource {
     unused: usize,
 }
@@ -29,72 +38,136 @@ impl ProgramBuilder {
     }
 }
 
+/// The nodes being flattened, along with the value each node has been flattened into so far
+struct Nodes<'a> {
+    dag: &'a Dag,
+    uses: Vec<usize>,
+    values: HashMap<NodeId, register::Value>,
+    aliased: &'a HashSet<register::Register>,
+    options: Options,
+}
+
+impl Nodes<'_> {
+    /// Whether the register holding `value`, the result of `id`, can be overwritten, because
+    /// nothing else reads it.
+    fn reusable(&self, id: NodeId, value: register::Value) -> Option<register::Register> {
+        match value {
+            register::Value::Register(register)
+                if !self.aliased.contains(&register) && self.uses[id.index] == 1 =>
+            {
+                Some(register)
+            }
+            _ => None,
+        }
+    }
+
+    /// The addend and factors of `id` if it is a sum with a product as an operand that nothing else
+    /// reads, like `eval::expr::evaluate_fused`. A product that is read elsewhere is flattened on
+    /// its own and added, since its operands may be overwritten once it has been computed.
+    fn multiply_add(&self, id: NodeId) -> Option<(NodeId, [NodeId; 2])> {
+        self.dag.multiply_add(id, |product| {
+            self.uses[product.index] == 1 && !self.values.contains_key(&product)
+        })
+    }
+}
+
+/// Flattens the node `id` into statements, returning the value holding its result.
+/// Every node is flattened once, no matter how many times it is used.
 fn flatten(
-    expr: &expr::Expr,
+    id: NodeId,
+    nodes: &mut Nodes,
     registers: &mut RegisterSource,
     program: &mut ProgramBuilder,
-    aliased: &HashSet<register::Register>,
 ) -> register::Value {
-    match expr {
-        expr::Expr::Operation { operator, operands } => {
-            let a = flatten(&operands[0], registers, program, aliased);
-            let b = flatten(&operands[1], registers, program, aliased);
+    if let Some(value) = nodes.values.get(&id) {
+        return *value;
+    }
+
+    let fused = if nodes.options.fuse_multiply_add {
+        nodes.multiply_add(id)
+    } else {
+        None
+    };
+    if let Some((addend, [first, second])) = fused {
+        let addend_value = flatten(addend, nodes, registers, program);
+        let first = flatten(first, nodes, registers, program);
+        let second = flatten(second, nodes, registers, program);
+
+        // the product is added in place to an addend that nothing else reads
+        let result = match nodes.reusable(addend, addend_value) {
+            Some(result) => result,
+            None => {
+                let result = registers.fresh();
+                program.with_statement(register::Statement {
+                    destination: result,
+                    expr: register::Expr::Move(addend_value),
+                });
+                result
+            }
+        };
+        program.with_statement(register::Statement {
+            destination: result,
+            expr: register::Expr::MultiplyAdd { first, second },
+        });
+
+        let value = register::Value::Register(result);
+        nodes.values.insert(id, value);
+        return value;
+    }
+
+    let value = match nodes.dag.node(id) {
+        Node::Operation { operator, operands } => {
+            let a = flatten(operands[0], nodes, registers, program);
+            let b = flatten(operands[1], nodes, registers, program);
+
+            // the operation is done in place on an operand that nothing else reads
+            let reused = match nodes.reusable(operands[0], a) {
+                Some(result) => Some((result, b)),
+                None if operator.is_associative() => {
+                    nodes.reusable(operands[1], b).map(|result| (result, a))
+                }
+                None => None,
+            };
+
+            match reused {
+                Some((result, operand)) => {
+                    program.with_statement(register::Statement {
+                        destination: result,
+                        expr: register::Expr::Operation { operator, operand },
+                    });
+
+                    register::Value::Register(result)
+                }
+                None => {
+                    let result = registers.fresh();
+
+                    program.with_statement(register::Statement {
+                        destination: result,
+                        expr: register::Expr::Move(a),
+                    });
 
-            if let register::Value::Register(result) = a {
-                if !aliased.contains(&result) {
                     program.with_statement(register::Statement {
                         destination: result,
                         expr: register::Expr::Operation {
-                            operator: *operator,
+                            operator,
                             operand: b,
                         },
                     });
 
-                    return register::Value::Register(result);
-                }
-            }
-
-            if operator.is_associative() {
-                if let register::Value::Register(result) = b {
-                    if !aliased.contains(&result) {
-                        program.with_statement(register::Statement {
-                            destination: result,
-                            expr: register::Expr::Operation {
-                                operator: *operator,
-                                operand: a,
-                            },
-                        });
-
-                        return register::Value::Register(result);
-                    }
+                    register::Value::Register(result)
                 }
             }
-
-            let result = registers.fresh();
-
-            program.with_statement(register::Statement {
-                destination: result,
-                expr: register::Expr::Move(a),
-            });
-
-            program.with_statement(register::Statement {
-                destination: result,
-                expr: register::Expr::Operation {
-                    operator: *operator,
-                    operand: b,
-                },
-            });
-
-            register::Value::Register(result)
         }
-        expr::Expr::Variable(index) => {
-            register::Value::Register(register::Register { index: *index })
-        }
-        expr::Expr::Number(number) => register::Value::Number(*number),
-        expr::Expr::IfPositive(if_positive) => {
-            let predicate = flatten(&if_positive.predicate, registers, program, aliased);
-            let consequent = flatten(&if_positive.consequent, registers, program, aliased);
-            let alternative = flatten(&if_positive.alternative, registers, program, aliased);
+        Node::Variable(index) => register::Value::Register(register::Register { index }),
+        Node::Number(bits) => register::Value::Number(f32::from_bits(bits)),
+        Node::IfPositive {
+            predicate,
+            consequent,
+            alternative,
+        } => {
+            let predicate = flatten(predicate, nodes, registers, program);
+            let consequent = flatten(consequent, nodes, registers, program);
+            let alternative = flatten(alternative, nodes, registers, program);
 
             let result = registers.fresh();
 
@@ -109,16 +182,44 @@ fn flatten(
 
             register::Value::Register(result)
         }
-    }
+    };
+
+    nodes.values.insert(id, value);
+    value
+}
+
+/// Flattens `exprs` into one program whose outputs are their values, in order.
+/// Subexpressions shared between the expressions are only computed once.
+pub fn to_program(exprs: &[expr::Expr], input: Vec<register::Register>) -> register::Program {
+    to_program_with(exprs, input, &Options::default())
 }
 
-pub fn to_program(expr: &expr::Expr, input: Vec<register::Register>) -> register::Program {
+/// Like `to_program`, with the choices in `options`
+pub fn to_program_with(
+    exprs: &[expr::Expr],
+    input: Vec<register::Register>,
+    options: &Options,
+) -> register::Program {
     let mut registers =
         RegisterSource::new(input.iter().map(|reg| reg.index + 1).max().unwrap_or(0));
     let mut program = ProgramBuilder::default();
     let aliased = input.iter().copied().collect();
 
-    let output = flatten(expr, &mut registers, &mut program, &aliased);
+    let mut dag = Dag::new();
+    let roots: Vec<_> = exprs.iter().map(|expr| dag.add(expr)).collect();
+    let mut nodes = Nodes {
+        uses: dag.uses(&roots),
+        dag: &dag,
+        values: HashMap::new(),
+        aliased: &aliased,
+        options: *options,
+    };
+
+    // an output is never reused, since being an output already counts as one use
+    let output = roots
+        .into_iter()
+        .map(|root| flatten(root, &mut nodes, &mut registers, &mut program))
+        .collect();
 
     register::Program {
         input,
@@ -126,3 +227,178 @@ pub fn to_program(expr: &expr::Expr, input: Vec<register::Register>) -> register
         output,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::slice;
+
+    use super::*;
+    use crate::eval;
+    use crate::harness::generate::spread_values;
+    use crate::neurons::{learning::layer, neuron::Neuron};
+
+    fn network(depth: usize) -> (expr::Expr, usize) {
+        let mut values: Vec<_> = (0..3).map(expr::Expr::Variable).collect();
+        let mut variables = 3;
+        for _ in 0..depth {
+            let layer = layer(3, 3);
+            let data: Vec<_> = (variables..variables + layer.size().data)
+                .map(expr::Expr::Variable)
+                .collect();
+            variables += data.len();
+            values = layer.evaluate(&values, &data);
+        }
+        (values.swap_remove(0), variables)
+    }
+
+    fn registers(variables: usize) -> Vec<register::Register> {
+        (0..variables)
+            .map(|index| register::Register { index })
+            .collect()
+    }
+
+    #[test]
+    fn shared_nodes_flatten_once() {
+        let x = || expr::Expr::Variable(0);
+        let sum = x() + expr::Expr::Number(1.0);
+        // the sum is computed once, but it's used twice, so the product goes in a new register
+        let expr = sum.clone() * sum;
+        let program = to_program(&[expr], registers(1));
+
+        assert_eq!(program.statements.len(), 4, "{:?}", program);
+        let env = [(register::Register { index: 0 }, 3.0)]
+            .into_iter()
+            .collect();
+        assert_eq!(eval::register::evaluate(&program, env), [16.0]);
+    }
+
+    #[test]
+    fn outputs_share_nodes() {
+        let x = || expr::Expr::Variable(0);
+        let sum = x() + expr::Expr::Number(1.0);
+        // built separately, so the sums are only shared by hash-consing
+        let outputs = [
+            sum.clone() * x(),
+            x() - (x() + expr::Expr::Number(1.0)),
+            sum,
+        ];
+        let program = to_program(&outputs, registers(1));
+
+        // the sum, then each product and difference in a new register, since the sum is read again
+        assert_eq!(program.statements.len(), 6, "{:?}", program);
+        let env = [(register::Register { index: 0 }, 3.0)]
+            .into_iter()
+            .collect();
+        assert_eq!(eval::register::evaluate(&program, env), [12.0, -1.0, 4.0]);
+    }
+
+    #[test]
+    fn deep_networks_flatten_linearly() {
+        let lengths: Vec<_> = (1..=8)
+            .map(|depth| {
+                let (expr, variables) = network(depth);
+                to_program(&[expr], registers(variables)).statements.len()
+            })
+            .collect();
+
+        // after the first layer, which only feeds one node, every layer adds the same statements
+        let growth: Vec<_> = lengths.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(
+            growth[1..].windows(2).all(|w| w[0] == w[1]),
+            "{:?}",
+            lengths
+        );
+    }
+
+    #[test]
+    fn flattening_matches_tree() {
+        let (expr, variables) = network(3);
+        let values = spread_values(variables, 0);
+        let program = to_program(slice::from_ref(&expr), registers(variables));
+
+        let tree = eval::expr::evaluate(&expr, &values.iter().copied().enumerate().collect());
+        let flat = eval::register::evaluate(
+            &program,
+            registers(variables).into_iter().zip(values).collect(),
+        );
+        assert_eq!(tree.to_bits(), flat[0].to_bits());
+    }
+
+    #[test]
+    fn weighted_sums_fuse() {
+        let (expr, variables) = network(3);
+        let values = spread_values(variables, 0);
+        let options = Options {
+            fuse_multiply_add: true,
+        };
+        let program = to_program_with(slice::from_ref(&expr), registers(variables), &options);
+
+        // every weight is multiplied in as part of a multiply add: three for each neuron of the
+        // first two layers, and three for the one neuron of the last layer that is read
+        let count = |matches: fn(&register::Expr) -> bool| {
+            program
+                .statements
+                .iter()
+                .filter(|statement| matches(&statement.expr))
+                .count()
+        };
+        assert_eq!(
+            count(|expr| matches!(expr, register::Expr::MultiplyAdd { .. })),
+            21
+        );
+        assert_eq!(
+            count(|expr| matches!(
+                expr,
+                register::Expr::Operation {
+                    operator: expr::Operator::Multiply | expr::Operator::Add,
+                    ..
+                }
+            )),
+            0,
+            "{:?}",
+            program
+        );
+
+        let tree = eval::expr::evaluate_fused(
+            slice::from_ref(&expr),
+            &values.iter().copied().enumerate().collect(),
+        );
+        let flat = eval::register::evaluate(
+            &program,
+            registers(variables).into_iter().zip(values).collect(),
+        );
+        assert_eq!(tree[0].to_bits(), flat[0].to_bits());
+    }
+
+    #[test]
+    fn shared_products_are_added() {
+        let x = || expr::Expr::Variable(0);
+        let number = expr::Expr::Number;
+        // built separately, so the products are only shared by hash-consing
+        let product = || (x() + number(1.0)) * x();
+        let outputs = [product() - number(2.0), number(3.0) + product()];
+        let within = [(product() - number(2.0)) + (number(3.0) + product())];
+        let options = Options {
+            fuse_multiply_add: true,
+        };
+
+        for (exprs, expected) in [(&outputs[..], &[10.0, 15.0][..]), (&within, &[25.0])] {
+            let program = to_program_with(exprs, registers(1), &options);
+            // the product is computed once, in place of the sum it reads, so it can't be fused
+            assert!(
+                program
+                    .statements
+                    .iter()
+                    .all(|statement| !matches!(statement.expr, register::Expr::MultiplyAdd { .. })),
+                "{:?}",
+                program
+            );
+            let env = [(register::Register { index: 0 }, 3.0)]
+                .into_iter()
+                .collect();
+            assert_eq!(eval::register::evaluate(&program, env), expected);
+            let tree = eval::expr::evaluate_fused(exprs, &[(0, 3.0)].into_iter().collect());
+            assert_eq!(tree, expected);
+        }
+    }
+}
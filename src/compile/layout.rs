@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use crate::ir::asm::{Instruction, Jump, Label, Program};
+use crate::ir::bytes::Bytes;
+
+use super::assemble::Assemblable;
+
+/// The alignment of the constant pool
+pub const CONSTANT_ALIGNMENT: usize = 16;
+
+/// How a jump's displacement is encoded
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JumpEncoding {
+    /// an 8 bit displacement
+    Short,
+    /// a 32 bit displacement
+    Near,
+}
+
+/// The byte offset of every instruction and label in a program
+pub struct Layout {
+    /// `offsets[i]` is the offset of the start of instruction `i`, `offsets[len]` is the length of
+    /// the program
+    pub offsets: Vec<usize>,
+    /// The encoding of every jump, indexed by instruction
+    pub jumps: HashMap<usize, JumpEncoding>,
+    pub labels: HashMap<Label, usize>,
+    /// The offset of the constant pool, after the instructions
+    pub constants: usize,
+}
+
+impl Jump {
+    pub fn target(&self) -> Label {
+        match self {
+            Jump::Unconditional { target } | Jump::AboveEqual { target } => *target,
+        }
+    }
+
+    pub fn length(&self, encoding: JumpEncoding) -> usize {
+        match (self, encoding) {
+            (_, JumpEncoding::Short) => 2,
+            (Jump::Unconditional { .. }, JumpEncoding::Near) => 5,
+            (Jump::AboveEqual { .. }, JumpEncoding::Near) => 6,
+        }
+    }
+}
+
+impl Layout {
+    /// The displacement of the jump at `index`, measured from the end of the jump to its target
+    pub fn displacement(&self, index: usize, jump: &Jump) -> i64 {
+        let target = self
+            .labels
+            .get(&jump.target())
+            .unwrap_or_else(|| panic!("jump to undefined label {:?}", jump.target()));
+
+        *target as i64 - self.offsets[index + 1] as i64
+    }
+}
+
+/// Computes the layout of `program`, choosing the smallest encoding of every jump.
+///
+/// Every jump starts out short, and jumps whose displacement doesn't fit in 8 bits are relaxed to
+/// near jumps until nothing changes. Jumps only ever grow, so this terminates.
+pub fn layout(program: &Program) -> Layout {
+    let lengths: Vec<Option<usize>> = program
+        .instructions
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::Jump(_) => None,
+            Instruction::Label(_) => Some(0),
+            instruction => {
+                let mut scratch = Bytes::new();
+                instruction.assemble(&mut scratch);
+                Some(scratch.len())
+            }
+        })
+        .collect();
+
+    let mut layout = Layout {
+        offsets: Vec::new(),
+        jumps: program
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| matches!(instruction, Instruction::Jump(_)))
+            .map(|(index, _)| (index, JumpEncoding::Short))
+            .collect(),
+        labels: HashMap::new(),
+        constants: 0,
+    };
+
+    loop {
+        layout.offsets.clear();
+        layout.labels.clear();
+
+        let mut position = 0;
+        for (index, instruction) in program.instructions.iter().enumerate() {
+            layout.offsets.push(position);
+            position += match instruction {
+                Instruction::Jump(jump) => jump.length(layout.jumps[&index]),
+                _ => lengths[index].unwrap(),
+            };
+            if let Instruction::Label(label) = instruction {
+                let previous = layout.labels.insert(*label, layout.offsets[index]);
+                assert!(previous.is_none(), "label {:?} defined twice", label);
+            }
+        }
+        layout.offsets.push(position);
+
+        let mut relaxed = false;
+        for (index, instruction) in program.instructions.iter().enumerate() {
+            if let Instruction::Jump(jump) = instruction {
+                if layout.jumps[&index] == JumpEncoding::Short
+                    && i8::try_from(layout.displacement(index, jump)).is_err()
+                {
+                    layout.jumps.insert(index, JumpEncoding::Near);
+                    relaxed = true;
+                }
+            }
+        }
+
+        if !relaxed {
+            layout.constants = position.next_multiple_of(CONSTANT_ALIGNMENT);
+            return layout;
+        }
+    }
+}
@@ -1,4 +1,12 @@
 pub mod assemble;
+pub mod colouring;
+pub mod disassemble;
 pub mod emit;
 pub mod flatten;
+pub mod layout;
+pub mod peephole;
+pub mod print;
 pub mod register_alloc;
+pub mod simplify;
+pub mod vectorise;
+pub mod verify;
@@ -0,0 +1,377 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ir::register::{Expr, Program, Register, Statement, Value};
+
+/// How many statements each rewrite of `optimise` removed
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// `%a = %a`
+    pub self_moves: usize,
+    /// `%b = %a; %b += %c` where `%a` is never used again, which becomes `%a += %c`
+    pub coalesced_moves: usize,
+    /// Statements whose result is never read
+    pub dead_stores: usize,
+    /// Reads of a copy that were replaced by reads of the original. This doesn't remove any
+    /// statements by itself, but often leaves the copy dead.
+    pub propagated_copies: usize,
+}
+
+impl Statistics {
+    pub fn removed(&self) -> usize {
+        self.self_moves + self.coalesced_moves + self.dead_stores
+    }
+}
+
+/// The values read by `expr`, like `Expr::operands`, but mutable so that passes can rewrite them
+fn operands(expr: &mut Expr) -> Vec<&mut Value> {
+    match expr {
+        Expr::Move(value) => vec![value],
+        Expr::Operation { operand, .. } => vec![operand],
+        Expr::MultiplyAdd { first, second } => vec![first, second],
+        Expr::IfPositive {
+            predicate,
+            consequent,
+            alternative,
+        } => vec![predicate, consequent, alternative],
+    }
+}
+
+/// Whether `statement` mentions `register` at all, either reading or writing it
+fn mentions(statement: &Statement, register: Register) -> bool {
+    statement.destination == register
+        || statement
+            .expr
+            .operands()
+            .into_iter()
+            .any(|value| matches!(value, Value::Register(read) if read == register))
+}
+
+/// Whether `statement` overwrites its destination without reading it
+fn defines(statement: &Statement) -> bool {
+    !statement.expr.reads_destination()
+}
+
+fn remove_self_moves(program: &mut Program, statistics: &mut Statistics) {
+    let before = program.statements.len();
+    program.statements.retain(|statement| {
+        !matches!(
+            statement.expr,
+            Expr::Move(Value::Register(source)) if source == statement.destination
+        )
+    });
+    statistics.self_moves += before - program.statements.len();
+}
+
+/// Replaces reads of a register holding a copy of a value with reads of the value itself, for as
+/// long as neither has been written since the copy.
+fn propagate_copies(program: &mut Program, statistics: &mut Statistics) {
+    let mut copies: HashMap<Register, Value> = HashMap::new();
+
+    for statement in &mut program.statements {
+        for value in operands(&mut statement.expr) {
+            if let Value::Register(register) = value {
+                if let Some(copy) = copies.get(register) {
+                    *value = *copy;
+                    statistics.propagated_copies += 1;
+                }
+            }
+        }
+
+        let destination = statement.destination;
+        copies.remove(&destination);
+        copies
+            .retain(|_, value| !matches!(value, Value::Register(source) if *source == destination));
+
+        if let Expr::Move(value) = statement.expr {
+            copies.insert(destination, value);
+        }
+    }
+
+    for value in &mut program.output {
+        if let Value::Register(register) = value {
+            if let Some(copy) = copies.get(register) {
+                *value = *copy;
+                statistics.propagated_copies += 1;
+            }
+        }
+    }
+}
+
+/// Turns `%b = %a; %b op= %c` into `%a op= %c`, or likewise for a multiply add, when nothing
+/// mentions `%a` afterwards, renaming `%b` to `%a` until `%b` is next overwritten. Inputs are never
+/// modified in place.
+fn coalesce_moves(program: &mut Program, statistics: &mut Statistics) {
+    let inputs: HashSet<_> = program.input.iter().copied().collect();
+    let mut index = 0;
+
+    while index + 1 < program.statements.len() {
+        let destination = program.statements[index].destination;
+        let source = match program.statements[index].expr {
+            Expr::Move(Value::Register(source)) => source,
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        let next = &program.statements[index + 1];
+        let operates_on_copy = next.destination == destination && next.expr.reads_destination();
+        let output_is_source = program
+            .output
+            .iter()
+            .any(|output| matches!(output, Value::Register(output) if *output == source));
+
+        if !operates_on_copy
+            || inputs.contains(&source)
+            || inputs.contains(&destination)
+            || source == destination
+            || output_is_source
+            || program.statements[index + 2..]
+                .iter()
+                .any(|statement| mentions(statement, source))
+        {
+            index += 1;
+            continue;
+        }
+
+        program.statements.remove(index);
+        statistics.coalesced_moves += 1;
+
+        let mut redefined = false;
+        for statement in &mut program.statements[index..] {
+            for value in operands(&mut statement.expr) {
+                if matches!(value, Value::Register(read) if *read == destination) {
+                    *value = Value::Register(source);
+                }
+            }
+            if statement.destination == destination {
+                if defines(statement) {
+                    redefined = true;
+                    break;
+                }
+                statement.destination = source;
+            }
+        }
+        if !redefined {
+            for output in &mut program.output {
+                if matches!(output, Value::Register(output) if *output == destination) {
+                    *output = Value::Register(source);
+                }
+            }
+        }
+    }
+}
+
+/// Removes every statement whose result is never read, scanning backwards from the output
+fn eliminate_dead_stores(program: &mut Program, statistics: &mut Statistics) {
+    let mut live: HashSet<_> = program
+        .output
+        .iter()
+        .filter_map(|output| match output {
+            Value::Register(register) => Some(*register),
+            Value::Number(_) => None,
+        })
+        .collect();
+
+    let mut kept = Vec::with_capacity(program.statements.len());
+    for mut statement in program.statements.drain(..).rev() {
+        if !live.contains(&statement.destination) {
+            statistics.dead_stores += 1;
+            continue;
+        }
+        if defines(&statement) {
+            live.remove(&statement.destination);
+        }
+        for value in operands(&mut statement.expr) {
+            if let Value::Register(register) = value {
+                live.insert(*register);
+            }
+        }
+        kept.push(statement);
+    }
+
+    kept.reverse();
+    program.statements = kept;
+}
+
+/// Removes redundant statements from `program` until none of the rewrites apply.
+/// Every rewrite keeps the program's output the same for every input, bit for bit.
+pub fn optimise(program: &mut Program) -> Statistics {
+    let mut statistics = Statistics::default();
+    loop {
+        let before = program.statements.len();
+        remove_self_moves(program, &mut statistics);
+        propagate_copies(program, &mut statistics);
+        coalesce_moves(program, &mut statistics);
+        eliminate_dead_stores(program, &mut statistics);
+        if program.statements.len() == before {
+            return statistics;
+        }
+    }
+}
+
+impl fmt::Debug for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} statements removed ({} self moves, {} coalesced moves, {} dead stores), {} copies propagated",
+            self.removed(),
+            self.self_moves,
+            self.coalesced_moves,
+            self.dead_stores,
+            self.propagated_copies
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::slice;
+
+    use super::*;
+    use crate::compile::flatten;
+    use crate::eval;
+    use crate::ir::expr::{self, Operator};
+    use crate::math::number::Number;
+
+    fn r(index: usize) -> Register {
+        Register { index }
+    }
+
+    fn statement(destination: usize, expr: Expr) -> Statement {
+        Statement {
+            destination: r(destination),
+            expr,
+        }
+    }
+
+    fn mov(destination: usize, source: usize) -> Statement {
+        statement(destination, Expr::Move(Value::Register(r(source))))
+    }
+
+    fn operation(destination: usize, operator: Operator, operand: usize) -> Statement {
+        statement(
+            destination,
+            Expr::Operation {
+                operator,
+                operand: Value::Register(r(operand)),
+            },
+        )
+    }
+
+    fn assert_preserves(program: &Program, optimised: &Program, values: &[f32]) {
+        let env = || {
+            program
+                .input
+                .iter()
+                .copied()
+                .zip(values.iter().copied())
+                .collect()
+        };
+        let bits = |program| -> Vec<_> {
+            eval::register::evaluate(program, env())
+                .iter()
+                .map(|value| value.to_bits())
+                .collect()
+        };
+        assert_eq!(
+            bits(program),
+            bits(optimised),
+            "{:?} optimised to {:?}",
+            program,
+            optimised
+        );
+    }
+
+    #[test]
+    fn rewrites() {
+        // %2 = %0; %2 = %2; %3 = %2; %4 = %0; %4 *= %1; %3 += %1; %5 = %3; %5 *= %3
+        let program = || Program {
+            input: vec![r(0), r(1)],
+            statements: vec![
+                mov(2, 0),
+                mov(2, 2),
+                mov(3, 2),
+                mov(4, 0),
+                operation(4, Operator::Multiply, 1),
+                operation(3, Operator::Add, 1),
+                mov(5, 3),
+                operation(5, Operator::Multiply, 3),
+            ],
+            output: vec![Value::Register(r(5))],
+        };
+
+        let mut optimised = program();
+        let statistics = optimise(&mut optimised);
+
+        // %3 = %0; %3 += %1; %3 *= %3
+        assert_eq!(optimised.statements.len(), 3, "{:?}", optimised);
+        assert_eq!(statistics.self_moves, 1);
+        assert_eq!(statistics.coalesced_moves, 1);
+        assert_eq!(statistics.dead_stores, 3);
+        assert_eq!(statistics.removed(), 5);
+        assert_preserves(&program(), &optimised, &[1.5, -2.25]);
+    }
+
+    #[test]
+    fn copies_stop_at_writes() {
+        // %2 is a copy of %0 only until %0 is multiplied, so %3 must not read %0 instead
+        let program = || Program {
+            input: vec![r(0), r(1)],
+            statements: vec![
+                mov(2, 0),
+                operation(0, Operator::Multiply, 1),
+                mov(3, 2),
+                operation(3, Operator::Add, 0),
+            ],
+            output: vec![Value::Register(r(3))],
+        };
+
+        let mut optimised = program();
+        optimise(&mut optimised);
+        assert_preserves(&program(), &optimised, &[3.0, 0.5]);
+    }
+
+    #[test]
+    fn outputs_stay_live() {
+        // %3 can't be computed in place on %2, since %2 is also an output
+        let program = || Program {
+            input: vec![r(0), r(1)],
+            statements: vec![
+                mov(2, 0),
+                operation(2, Operator::Multiply, 1),
+                mov(3, 2),
+                operation(3, Operator::Add, 1),
+                mov(4, 3),
+            ],
+            output: vec![Value::Register(r(4)), Value::Register(r(2))],
+        };
+
+        let mut optimised = program();
+        let statistics = optimise(&mut optimised);
+
+        assert_eq!(statistics.removed(), 1, "{:?}", optimised);
+        assert_preserves(&program(), &optimised, &[1.5, -2.25]);
+    }
+
+    #[test]
+    fn gradients_shrink() {
+        let x = || expr::Expr::Variable(0);
+        let y = || expr::Expr::Variable(1);
+        let square = (x() * y() - y()).if_positive(x(), y());
+        let expr = square.clone() * square / (x() + y());
+        let program = || flatten::to_program(slice::from_ref(&expr), vec![r(0), r(1)]);
+
+        let mut optimised = program();
+        let statistics = optimise(&mut optimised);
+
+        assert!(statistics.removed() > 0, "{:?}", program());
+        assert_eq!(
+            program().statements.len() - optimised.statements.len(),
+            statistics.removed()
+        );
+        for values in [[0.5, 2.0], [-1.0, 3.0], [0.0, -0.0]] {
+            assert_preserves(&program(), &optimised, &values);
+        }
+    }
+}
@@ -0,0 +1,700 @@
+use std::fmt::Write;
+
+use crate::ir::asm::{
+    Arithmetic, Base, Compare, Condition, FloatAssign, FloatOperation, FusedMultiplyAdd, Index,
+    Instruction, IntegerAssign, Jump, Label, Logical, LogicalAssign, LogicalOperation, Memory,
+    Move, Operand, Program, Stack, Xmm, Ymm, R,
+};
+use crate::ir::expr::Operator;
+
+use super::layout::CONSTANT_ALIGNMENT;
+
+/// The dialect of assembly accepted by GNU as
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// `addss 4(%rsp), %xmm0`
+    #[default]
+    Att,
+    /// `addss xmm0, dword ptr [rsp + 4]`, under `.intel_syntax noprefix`
+    Intel,
+}
+
+/// An operand as it appears in the text of an instruction
+enum Text<'a> {
+    Register(String),
+    Memory(&'a Memory),
+    /// Memory holding four packed floats rather than one
+    PackedMemory(&'a Memory),
+    /// Memory holding eight packed floats
+    WideMemory(&'a Memory),
+    Immediate(i64),
+    Label(Label),
+}
+
+struct Printer<'a> {
+    syntax: Syntax,
+    /// Prefixes the labels of the program, so that several functions can share a file
+    name: &'a str,
+}
+
+/// Prints `program` as a global function called `name`, followed by its constant pool.
+/// The output can be assembled by GNU as, and assembles to the same bytes as `Program::assemble`.
+pub fn print(program: &Program, name: &str, syntax: Syntax) -> String {
+    let printer = Printer { syntax, name };
+    let mut out = String::new();
+
+    if syntax == Syntax::Intel {
+        writeln!(out, "\t.intel_syntax noprefix").unwrap();
+    }
+    writeln!(out, "\t.text").unwrap();
+    writeln!(out, "\t.globl\t{}", name).unwrap();
+    writeln!(out, "\t.p2align\t4, 0x90").unwrap();
+    writeln!(out, "{}:", name).unwrap();
+
+    for instruction in &program.instructions {
+        match instruction {
+            Instruction::Label(label) => writeln!(out, "{}:", printer.label(*label)).unwrap(),
+            instruction => {
+                let (mnemonic, operands) = instruction_text(instruction);
+                writeln!(out, "\t{}", printer.instruction(mnemonic, operands)).unwrap();
+            }
+        }
+    }
+
+    if !program.constants.is_empty() {
+        writeln!(
+            out,
+            "\t.p2align\t{}, 0xcc",
+            CONSTANT_ALIGNMENT.trailing_zeros()
+        )
+        .unwrap();
+        for (index, constant) in program.constants.iter().enumerate() {
+            writeln!(out, "{}:", printer.constant(index)).unwrap();
+            writeln!(
+                out,
+                "\t.long\t{:#010x}\t# {:?}",
+                constant.to_bits(),
+                constant
+            )
+            .unwrap();
+        }
+    }
+
+    out
+}
+
+/// The mnemonic and operands of `instruction`, with the destination first as in Intel syntax
+fn instruction_text<'a>(instruction: &'a Instruction) -> (&'static str, Vec<Text<'a>>) {
+    let xmm = |xmm: &Xmm| Text::Register(format!("xmm{}", i64::from(*xmm)));
+    let ymm = |ymm: &Ymm| Text::Register(format!("ymm{}", i64::from(*ymm)));
+    let quad = |r: &R| Text::Register(register_name(*r, true));
+    let double = |r: &R| Text::Register(register_name(*r, false));
+    let operand = |operand: &'a Operand| match operand {
+        Operand::Xmm(value) => xmm(value),
+        Operand::Memory(value) => Text::Memory(value),
+    };
+
+    match instruction {
+        Instruction::Move(mov) => match mov {
+            Move::FloatFromMemory { dest, src } => ("movss", vec![xmm(dest), Text::Memory(src)]),
+            Move::FloatToMemory { dest, src } => ("movss", vec![Text::Memory(dest), xmm(src)]),
+            Move::FloatToFloat { dest, src } => ("movss", vec![xmm(dest), xmm(src)]),
+            Move::IntegerFromConstant { dest, src } => {
+                ("mov", vec![double(dest), Text::Immediate(i64::from(*src))])
+            }
+            Move::FloatFromInteger { dest, src } => ("movd", vec![xmm(dest), double(src)]),
+            Move::IntegerToMemory { dest, src } => ("mov", vec![Text::Memory(dest), double(src)]),
+            Move::BlendWithMask { dest, src } => (
+                "blendvps",
+                vec![xmm(dest), xmm(src), Text::Register(String::from("xmm0"))],
+            ),
+            Move::PackedFromMemory { dest, src } => {
+                ("movups", vec![xmm(dest), Text::PackedMemory(src)])
+            }
+            Move::PackedToMemory { dest, src } => {
+                ("movups", vec![Text::PackedMemory(dest), xmm(src)])
+            }
+            Move::PackedToPacked { dest, src } => ("movaps", vec![xmm(dest), xmm(src)]),
+            Move::Shuffle {
+                dest,
+                src,
+                selector,
+            } => (
+                "shufps",
+                vec![xmm(dest), xmm(src), Text::Immediate(i64::from(*selector))],
+            ),
+            Move::InterleaveLow { dest, src } => ("unpcklps", vec![xmm(dest), xmm(src)]),
+            Move::LowToHigh { dest, src } => ("movlhps", vec![xmm(dest), xmm(src)]),
+            Move::WideFromMemory { dest, src } => {
+                ("vmovups", vec![ymm(dest), Text::WideMemory(src)])
+            }
+            Move::WideToMemory { dest, src } => ("vmovups", vec![Text::WideMemory(dest), ymm(src)]),
+            Move::WideToWide { dest, src } => ("vmovaps", vec![ymm(dest), ymm(src)]),
+            Move::BroadcastFromMemory { dest, src } => {
+                ("vbroadcastss", vec![ymm(dest), Text::Memory(src)])
+            }
+            Move::InsertFromMemory {
+                dest,
+                first,
+                src,
+                lane,
+            } => (
+                "vinsertps",
+                vec![
+                    xmm(dest),
+                    xmm(first),
+                    Text::Memory(src),
+                    Text::Immediate(i64::from(*lane) << 4),
+                ],
+            ),
+            Move::XmmToHigh { dest, first, src } => (
+                "vinsertf128",
+                vec![ymm(dest), ymm(first), xmm(src), Text::Immediate(1)],
+            ),
+            Move::WideBlendWithMask {
+                dest,
+                first,
+                src,
+                mask,
+            } => (
+                "vblendvps",
+                vec![ymm(dest), ymm(first), ymm(src), ymm(mask)],
+            ),
+            Move::HighToXmm { dest, src } => (
+                "vextractf128",
+                vec![xmm(dest), ymm(src), Text::Immediate(1)],
+            ),
+            Move::LaneToMemory { dest, src, lane } => (
+                "vextractps",
+                vec![
+                    Text::Memory(dest),
+                    xmm(src),
+                    Text::Immediate(i64::from(*lane)),
+                ],
+            ),
+        },
+        Instruction::ArithmeticOperation(arithmetic) => match arithmetic {
+            Arithmetic::FloatAssign(FloatAssign {
+                operator,
+                dest,
+                value,
+            }) => {
+                let mnemonic = match operator {
+                    Operator::Add => "addss",
+                    Operator::Subtract => "subss",
+                    Operator::Multiply => "mulss",
+                    Operator::Divide => "divss",
+                };
+                (mnemonic, vec![xmm(dest), operand(value)])
+            }
+            Arithmetic::PackedAssign(FloatAssign {
+                operator,
+                dest,
+                value,
+            }) => {
+                let mnemonic = match operator {
+                    Operator::Add => "addps",
+                    Operator::Subtract => "subps",
+                    Operator::Multiply => "mulps",
+                    Operator::Divide => "divps",
+                };
+                let value = match value {
+                    Operand::Xmm(value) => xmm(value),
+                    Operand::Memory(value) => Text::PackedMemory(value),
+                };
+                (mnemonic, vec![xmm(dest), value])
+            }
+            Arithmetic::FloatOperation(FloatOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => {
+                let mnemonic = match operator {
+                    Operator::Add => "vaddss",
+                    Operator::Subtract => "vsubss",
+                    Operator::Multiply => "vmulss",
+                    Operator::Divide => "vdivss",
+                };
+                (mnemonic, vec![xmm(dest), xmm(first), operand(value)])
+            }
+            Arithmetic::FusedMultiplyAdd(FusedMultiplyAdd { dest, first, value }) => {
+                ("vfmadd231ss", vec![xmm(dest), xmm(first), operand(value)])
+            }
+            Arithmetic::WideOperation(FloatOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => {
+                let mnemonic = match operator {
+                    Operator::Add => "vaddps",
+                    Operator::Subtract => "vsubps",
+                    Operator::Multiply => "vmulps",
+                    Operator::Divide => "vdivps",
+                };
+                let value = match value {
+                    Operand::Xmm(value) => ymm(value),
+                    Operand::Memory(value) => Text::WideMemory(value),
+                };
+                (mnemonic, vec![ymm(dest), ymm(first), value])
+            }
+            Arithmetic::WideLogical(LogicalOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => {
+                let mnemonic = match operator {
+                    Logical::And => "vandps",
+                    Logical::AndNot => "vandnps",
+                    Logical::Or => "vorps",
+                };
+                (mnemonic, vec![ymm(dest), ymm(first), ymm(value)])
+            }
+            Arithmetic::LogicalAssign(LogicalAssign {
+                operator,
+                dest,
+                value,
+            }) => {
+                let mnemonic = match operator {
+                    Logical::And => "andps",
+                    Logical::AndNot => "andnps",
+                    Logical::Or => "orps",
+                };
+                (mnemonic, vec![xmm(dest), xmm(value)])
+            }
+            Arithmetic::IntegerAddAssign(IntegerAssign { dest, value }) => {
+                ("add", vec![quad(dest), Text::Immediate(i64::from(*value))])
+            }
+            Arithmetic::IntegerSubAssign(IntegerAssign { dest, value }) => {
+                ("sub", vec![quad(dest), Text::Immediate(i64::from(*value))])
+            }
+        },
+        Instruction::Compare(compare) => match compare {
+            Compare::CompareFloats { first, second } => ("comiss", vec![xmm(first), xmm(second)]),
+            Compare::CompareToMask {
+                condition,
+                dest,
+                value,
+            } => (
+                "cmpss",
+                vec![
+                    xmm(dest),
+                    xmm(value),
+                    Text::Immediate(condition_immediate(*condition)),
+                ],
+            ),
+            Compare::PackedCompareToMask {
+                condition,
+                dest,
+                value,
+            } => (
+                "cmpps",
+                vec![
+                    xmm(dest),
+                    xmm(value),
+                    Text::Immediate(condition_immediate(*condition)),
+                ],
+            ),
+            Compare::WideCompareToMask {
+                condition,
+                dest,
+                first,
+                value,
+            } => (
+                "vcmpps",
+                vec![
+                    ymm(dest),
+                    ymm(first),
+                    ymm(value),
+                    Text::Immediate(condition_immediate(*condition)),
+                ],
+            ),
+        },
+        Instruction::Jump(jump) => match jump {
+            Jump::Unconditional { target } => ("jmp", vec![Text::Label(*target)]),
+            Jump::AboveEqual { target } => ("jae", vec![Text::Label(*target)]),
+        },
+        Instruction::Stack(stack) => match stack {
+            Stack::Push(register) => ("push", vec![quad(register)]),
+            Stack::Pop(register) => ("pop", vec![quad(register)]),
+        },
+        Instruction::Return => ("ret", Vec::new()),
+        Instruction::ZeroUpper => ("vzeroupper", Vec::new()),
+        Instruction::Label(_) => unreachable!("labels are printed on their own line"),
+    }
+}
+
+fn condition_immediate(condition: Condition) -> i64 {
+    match condition {
+        Condition::Equal => 0,
+        Condition::LessThan => 1,
+        Condition::LessEqual => 2,
+        Condition::Unordered => 3,
+        Condition::NotEqual => 4,
+        Condition::NotLessThan => 5,
+        Condition::NotLessEqual => 6,
+        Condition::Ordered => 7,
+    }
+}
+
+/// The name of the 64 bit register `r`, or of its low 32 bits
+fn register_name(r: R, quad: bool) -> String {
+    let legacy = |name: &str| {
+        if quad {
+            format!("r{}", name)
+        } else {
+            format!("e{}", name)
+        }
+    };
+    let numbered = |number: u8| {
+        if quad {
+            format!("r{}", number)
+        } else {
+            format!("r{}d", number)
+        }
+    };
+
+    match r {
+        R::Rax => legacy("ax"),
+        R::Rbx => legacy("bx"),
+        R::Rcx => legacy("cx"),
+        R::Rdx => legacy("dx"),
+        R::Rsi => legacy("si"),
+        R::Rdi => legacy("di"),
+        R::Rbp => legacy("bp"),
+        R::Rsp => legacy("sp"),
+        R::RLow(_) | R::RHigh(_) => numbered(r.number()),
+    }
+}
+
+impl Printer<'_> {
+    fn label(&self, label: Label) -> String {
+        format!(".L{}_{}", self.name, label.index)
+    }
+
+    fn constant(&self, index: usize) -> String {
+        format!(".L{}_constant{}", self.name, index)
+    }
+
+    fn instruction(&self, mnemonic: &str, mut operands: Vec<Text>) -> String {
+        if operands.is_empty() {
+            return mnemonic.to_string();
+        }
+        if self.syntax == Syntax::Att {
+            operands.reverse();
+        }
+        let operands: Vec<_> = operands
+            .iter()
+            .map(|operand| self.operand(operand))
+            .collect();
+        format!("{}\t{}", mnemonic, operands.join(", "))
+    }
+
+    fn operand(&self, operand: &Text) -> String {
+        match (self.syntax, operand) {
+            (Syntax::Att, Text::Register(name)) => format!("%{}", name),
+            (Syntax::Intel, Text::Register(name)) => name.clone(),
+            (Syntax::Att, Text::Immediate(value)) => format!("${}", value),
+            (Syntax::Intel, Text::Immediate(value)) => value.to_string(),
+            (_, Text::Label(label)) => self.label(*label),
+            (_, Text::Memory(memory)) => self.memory(memory, "dword"),
+            (_, Text::PackedMemory(memory)) => self.memory(memory, "xmmword"),
+            (_, Text::WideMemory(memory)) => self.memory(memory, "ymmword"),
+        }
+    }
+
+    /// `size` is the Intel name of the size of the memory, like `dword`
+    fn memory(&self, memory: &Memory, size: &str) -> String {
+        let index = memory
+            .index
+            .as_ref()
+            .map(|Index { index, scale }| (register_name(*index, true), u8::from(*scale)));
+
+        match self.syntax {
+            Syntax::Att => {
+                let (displacement, base) = match memory.base {
+                    Base::R(base) => (
+                        if memory.displacement == 0 {
+                            String::new()
+                        } else {
+                            memory.displacement.to_string()
+                        },
+                        register_name(base, true),
+                    ),
+                    Base::Constant(constant) => (
+                        format!("{}{}", self.constant(constant), offset(memory.displacement)),
+                        String::from("rip"),
+                    ),
+                };
+                match index {
+                    Some((index, scale)) => {
+                        format!("{}(%{},%{},{})", displacement, base, index, scale)
+                    }
+                    None => format!("{}(%{})", displacement, base),
+                }
+            }
+            Syntax::Intel => {
+                let mut address = match memory.base {
+                    Base::R(base) => register_name(base, true),
+                    Base::Constant(constant) => format!("rip + {}", self.constant(constant)),
+                };
+                if let Some((index, scale)) = index {
+                    write!(address, " + {}*{}", index, scale).unwrap();
+                }
+                let displacement = i64::from(memory.displacement);
+                if displacement > 0 {
+                    write!(address, " + {}", displacement).unwrap();
+                } else if displacement < 0 {
+                    write!(address, " - {}", -displacement).unwrap();
+                }
+                format!("{} ptr [{}]", size, address)
+            }
+        }
+    }
+}
+
+/// A displacement added to a symbol, like `+4` or `-8`, or nothing if it is 0
+fn offset(displacement: i32) -> String {
+    if displacement == 0 {
+        String::new()
+    } else {
+        format!("{:+}", displacement)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::*;
+    use crate::compile::assemble::Assemblable;
+    use crate::compile::emit::{self, IfPositiveLowering, Options};
+    use crate::compile::{disassemble, flatten, register_alloc, vectorise};
+    use crate::harness::generate::symbolic_outputs;
+    use crate::ir::bytes::Bytes;
+    use crate::ir::expr::Expr;
+    use crate::ir::register;
+    use crate::ir::target::Target;
+    use crate::neurons::learning::layer;
+    use crate::neurons::neuron::Neuron;
+
+    fn xmm(index: i64) -> Xmm {
+        index.try_into().unwrap()
+    }
+
+    fn program(instructions: Vec<Instruction>, constants: Vec<f32>) -> Program {
+        Program {
+            instructions,
+            constants,
+            target: Target::Avx512,
+        }
+    }
+
+    #[test]
+    fn syntaxes() {
+        let assembly = program(
+            vec![
+                Instruction::ArithmeticOperation(Arithmetic::FloatAssign(FloatAssign {
+                    operator: Operator::Add,
+                    dest: xmm(0),
+                    value: Operand::Memory(Memory {
+                        displacement: 4,
+                        base: Base::R(R::Rsp),
+                        index: None,
+                    }),
+                })),
+                Instruction::Move(Move::FloatFromMemory {
+                    dest: xmm(9),
+                    src: Memory {
+                        displacement: -8,
+                        base: Base::R(R::Rdi),
+                        index: Some(Index {
+                            index: R::Rax,
+                            scale: crate::ir::asm::ScaleFactor::S4,
+                        }),
+                    },
+                }),
+                Instruction::Move(Move::FloatFromMemory {
+                    dest: xmm(1),
+                    src: Memory {
+                        displacement: 0,
+                        base: Base::Constant(0),
+                        index: None,
+                    },
+                }),
+                Instruction::Label(Label { index: 0 }),
+                Instruction::Jump(Jump::AboveEqual {
+                    target: Label { index: 0 },
+                }),
+                Instruction::Return,
+            ],
+            vec![0.5],
+        );
+
+        assert_eq!(
+            print(&assembly, "f", Syntax::Att),
+            "\t.text
+\t.globl\tf
+\t.p2align\t4, 0x90
+f:
+\taddss\t4(%rsp), %xmm0
+\tmovss\t-8(%rdi,%rax,4), %xmm9
+\tmovss\t.Lf_constant0(%rip), %xmm1
+.Lf_0:
+\tjae\t.Lf_0
+\tret
+\t.p2align\t4, 0xcc
+.Lf_constant0:
+\t.long\t0x3f000000\t# 0.5
+"
+        );
+        assert_eq!(
+            print(&assembly, "f", Syntax::Intel),
+            "\t.intel_syntax noprefix
+\t.text
+\t.globl\tf
+\t.p2align\t4, 0x90
+f:
+\taddss\txmm0, dword ptr [rsp + 4]
+\tmovss\txmm9, dword ptr [rdi + rax*4 - 8]
+\tmovss\txmm1, dword ptr [rip + .Lf_constant0]
+.Lf_0:
+\tjae\t.Lf_0
+\tret
+\t.p2align\t4, 0xcc
+.Lf_constant0:
+\t.long\t0x3f000000\t# 0.5
+"
+        );
+    }
+
+    /// Assembles `source` with GNU as, returning the contents of its text section. Fails if binutils
+    /// isn't installed, rather than letting the comparison pass without checking anything.
+    fn gnu_assemble(source: &str, name: &str) -> Vec<u8> {
+        let directory = std::env::temp_dir();
+        let stem = format!("learning-jit-{}-{}", std::process::id(), name);
+        let source_path = directory.join(format!("{}.S", stem));
+        let object_path = directory.join(format!("{}.o", stem));
+        let binary_path = directory.join(format!("{}.bin", stem));
+        std::fs::write(&source_path, source).unwrap();
+
+        let assembled = Command::new("as")
+            .arg("--64")
+            .arg("-o")
+            .arg(&object_path)
+            .arg(&source_path)
+            .output()
+            .unwrap_or_else(|error| {
+                panic!("couldn't run as, which is part of binutils: {}", error)
+            });
+        assert!(
+            assembled.status.success(),
+            "as rejected the program:\n{}\n{}",
+            source,
+            String::from_utf8_lossy(&assembled.stderr)
+        );
+
+        let copied = Command::new("objcopy")
+            .args(["-O", "binary", "--only-section=.text"])
+            .arg(&object_path)
+            .arg(&binary_path)
+            .output()
+            .unwrap_or_else(|error| {
+                panic!("couldn't run objcopy, which is part of binutils: {}", error)
+            });
+        assert!(copied.status.success());
+
+        let text = std::fs::read(&binary_path).unwrap();
+        for path in [source_path, object_path, binary_path] {
+            let _ = std::fs::remove_file(path);
+        }
+        text
+    }
+
+    /// Checks that GNU as assembles the printed `assembly` to the same bytes as `Assemblable`, in
+    /// both syntaxes
+    fn assert_gnu_as_agrees(assembly: &Program, name: &str) {
+        let mut bytes = Bytes::new();
+        assembly.assemble(&mut bytes);
+
+        for syntax in [Syntax::Att, Syntax::Intel] {
+            let name = format!("{}{:?}", name, syntax);
+            let source = print(assembly, &name, syntax);
+            let text = gnu_assemble(&source, &name);
+            assert_eq!(
+                text,
+                bytes.as_slice(),
+                "GNU as disagrees with the assembler on\n{}\nwhich it assembled to\n{}",
+                source,
+                disassemble::listing(bytes.as_slice(), bytes.constants())
+            );
+        }
+    }
+
+    #[test]
+    fn gnu_as_agrees_with_assembler() {
+        let (outputs, variables) = symbolic_outputs(&layer(3, 3).compose(layer(3, 1)));
+        let registers: Vec<_> = (0..variables)
+            .map(|index| register::Register { index })
+            .collect();
+
+        for fuse_multiply_add in [false, true] {
+            let flatten_options = flatten::Options { fuse_multiply_add };
+            let program = flatten::to_program_with(&outputs, registers.clone(), &flatten_options);
+
+            let allocations: [&[Xmm]; 2] = [&emit::allocatable(), &[]];
+            for (allocation, available) in allocations.into_iter().enumerate() {
+                let assignment = register_alloc::assign(&program, available);
+                for (if_positive, three_operand) in [
+                    IfPositiveLowering::Branch,
+                    IfPositiveLowering::Mask,
+                    IfPositiveLowering::Blend,
+                ]
+                .into_iter()
+                .flat_map(|if_positive| [(if_positive, false), (if_positive, true)])
+                {
+                    let options = Options {
+                        target: Target::Avx512,
+                        if_positive,
+                        three_operand,
+                    };
+                    let assembly = emit::emit_program(&program, &assignment, &options);
+                    let fused = assembly.instructions.iter().any(|instruction| {
+                        matches!(
+                            instruction,
+                            Instruction::ArithmeticOperation(Arithmetic::FusedMultiplyAdd(_))
+                        )
+                    });
+                    assert_eq!(fused, fuse_multiply_add);
+
+                    let name = format!(
+                        "{:?}{}{}{}",
+                        if_positive, allocation, three_operand, fuse_multiply_add
+                    );
+                    assert_gnu_as_agrees(&assembly, &name);
+                }
+            }
+        }
+
+        // packed instructions, with outputs in an order that has to be stored one lane at a time,
+        // and operands that have to be gathered from separate floats
+        for lanes in [vectorise::LANES, vectorise::WIDE_LANES] {
+            let (mut outputs, _) = symbolic_outputs(&layer(3, lanes));
+            outputs.swap(0, 1);
+            outputs.extend(
+                (0..lanes).map(|lane| Expr::Variable(lanes - 1 - lane) * Expr::Number(lane as f32)),
+            );
+            for if_positive in [IfPositiveLowering::Mask, IfPositiveLowering::Blend] {
+                let options = vectorise::Options {
+                    target: Target::Avx512,
+                    lanes,
+                    if_positive,
+                };
+                let assembly = vectorise::emit_exprs(&outputs, &options);
+                assert_gnu_as_agrees(&assembly, &format!("Packed{}{:?}", lanes, if_positive));
+            }
+        }
+    }
+}
@@ -1,68 +1,118 @@
 use std::collections::HashMap;
 
-use crate::ir::register::{Expr, Program, Register, Statement, Value};
+use crate::ir::asm::Xmm;
+use crate::ir::register::{Expr, Program, Register, Value};
 
+/// Positions order every read and write in a program. Before the statement at `index`, the inputs
+/// it is the first to read are loaded at `2 * index`; it then reads its operands at `2 * index + 1`
+/// and writes its destination at `2 * index + 2`. The outputs are read as if by one more statement.
+///
+/// Since a statement reads before it writes, a register whose last read is at a statement can hold
+/// that statement's destination too.
+fn load_position(index: usize) -> usize {
+    2 * index
+}
+
+fn read_position(index: usize) -> usize {
+    2 * index + 1
+}
+
+fn write_position(index: usize) -> usize {
+    2 * index + 2
+}
+
+/// The positions from the first write of a register to its last read
 #[derive(Debug)]
-pub struct LiveRange {
+pub struct Interval {
     pub start: usize,
     pub end: usize,
     pub register: Register,
+    /// Every position at which the register is read or written
+    pub uses: Vec<usize>,
 }
 
-pub fn live_ranges(program: &Program) -> Vec<LiveRange> {
-    let mut ranges = HashMap::new();
-
-    for reg in &program.input {
-        ranges.insert(
-            *reg,
-            LiveRange {
-                start: 0,
-                end: 0,
-                register: *reg,
-            },
-        );
+impl Interval {
+    /// How many memory accesses keeping the register on the stack costs, for each position it
+    /// would otherwise occupy an xmm register
+    pub fn spill_weight(&self) -> f32 {
+        self.uses.len() as f32 / (self.end - self.start + 1) as f32
     }
+}
 
+/// For each input, the index of the statement before which it must be loaded, which is the first
+/// one to read it, or `program.statements.len()` if only the outputs read it.
+/// Inputs that are never read, or that are overwritten before being read, aren't loaded at all.
+pub fn input_loads(program: &Program) -> Vec<Option<usize>> {
+    let mut first_mentions = HashMap::new();
     for (index, statement) in program.statements.iter().enumerate() {
-        let mut registers = vec![statement.destination];
+        for register in registers(statement.expr.operands()) {
+            first_mentions.entry(register).or_insert((index, true));
+        }
+        // an `Operation` or a `MultiplyAdd` reads its destination before writing it
+        let read = statement.expr.reads_destination();
+        first_mentions
+            .entry(statement.destination)
+            .or_insert((index, read));
+    }
+    for register in registers(program.output.iter().copied()) {
+        first_mentions
+            .entry(register)
+            .or_insert((program.statements.len(), true));
+    }
 
-        match statement.expr {
-            Expr::Move(Value::Register(register)) => registers.push(register),
-            Expr::Operation {
-                operand: Value::Register(register),
-                ..
-            } => registers.push(register),
-            Expr::IfPositive {
-                predicate,
-                consequent,
-                alternative,
-            } => {
-                for value in [predicate, consequent, alternative] {
-                    if let Value::Register(register) = value {
-                        registers.push(register);
-                    }
-                }
-            }
-            _ => {}
+    program
+        .input
+        .iter()
+        .map(|input| match first_mentions.get(input) {
+            Some((index, true)) => Some(*index),
+            _ => None,
+        })
+        .collect()
+}
+
+fn registers(values: impl IntoIterator<Item = Value>) -> impl Iterator<Item = Register> {
+    values.into_iter().filter_map(|value| match value {
+        Value::Register(register) => Some(register),
+        Value::Number(_) => None,
+    })
+}
+
+/// The interval of every register that is loaded or written, in no particular order
+pub fn intervals(program: &Program) -> Vec<Interval> {
+    let mut uses: HashMap<Register, Vec<usize>> = HashMap::new();
+    let mut used = |register, position| uses.entry(register).or_default().push(position);
+
+    for (input, load) in program.input.iter().zip(input_loads(program)) {
+        if let Some(index) = load {
+            used(*input, load_position(index));
         }
+    }
 
-        for register in registers {
-            if let Some(range) = ranges.get_mut(&register) {
-                range.end = index;
-            } else {
-                ranges.insert(
-                    register,
-                    LiveRange {
-                        start: index,
-                        end: index,
-                        register,
-                    },
-                );
-            }
+    for (index, statement) in program.statements.iter().enumerate() {
+        for register in registers(statement.expr.operands()) {
+            used(register, read_position(index));
+        }
+        if statement.expr.reads_destination() {
+            used(statement.destination, read_position(index));
         }
+        used(statement.destination, write_position(index));
+    }
+
+    for register in registers(program.output.iter().copied()) {
+        used(register, read_position(program.statements.len()));
     }
 
-    ranges.into_values().collect()
+    uses.into_iter()
+        .map(|(register, mut uses)| {
+            uses.sort_unstable();
+            Interval {
+                start: uses[0],
+                end: *uses.last().unwrap(),
+                register,
+                uses,
+            }
+        })
+        .collect()
 }
 
 fn apply_allocation(program: &mut Program, allocation: &HashMap<Register, Register>) {
@@ -87,6 +137,13 @@ fn apply_allocation(program: &mut Program, allocation: &HashMap<Register, Regist
             } => {
                 *register = allocation.get(register).copied().unwrap_or(*register);
             }
+            Expr::MultiplyAdd { first, second } => {
+                for value in [first, second] {
+                    if let Value::Register(register) = value {
+                        *register = allocation.get(register).copied().unwrap_or(*register);
+                    }
+                }
+            }
             Expr::IfPositive {
                 predicate,
                 consequent,
@@ -102,8 +159,10 @@ fn apply_allocation(program: &mut Program, allocation: &HashMap<Register, Regist
         }
     }
 
-    if let Value::Register(register) = &mut program.output {
-        *register = allocation.get(register).copied().unwrap_or(*register);
+    for value in &mut program.output {
+        if let Value::Register(register) = value {
+            *register = allocation.get(register).copied().unwrap_or(*register);
+        }
     }
 }
 
@@ -135,36 +194,332 @@ impl RegisterPool {
     }
 }
 
-/// Return the number of registers the new program requires
+/// Renames the registers of `program` so that registers whose intervals don't overlap share a
+/// name, returning the number of registers the new program requires. Inputs that are never loaded
+/// are given names after those.
 pub fn realloc(program: &mut Program) -> usize {
-    let mut ranges = live_ranges(program);
-    ranges.sort_by_key(|range| range.start);
+    let mut intervals = intervals(program);
+    // unlike native code, which loads inputs when they are first read, `eval::register` defines
+    // every input on entry, so nothing may share an input's register before it is read
+    for interval in &mut intervals {
+        if program.input.contains(&interval.register) {
+            interval.start = 0;
+        }
+    }
+    intervals.sort_by_key(|interval| (interval.start, interval.register));
 
-    let mut active_ranges: Vec<LiveRange> = Vec::new();
+    let mut active: Vec<Interval> = Vec::new();
     let mut free_registers = RegisterPool::new();
     let mut substitution = HashMap::new();
 
-    // iterate over the ranges in increasing starting order.
-    // for each range, GC all the now dead ranges, then choose a new register
-    for current_range in ranges {
-        active_ranges.retain(|active_range| {
-            let old = active_range.end < current_range.start;
+    // iterate over the intervals in increasing starting order.
+    // for each interval, free the registers of the now dead intervals, then choose a new register
+    for current in intervals {
+        active.retain(|interval| {
+            let old = interval.end < current.start;
 
             if old {
-                free_registers.free(substitution[&active_range.register]);
+                free_registers.free(substitution[&interval.register]);
             }
             !old
         });
 
         let new_reg = free_registers.get();
-        substitution.insert(current_range.register, new_reg);
-        active_ranges.push(current_range);
+        substitution.insert(current.register, new_reg);
+        active.push(current);
+    }
+
+    let registers = free_registers.unused;
+    for input in &program.input {
+        substitution.entry(*input).or_insert_with(|| {
+            let index = free_registers.unused;
+            free_registers.unused += 1;
+            Register { index }
+        });
     }
     apply_allocation(program, &substitution);
 
-    substitution
-        .into_values()
-        .map(|x| x.index + 1)
-        .max()
-        .unwrap_or(0)
+    registers
+}
+
+/// Where a register of a program lives once it is turned into machine code
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    Xmm(Xmm),
+    /// The index of a 4 byte slot on the stack
+    Stack(usize),
+}
+
+#[derive(Debug)]
+pub struct Assignment {
+    pub locations: HashMap<Register, Location>,
+    pub stack_slots: usize,
+    /// The number of reads and writes of registers on the stack, each of which is a memory access
+    pub spilled_uses: usize,
+}
+
+impl Assignment {
+    pub fn location(&self, register: Register) -> Location {
+        match self.locations.get(&register) {
+            Some(location) => *location,
+            None => panic!("register {:?} has no location", register),
+        }
+    }
+
+    /// The number of distinct xmm registers used
+    pub fn registers(&self) -> usize {
+        let mut xmms: Vec<_> = self
+            .locations
+            .values()
+            .filter_map(|location| match location {
+                Location::Xmm(xmm) => Some(*xmm),
+                Location::Stack(_) => None,
+            })
+            .collect();
+        xmms.sort_unstable();
+        xmms.dedup();
+        xmms.len()
+    }
+}
+
+/// A way of giving every register of a program a location
+pub trait Allocator {
+    /// Assigns the registers of `program` to at most the `available` xmm registers, putting the
+    /// rest on the stack
+    fn assign(&self, program: &Program, available: &[Xmm]) -> Assignment;
+}
+
+/// The linear scan of `assign`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LinearScan;
+
+impl Allocator for LinearScan {
+    fn assign(&self, program: &Program, available: &[Xmm]) -> Assignment {
+        assign(program, available)
+    }
+}
+
+/// Assigns every register in `program` to one of the `available` xmm registers, using linear scan
+/// over its intervals. `available` is the register budget: when there are more live intervals than
+/// registers, the one with the lowest spill weight is moved to the stack, preferring the one that
+/// ends last. Like registers, stack slots are reused once the intervals in them have ended, so the
+/// frame grows with the peak number of spilled intervals rather than the total.
+pub fn assign(program: &Program, available: &[Xmm]) -> Assignment {
+    let mut intervals = intervals(program);
+    intervals.sort_by_key(|interval| (interval.start, interval.register));
+
+    let mut active: Vec<(Interval, Xmm)> = Vec::new();
+    let mut free: Vec<Xmm> = available.iter().rev().copied().collect();
+    let mut locations = HashMap::new();
+    let mut stack_slots = 0;
+    let mut spilled_uses = 0;
+    // the end and slot of every interval on the stack that is still live
+    let mut spilled_active: Vec<(usize, usize)> = Vec::new();
+    // slots whose intervals have ended, along with the position the last of them ended at
+    let mut free_slots: Vec<(usize, usize)> = Vec::new();
+
+    let cheaper = |first: &Interval, second: &Interval| {
+        first
+            .spill_weight()
+            .total_cmp(&second.spill_weight())
+            .then(second.end.cmp(&first.end))
+    };
+
+    for current in intervals {
+        active.retain(|(interval, xmm)| {
+            let old = interval.end < current.start;
+            if old {
+                free.push(*xmm);
+            }
+            !old
+        });
+        spilled_active.retain(|(end, slot)| {
+            let old = *end < current.start;
+            if old {
+                free_slots.push((*slot, *end));
+            }
+            !old
+        });
+
+        if let Some(xmm) = free.pop() {
+            locations.insert(current.register, Location::Xmm(xmm));
+            active.push((current, xmm));
+            continue;
+        }
+
+        let cheapest = active
+            .iter()
+            .enumerate()
+            .min_by(|(_, (first, _)), (_, (second, _))| cheaper(first, second))
+            .map(|(index, _)| index);
+
+        let spilled = match cheapest {
+            Some(index) if cheaper(&active[index].0, &current).is_lt() => {
+                let (spilled, xmm) = active.swap_remove(index);
+                locations.insert(current.register, Location::Xmm(xmm));
+                active.push((current, xmm));
+                spilled
+            }
+            _ => current,
+        };
+        // an interval spilled partway through has been live since before `current`, and can only
+        // take a slot that was already free when it started
+        let slot = match free_slots.iter().position(|(_, end)| *end < spilled.start) {
+            Some(index) => free_slots.swap_remove(index).0,
+            None => {
+                stack_slots += 1;
+                stack_slots - 1
+            }
+        };
+        locations.insert(spilled.register, Location::Stack(slot));
+        spilled_active.push((spilled.end, slot));
+        spilled_uses += spilled.uses.len();
+    }
+
+    Assignment {
+        locations,
+        stack_slots,
+        spilled_uses,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compile::assemble::Assemblable;
+    use crate::compile::emit::{self, Options};
+    use crate::eval;
+    use crate::ir::bytes::Bytes;
+    use crate::ir::expr::Operator;
+    use crate::ir::register::Statement;
+    use crate::jit::executable::Function;
+
+    fn r(index: usize) -> Register {
+        Register { index }
+    }
+
+    fn statement(destination: usize, expr: Expr) -> Statement {
+        Statement {
+            destination: r(destination),
+            expr,
+        }
+    }
+
+    fn operation(operator: Operator, operand: usize) -> Expr {
+        Expr::Operation {
+            operator,
+            operand: Value::Register(r(operand)),
+        }
+    }
+
+    fn assert_runs(program: &Program, assignment: &Assignment, values: &[f32]) {
+        let mut bytes = Bytes::new();
+        emit::emit_program(program, assignment, &Options::default()).assemble(&mut bytes);
+        let function =
+            unsafe { Function::new(&bytes, program.input.len(), program.output.len()) }.unwrap();
+        let env = program.input.iter().copied().zip(values.iter().copied());
+        assert_eq!(
+            function.call(values),
+            eval::register::evaluate(program, env.collect())
+        );
+    }
+
+    #[test]
+    fn destinations_reuse_dying_operands() {
+        // %1 = %0; %1 *= %1; %2 = if %1 >= 0 then %1 else 0
+        let mut program = Program {
+            input: vec![r(0)],
+            statements: vec![
+                statement(1, Expr::Move(Value::Register(r(0)))),
+                statement(1, operation(Operator::Multiply, 1)),
+                statement(
+                    2,
+                    Expr::IfPositive {
+                        predicate: Value::Register(r(1)),
+                        consequent: Value::Register(r(1)),
+                        alternative: Value::Number(0.0),
+                    },
+                ),
+            ],
+            output: vec![Value::Register(r(2))],
+        };
+
+        let assignment = assign(&program, &emit::allocatable()[..1]);
+        assert_eq!(assignment.stack_slots, 0, "{:?}", assignment);
+        assert_runs(&program, &assignment, &[-1.5]);
+
+        assert_eq!(realloc(&mut program), 1, "{:?}", program);
+    }
+
+    #[test]
+    fn inputs_load_when_first_read() {
+        // %2 = %0; %2 *= %0; %2 += %1, where %1 can share a register with %0
+        let program = Program {
+            input: vec![r(0), r(1)],
+            statements: vec![
+                statement(2, Expr::Move(Value::Register(r(0)))),
+                statement(2, operation(Operator::Multiply, 0)),
+                statement(2, operation(Operator::Add, 1)),
+            ],
+            output: vec![Value::Register(r(2))],
+        };
+
+        assert_eq!(input_loads(&program), [Some(0), Some(2)]);
+        let assignment = assign(&program, &emit::allocatable()[..2]);
+        assert_eq!(assignment.stack_slots, 0, "{:?}", assignment);
+        assert_eq!(
+            assignment.location(r(0)),
+            assignment.location(r(1)),
+            "{:?}",
+            assignment
+        );
+        assert_runs(&program, &assignment, &[3.0, 0.5]);
+    }
+
+    #[test]
+    fn spills_lightest_interval() {
+        // %2 = %0; %2 *= %2; %2 *= %2; %2 += %1, where %2 is used far more often than %1
+        let program = Program {
+            input: vec![r(0), r(1)],
+            statements: vec![
+                statement(2, Expr::Move(Value::Register(r(0)))),
+                statement(2, operation(Operator::Multiply, 2)),
+                statement(2, operation(Operator::Multiply, 2)),
+                statement(2, operation(Operator::Add, 1)),
+            ],
+            output: vec![Value::Register(r(2))],
+        };
+
+        let assignment = assign(&program, &emit::allocatable()[..1]);
+        assert!(matches!(assignment.location(r(2)), Location::Xmm(_)));
+        assert_eq!(assignment.location(r(1)), Location::Stack(0));
+        // loading %1 into its slot, then reading it
+        assert_eq!(assignment.spilled_uses, 2);
+        assert_runs(&program, &assignment, &[1.25, -3.0]);
+    }
+
+    #[test]
+    fn spill_slots_are_reused() {
+        // %1 = %0; %1 *= %0; %2 = %1; %2 *= %1; ..., where only two registers are live at once
+        let mut statements = Vec::new();
+        for destination in 1..=8 {
+            statements.push(statement(
+                destination,
+                Expr::Move(Value::Register(r(destination - 1))),
+            ));
+            statements.push(statement(
+                destination,
+                operation(Operator::Multiply, destination - 1),
+            ));
+        }
+        let program = Program {
+            input: vec![r(0)],
+            statements,
+            output: vec![Value::Register(r(8))],
+        };
+
+        let assignment = assign(&program, &[]);
+        assert_eq!(assignment.stack_slots, 2, "{:?}", assignment);
+        assert_runs(&program, &assignment, &[1.0625]);
+    }
 }
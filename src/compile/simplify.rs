@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+
+use crate::ir::expr::{Expr, Identity, Operator};
+use crate::math::number::Number;
+
+/// Which rewrites `simplify` may apply.
+///
+/// Every rule except `fast_math` preserves the result of `eval::expr::evaluate` bit for bit, for
+/// every input, so the defaults are safe to use anywhere.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rules {
+    /// Evaluates operations whose operands are both numbers
+    pub fold_constants: bool,
+    /// `x * 1`, `1 * x`, `x / 1`, `x - 0` and `x + -0` become `x`
+    pub identities: bool,
+    /// `x / c` becomes `x * (1 / c)` when `c` is a power of two, so its reciprocal is exact
+    pub exact_reciprocals: bool,
+    /// `IfPositive` with a number as its predicate, or with equal branches, becomes one branch
+    pub branches: bool,
+    /// Rewrites that are only true of real numbers: `x + 0` and `x * 0` ignore signed zeros,
+    /// infinities and NaNs, `x / c` becomes `x * (1 / c)` for any `c`, and constants are
+    /// reassociated, as in `(x + 1) + 2` becoming `x + 3`.
+    pub fast_math: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            fold_constants: true,
+            identities: true,
+            exact_reciprocals: true,
+            branches: true,
+            fast_math: false,
+        }
+    }
+}
+
+/// Whether `expr` is exactly the number `value`, telling `0` and `-0` apart
+fn is(expr: &Expr, value: f32) -> bool {
+    matches!(expr, Expr::Number(number) if number.to_bits() == value.to_bits())
+}
+
+fn is_zero(expr: &Expr) -> bool {
+    matches!(expr, Expr::Number(number) if *number == 0.0)
+}
+
+/// Whether two expressions are certainly equal, without comparing them structurally
+fn same(first: &Expr, second: &Expr) -> bool {
+    match (first, second) {
+        (Expr::Number(first), Expr::Number(second)) => first.to_bits() == second.to_bits(),
+        _ => first.identity().is_some() && first.identity() == second.identity(),
+    }
+}
+
+/// The reciprocal of `number`, if multiplying by it always gives the same result as dividing by
+/// `number`. This holds for powers of two whose reciprocal is also a normal number.
+fn exact_reciprocal(number: f32) -> Option<f32> {
+    let mantissa = number.to_bits() & 0x007f_ffff;
+    let reciprocal = 1.0 / number;
+    (number.is_normal() && mantissa == 0 && reciprocal.is_normal()).then_some(reciprocal)
+}
+
+struct Simplifier<'a> {
+    rules: &'a Rules,
+    /// The simplified form of every shared node visited so far
+    simplified: HashMap<Identity, Expr>,
+}
+
+impl Simplifier<'_> {
+    fn simplify(&mut self, expr: &Expr) -> Expr {
+        let identity = expr.identity();
+        if let Some(simplified) = identity.and_then(|identity| self.simplified.get(&identity)) {
+            return simplified.clone();
+        }
+
+        let simplified = match expr {
+            Expr::Operation { operator, operands } => {
+                let first = self.simplify(&operands[0]);
+                let second = self.simplify(&operands[1]);
+                self.operation(*operator, first, second)
+            }
+            Expr::IfPositive(if_positive) => {
+                let predicate = self.simplify(&if_positive.predicate);
+                let consequent = self.simplify(&if_positive.consequent);
+                let alternative = self.simplify(&if_positive.alternative);
+                self.if_positive(predicate, consequent, alternative)
+            }
+            Expr::Variable(_) | Expr::Number(_) => expr.clone(),
+        };
+
+        if let Some(identity) = identity {
+            self.simplified.insert(identity, simplified.clone());
+        }
+        simplified
+    }
+
+    /// `first operator second`, with both operands already simplified
+    fn operation(&mut self, operator: Operator, first: Expr, second: Expr) -> Expr {
+        let rules = self.rules;
+
+        if rules.fold_constants {
+            if let (Expr::Number(first), Expr::Number(second)) = (&first, &second) {
+                return Expr::Number(operator.apply(*first, *second));
+            }
+        }
+
+        if rules.identities {
+            match operator {
+                Operator::Multiply if is(&second, 1.0) => return first,
+                Operator::Multiply if is(&first, 1.0) => return second,
+                Operator::Divide if is(&second, 1.0) => return first,
+                Operator::Subtract if is(&second, 0.0) => return first,
+                Operator::Add if is(&second, -0.0) => return first,
+                Operator::Add if is(&first, -0.0) => return second,
+                _ => {}
+            }
+        }
+
+        if rules.fast_math {
+            match operator {
+                Operator::Add if is_zero(&second) => return first,
+                Operator::Add if is_zero(&first) => return second,
+                Operator::Subtract if is_zero(&second) => return first,
+                Operator::Multiply if is_zero(&first) || is_zero(&second) => {
+                    return Expr::Number(0.0)
+                }
+                Operator::Subtract if same(&first, &second) => return Expr::Number(0.0),
+                Operator::Divide if same(&first, &second) => return Expr::Number(1.0),
+                _ => {}
+            }
+
+            // (x op c1) op c2 = x op (c1 op c2) for associative operators
+            if let (
+                Expr::Operation {
+                    operator: inner,
+                    operands,
+                },
+                Expr::Number(outer),
+            ) = (&first, &second)
+            {
+                if *inner == operator && operator.is_associative() {
+                    if let Expr::Number(constant) = operands[1] {
+                        let constant = Expr::Number(operator.apply(constant, *outer));
+                        return self.operation(operator, operands[0].clone(), constant);
+                    }
+                }
+            }
+        }
+
+        if let (Operator::Divide, Expr::Number(divisor)) = (operator, &second) {
+            let reciprocal = if rules.fast_math {
+                Some(1.0 / divisor)
+            } else if rules.exact_reciprocals {
+                exact_reciprocal(*divisor)
+            } else {
+                None
+            };
+            if let Some(reciprocal) = reciprocal {
+                return self.operation(Operator::Multiply, first, Expr::Number(reciprocal));
+            }
+        }
+
+        match operator {
+            Operator::Add => first + second,
+            Operator::Subtract => first - second,
+            Operator::Multiply => first * second,
+            Operator::Divide => first / second,
+        }
+    }
+
+    /// `if predicate >= 0 then consequent else alternative`, with every part already simplified
+    fn if_positive(&mut self, predicate: Expr, consequent: Expr, alternative: Expr) -> Expr {
+        if self.rules.branches {
+            if let Expr::Number(predicate) = predicate {
+                return if predicate >= 0.0 {
+                    consequent
+                } else {
+                    alternative
+                };
+            }
+            if same(&consequent, &alternative) {
+                return consequent;
+            }
+        }
+        predicate.if_positive(consequent, alternative)
+    }
+}
+
+/// Rewrites `expr` into an equivalent expression with fewer operations, applying `rules` bottom up.
+/// Subexpressions shared through an `Rc` are simplified once and stay shared.
+pub fn simplify(expr: &Expr, rules: &Rules) -> Expr {
+    Simplifier {
+        rules,
+        simplified: HashMap::new(),
+    }
+    .simplify(expr)
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::eval;
+    use crate::harness::generate::{random_case, random_value};
+
+    /// One more than the highest variable in `expr`
+    fn variables(expr: &Expr) -> usize {
+        match expr {
+            Expr::Operation { operands, .. } => {
+                variables(&operands[0]).max(variables(&operands[1]))
+            }
+            Expr::IfPositive(if_positive) => variables(&if_positive.predicate)
+                .max(variables(&if_positive.consequent))
+                .max(variables(&if_positive.alternative)),
+            Expr::Variable(index) => index + 1,
+            Expr::Number(_) => 0,
+        }
+    }
+
+    fn evaluate(expr: &Expr, values: &[f32]) -> f32 {
+        eval::expr::evaluate(expr, &values.iter().copied().enumerate().collect())
+    }
+
+    fn x() -> Expr {
+        Expr::Variable(0)
+    }
+
+    fn number(value: f32) -> Expr {
+        Expr::Number(value)
+    }
+
+    fn assert_simplifies(expr: Expr, rules: &Rules, expected: Expr) {
+        let simplified = simplify(&expr, rules);
+        assert_eq!(
+            format!("{:?}", simplified),
+            format!("{:?}", expected),
+            "{:?} simplified incorrectly",
+            expr
+        );
+    }
+
+    #[test]
+    fn safe_rules() {
+        let rules = Rules::default();
+        assert_simplifies(x() * number(1.0), &rules, x());
+        assert_simplifies(number(1.0) * x(), &rules, x());
+        assert_simplifies(x() - number(0.0), &rules, x());
+        assert_simplifies(x() + number(-0.0), &rules, x());
+        assert_simplifies(x() / number(2.0), &rules, x() * number(0.5));
+        assert_simplifies((number(3.0) + number(1.0)) * x(), &rules, number(4.0) * x());
+        assert_simplifies(number(-1.0).if_positive(x(), x() * x()), &rules, x() * x());
+
+        // these change the result for some inputs
+        assert_simplifies(x() + number(0.0), &rules, x() + number(0.0));
+        assert_simplifies(x() * number(0.0), &rules, x() * number(0.0));
+        assert_simplifies(x() / number(3.0), &rules, x() / number(3.0));
+        assert_simplifies(
+            (x() + number(1.0)) + number(2.0),
+            &rules,
+            (x() + number(1.0)) + number(2.0),
+        );
+    }
+
+    #[test]
+    fn fast_math_rules() {
+        let rules = Rules {
+            fast_math: true,
+            ..Rules::default()
+        };
+        assert_simplifies(x() + number(0.0), &rules, x());
+        assert_simplifies(x() * number(0.0), &rules, number(0.0));
+        assert_simplifies(x() / number(4.0), &rules, x() * number(0.25));
+        assert_simplifies((x() + number(1.0)) + number(2.0), &rules, x() + number(3.0));
+        assert_simplifies((x() * number(2.0)) * number(0.5), &rules, x());
+
+        let y = x() * x();
+        assert_simplifies(y.clone() - y, &rules, number(0.0));
+    }
+
+    #[test]
+    fn disabled_rules() {
+        let rules = Rules {
+            fold_constants: false,
+            identities: false,
+            exact_reciprocals: false,
+            branches: false,
+            fast_math: false,
+        };
+        for expr in [
+            x() * number(1.0),
+            number(2.0) + number(1.0),
+            x() / number(2.0),
+            number(1.0).if_positive(x(), x()),
+        ] {
+            assert_simplifies(expr.clone(), &rules, expr);
+        }
+    }
+
+    #[test]
+    fn exact_reciprocals() {
+        assert_eq!(exact_reciprocal(2.0), Some(0.5));
+        assert_eq!(exact_reciprocal(-0.25), Some(-4.0));
+        assert_eq!(exact_reciprocal(3.0), None);
+        assert_eq!(exact_reciprocal(0.0), None);
+        assert_eq!(exact_reciprocal(f32::INFINITY), None);
+        // 2^127 is normal, but its reciprocal isn't
+        assert_eq!(exact_reciprocal(f32::from_bits(0x7f00_0000)), None);
+    }
+
+    #[test]
+    fn preserves_evaluation() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let y = || Expr::Variable(1);
+        let constants = (x() * number(1.0) + number(-0.0)) / number(0.25)
+            - (y() - number(0.0)).if_positive(y() / number(1.0), number(2.0) * number(3.0))
+            + number(0.0).if_positive(x() / number(-8.0), y());
+
+        let mut exprs = vec![constants];
+        exprs.extend((0..32).flat_map(|_| random_case(&mut rng).exprs));
+
+        for expr in exprs {
+            let count = 2.max(variables(&expr));
+            let simplified = simplify(&expr, &Rules::default());
+
+            for _ in 0..8 {
+                let values: Vec<f32> = (0..count).map(|_| random_value(&mut rng)).collect();
+                let original = evaluate(&expr, &values);
+                let result = evaluate(&simplified, &values);
+                assert!(
+                    original.to_bits() == result.to_bits()
+                        || (original.is_nan() && result.is_nan()),
+                    "{:?} = {} but {:?} = {} on {:?}",
+                    expr,
+                    original,
+                    simplified,
+                    result,
+                    values
+                );
+            }
+        }
+    }
+}
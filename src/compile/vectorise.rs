@@ -0,0 +1,951 @@
+use std::collections::HashMap;
+
+use crate::ir::asm;
+use crate::ir::dag::{Dag, Node, NodeId};
+use crate::ir::expr::{self, Operator};
+use crate::ir::target::Target;
+
+use super::emit::{self, ConstantPool, IfPositiveLowering};
+
+/// The number of floats in an xmm register
+pub const LANES: usize = 4;
+
+/// The number of floats in a ymm register
+pub const WIDE_LANES: usize = 8;
+
+/// Choices about the code generated by `emit_exprs`
+pub struct Options {
+    /// The cpu the function will run on
+    pub target: Target,
+    /// The number of isomorphic trees evaluated at once, either 1, [`LANES`], or [`WIDE_LANES`] if
+    /// the target has AVX
+    pub lanes: usize,
+    /// `Branch` can't choose a different value in each lane, so it is lowered like `Mask`
+    pub if_positive: IfPositiveLowering,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            target: Target::default(),
+            lanes: LANES,
+            if_positive: IfPositiveLowering::Mask,
+        }
+    }
+}
+
+/// One node of the shape of a tree, in preorder
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum Shape {
+    Operation(Operator),
+    IfPositive,
+    /// A variable, a number, or the result of another tree, which are all read from memory
+    Leaf,
+}
+
+/// How the nodes of a dag are split into trees, and the order they are evaluated in.
+///
+/// Every root, every node used more than once, and every node that would need more registers than
+/// are available is the root of its own tree, whose result is stored to the stack. Trees of the
+/// same shape whose leaves are all ready are evaluated together, one per lane.
+struct Plan {
+    /// Whether each node is the root of a tree
+    separate: Vec<bool>,
+    /// The registers needed to evaluate each node as part of its parent's tree
+    need: Vec<usize>,
+    /// Trees of the same shape, each evaluated after the trees its leaves read
+    groups: Vec<Vec<NodeId>>,
+    /// The stack slot holding the result of each tree read by another tree
+    slots: HashMap<NodeId, usize>,
+}
+
+fn is_leaf(node: Node) -> bool {
+    matches!(node, Node::Variable(_) | Node::Number(_))
+}
+
+/// The registers needed to evaluate children needing `needs` registers, evaluating the child that
+/// needs the most first so that each following child has one register fewer
+fn combined_need(mut needs: Vec<usize>) -> usize {
+    needs.sort_by(|first, second| second.cmp(first));
+    needs
+        .iter()
+        .enumerate()
+        .map(|(index, need)| need + index)
+        .max()
+        .unwrap_or(1)
+}
+
+/// The children of the `index`th node in every lane of a group of isomorphic trees
+fn children(dag: &Dag, lanes: &[NodeId], index: usize) -> Vec<NodeId> {
+    lanes
+        .iter()
+        .map(|id| dag.node(*id).children()[index])
+        .collect()
+}
+
+impl Plan {
+    fn new(dag: &Dag, roots: &[NodeId], lanes: usize, registers: usize) -> Self {
+        assert!(
+            registers >= 3,
+            "an if positive of three results needs three registers"
+        );
+
+        let uses = dag.uses(roots);
+        let mut separate: Vec<_> = (0..dag.len())
+            .map(|index| {
+                let node = dag.node(NodeId { index });
+                !is_leaf(node) && uses[index] > 1
+            })
+            .collect();
+        for root in roots {
+            separate[root.index] = true;
+        }
+
+        // every child comes before its parent, so their needs are known
+        let mut need = vec![1; dag.len()];
+        for index in 0..dag.len() {
+            let node = dag.node(NodeId { index });
+            if uses[index] == 0 || is_leaf(node) {
+                continue;
+            }
+            loop {
+                let effective = |child: &NodeId| {
+                    if separate[child.index] || is_leaf(dag.node(*child)) {
+                        1
+                    } else {
+                        need[child.index]
+                    }
+                };
+                let children = node.children();
+                let total = combined_need(children.iter().map(effective).collect());
+                if total <= registers {
+                    need[index] = total;
+                    break;
+                }
+                // the child needing the most registers becomes a tree of its own
+                let heaviest = children
+                    .iter()
+                    .max_by_key(|child| effective(child))
+                    .unwrap();
+                separate[heaviest.index] = true;
+            }
+        }
+
+        // the number of rounds of trees that must be evaluated before each node
+        let mut after = vec![0; dag.len()];
+        for index in 0..dag.len() {
+            after[index] = dag
+                .node(NodeId { index })
+                .children()
+                .iter()
+                .map(|child| {
+                    if is_leaf(dag.node(*child)) {
+                        0
+                    } else if separate[child.index] {
+                        after[child.index] + 1
+                    } else {
+                        after[child.index]
+                    }
+                })
+                .max()
+                .unwrap_or(0);
+        }
+
+        let mut trees: Vec<_> = (0..dag.len())
+            .filter(|index| separate[*index] && uses[*index] > 0)
+            .map(|index| NodeId { index })
+            .collect();
+        trees.sort_by_key(|id| (after[id.index], id.index));
+
+        // trees of the same round and shape, in order of their first member
+        let mut groups = Vec::new();
+        for round in trees.chunk_by(|first, second| after[first.index] == after[second.index]) {
+            let mut shapes: Vec<(Vec<Shape>, Vec<NodeId>)> = Vec::new();
+            for id in round {
+                let mut shape = Vec::new();
+                tree_shape(dag, &separate, *id, true, &mut shape);
+                match shapes.iter_mut().find(|(existing, _)| *existing == shape) {
+                    Some((_, ids)) => ids.push(*id),
+                    None => shapes.push((shape, vec![*id])),
+                }
+            }
+            for (_, ids) in shapes {
+                // trees left over from the widest groups are packed into narrower ones
+                let mut chunks = ids.chunks_exact(lanes);
+                groups.extend(chunks.by_ref().map(<[_]>::to_vec));
+                let mut narrower = chunks.remainder().chunks_exact(LANES.min(lanes));
+                groups.extend(narrower.by_ref().map(<[_]>::to_vec));
+                groups.extend(narrower.remainder().iter().map(|id| vec![*id]));
+            }
+        }
+
+        // the results of a group are given consecutive slots, so they can be read back together
+        let mut read = vec![false; dag.len()];
+        for (index, uses) in uses.iter().enumerate() {
+            if *uses > 0 {
+                for child in dag.node(NodeId { index }).children() {
+                    read[child.index] = true;
+                }
+            }
+        }
+        let mut slots = HashMap::new();
+        for group in &groups {
+            if !is_leaf(dag.node(group[0])) && group.iter().any(|id| read[id.index]) {
+                for id in group {
+                    slots.insert(*id, slots.len());
+                }
+            }
+        }
+
+        Self {
+            separate,
+            need,
+            groups,
+            slots,
+        }
+    }
+
+    /// Whether `id` is read from memory by the tree containing it
+    fn is_leaf(&self, dag: &Dag, id: NodeId) -> bool {
+        self.separate[id.index] || is_leaf(dag.node(id))
+    }
+
+    fn effective_need(&self, dag: &Dag, id: NodeId) -> usize {
+        if self.is_leaf(dag, id) {
+            1
+        } else {
+            self.need[id.index]
+        }
+    }
+}
+
+fn tree_shape(dag: &Dag, separate: &[bool], id: NodeId, root: bool, shape: &mut Vec<Shape>) {
+    let node = dag.node(id);
+    if is_leaf(node) || (separate[id.index] && !root) {
+        shape.push(Shape::Leaf);
+        return;
+    }
+    shape.push(match node {
+        Node::Operation { operator, .. } => Shape::Operation(operator),
+        Node::IfPositive { .. } => Shape::IfPositive,
+        Node::Variable(_) | Node::Number(_) => unreachable!("leaves are handled above"),
+    });
+    for child in node.children() {
+        tree_shape(dag, separate, child, false, shape);
+    }
+}
+
+/// Where a float read or written by a tree lives
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Address {
+    Input(usize),
+    Output(usize),
+    Slot(usize),
+    /// The bits of a number in the constant pool
+    Constant(u32),
+}
+
+impl Address {
+    fn memory(self, constants: &mut ConstantPool) -> asm::Memory {
+        match self {
+            Address::Input(index) => emit::input_access(index),
+            Address::Output(index) => emit::output_access(index),
+            Address::Slot(slot) => emit::slot_access(slot),
+            Address::Constant(bits) => constants.access(f32::from_bits(bits)),
+        }
+    }
+
+    /// Whether `self` is the float right after `previous` in memory
+    fn follows(self, previous: Address) -> bool {
+        match (previous, self) {
+            (Address::Input(previous), Address::Input(next))
+            | (Address::Output(previous), Address::Output(next))
+            | (Address::Slot(previous), Address::Slot(next)) => next == previous + 1,
+            _ => false,
+        }
+    }
+}
+
+fn consecutive(addresses: &[Address]) -> bool {
+    addresses.windows(2).all(|pair| pair[1].follows(pair[0]))
+}
+
+struct Emitter<'a> {
+    dag: &'a Dag,
+    plan: &'a Plan,
+    if_positive: IfPositiveLowering,
+    /// The number of lanes of the group being emitted
+    lanes: usize,
+    constants: ConstantPool,
+    instructions: Vec<asm::Instruction>,
+}
+
+impl Emitter<'_> {
+    fn mov(&mut self, mov: asm::Move) {
+        self.instructions.push(asm::Instruction::Move(mov));
+    }
+
+    fn packed(&self) -> bool {
+        self.lanes > 1
+    }
+
+    /// Whether the group being emitted is in ymm registers. Its instructions are all VEX encoded,
+    /// since SSE instructions would have to preserve the high halves of the registers.
+    fn wide(&self) -> bool {
+        self.lanes > LANES
+    }
+
+    fn copy(&mut self, dest: asm::Xmm, src: asm::Xmm) {
+        if dest == src {
+            return;
+        }
+        if self.wide() {
+            self.mov(asm::Move::WideToWide { dest, src });
+        } else if self.packed() {
+            self.mov(asm::Move::PackedToPacked { dest, src });
+        } else {
+            self.mov(asm::Move::FloatToFloat { dest, src });
+        }
+    }
+
+    fn arithmetic(&mut self, operator: Operator, dest: asm::Xmm, value: asm::Xmm) {
+        let value = asm::Operand::Xmm(value);
+        let arithmetic = if self.wide() {
+            asm::Arithmetic::WideOperation(asm::FloatOperation {
+                operator,
+                dest,
+                first: dest,
+                value,
+            })
+        } else {
+            let assign = asm::FloatAssign {
+                operator,
+                dest,
+                value,
+            };
+            if self.packed() {
+                asm::Arithmetic::PackedAssign(assign)
+            } else {
+                asm::Arithmetic::FloatAssign(assign)
+            }
+        };
+        self.instructions
+            .push(asm::Instruction::ArithmeticOperation(arithmetic));
+    }
+
+    fn logical(&mut self, operator: asm::Logical, dest: asm::Xmm, value: asm::Xmm) {
+        if self.wide() {
+            self.instructions
+                .push(asm::Instruction::ArithmeticOperation(
+                    asm::Arithmetic::WideLogical(asm::LogicalOperation {
+                        operator,
+                        dest,
+                        first: dest,
+                        value,
+                    }),
+                ));
+        } else {
+            self.instructions.push(emit::logical(operator, dest, value));
+        }
+    }
+
+    fn address(&self, id: NodeId) -> Address {
+        match self.dag.node(id) {
+            Node::Variable(index) => Address::Input(index),
+            Node::Number(bits) => Address::Constant(bits),
+            Node::Operation { .. } | Node::IfPositive { .. } => Address::Slot(self.plan.slots[&id]),
+        }
+    }
+
+    /// Loads the float at each address into the matching lane of `dest`
+    fn load(&mut self, addresses: &[Address], dest: asm::Xmm) {
+        if self.wide() {
+            self.load_wide(addresses, dest);
+            return;
+        }
+        let first = addresses[0].memory(&mut self.constants);
+        if addresses.len() == 1 {
+            self.mov(asm::Move::FloatFromMemory { dest, src: first });
+        } else if addresses.iter().all(|address| *address == addresses[0]) {
+            // broadcasts the low lane to every lane
+            self.mov(asm::Move::FloatFromMemory { dest, src: first });
+            self.mov(asm::Move::Shuffle {
+                dest,
+                src: dest,
+                selector: 0,
+            });
+        } else if consecutive(addresses) {
+            self.mov(asm::Move::PackedFromMemory { dest, src: first });
+        } else {
+            // [a, b] and [c, d] are interleaved separately, then joined into [a, b, c, d]
+            assert_eq!(addresses.len(), LANES);
+            let high = emit::scratch(1);
+            let other = emit::scratch(2);
+            let load = |emitter: &mut Self, dest, address: Address| {
+                let src = address.memory(&mut emitter.constants);
+                emitter.mov(asm::Move::FloatFromMemory { dest, src });
+            };
+            load(self, dest, addresses[0]);
+            load(self, other, addresses[1]);
+            self.mov(asm::Move::InterleaveLow { dest, src: other });
+            load(self, high, addresses[2]);
+            load(self, other, addresses[3]);
+            self.mov(asm::Move::InterleaveLow {
+                dest: high,
+                src: other,
+            });
+            self.mov(asm::Move::LowToHigh { dest, src: high });
+        }
+    }
+
+    /// Like `load`, for the lanes of a ymm register. Floats that aren't next to each other are
+    /// broadcast and blended into the lanes that read them.
+    fn load_wide(&mut self, addresses: &[Address], dest: asm::Ymm) {
+        let first = addresses[0].memory(&mut self.constants);
+        if consecutive(addresses) {
+            self.mov(asm::Move::WideFromMemory { dest, src: first });
+            return;
+        }
+
+        if addresses.iter().all(|address| *address == addresses[0]) {
+            self.mov(asm::Move::BroadcastFromMemory { dest, src: first });
+            return;
+        }
+
+        // gather each half into an xmm register, then join them
+        let high = emit::scratch(1);
+        for (half, addresses) in [dest, high].into_iter().zip(addresses.chunks_exact(LANES)) {
+            let src = addresses[0].memory(&mut self.constants);
+            self.mov(asm::Move::BroadcastFromMemory { dest: half, src });
+            for (lane, address) in addresses.iter().enumerate().skip(1) {
+                let src = address.memory(&mut self.constants);
+                self.mov(asm::Move::InsertFromMemory {
+                    dest: half,
+                    first: half,
+                    src,
+                    lane: lane as u8,
+                });
+            }
+        }
+        self.mov(asm::Move::XmmToHigh {
+            dest,
+            first: dest,
+            src: high,
+        });
+    }
+
+    /// Stores each lane of `src` to the matching address
+    fn store(&mut self, src: asm::Xmm, addresses: &[Address]) {
+        if addresses.len() > 1 && consecutive(addresses) {
+            let dest = addresses[0].memory(&mut self.constants);
+            if self.wide() {
+                self.mov(asm::Move::WideToMemory { dest, src });
+            } else {
+                self.mov(asm::Move::PackedToMemory { dest, src });
+            }
+            return;
+        }
+        for (lane, address) in addresses.iter().enumerate() {
+            self.store_lane(src, lane, *address);
+        }
+    }
+
+    fn store_lane(&mut self, src: asm::Xmm, lane: usize, address: Address) {
+        if self.wide() {
+            let (src, lane) = if lane < LANES {
+                (src, lane)
+            } else {
+                let high = emit::scratch(1);
+                self.mov(asm::Move::HighToXmm { dest: high, src });
+                (high, lane - LANES)
+            };
+            let dest = address.memory(&mut self.constants);
+            self.mov(asm::Move::LaneToMemory {
+                dest,
+                src,
+                lane: lane as u8,
+            });
+            return;
+        }
+        let lane_src = if lane == 0 {
+            src
+        } else {
+            // broadcasts the lane into %xmm1, whose low lane is then stored
+            let scratch = emit::scratch(1);
+            self.mov(asm::Move::PackedToPacked { dest: scratch, src });
+            self.mov(asm::Move::Shuffle {
+                dest: scratch,
+                src: scratch,
+                selector: lane as u8 * 0b01010101,
+            });
+            scratch
+        };
+        let dest = address.memory(&mut self.constants);
+        self.mov(asm::Move::FloatToMemory {
+            dest,
+            src: lane_src,
+        });
+    }
+
+    /// Evaluates the trees rooted at `lanes`, one per lane, into `registers[0]`, clobbering the
+    /// rest of `registers`. `root` is set for the roots of the trees, which aren't read from memory
+    /// even though they are separate.
+    fn tree(&mut self, lanes: &[NodeId], registers: &[asm::Xmm], root: bool) {
+        let node = self.dag.node(lanes[0]);
+        if is_leaf(node) || (!root && self.plan.separate[lanes[0].index]) {
+            let addresses: Vec<_> = lanes.iter().map(|id| self.address(*id)).collect();
+            self.load(&addresses, registers[0]);
+            return;
+        }
+
+        // the children are evaluated in order of the registers they need, most first
+        let children: Vec<_> = (0..node.children().len())
+            .map(|index| children(self.dag, lanes, index))
+            .collect();
+        let mut order: Vec<_> = (0..children.len()).collect();
+        order.sort_by_key(|index| {
+            std::cmp::Reverse(self.plan.effective_need(self.dag, children[*index][0]))
+        });
+        let mut evaluated = vec![registers[0]; children.len()];
+        for (position, index) in order.iter().enumerate() {
+            self.tree(&children[*index], &registers[position..], false);
+            evaluated[*index] = registers[position];
+        }
+
+        match node {
+            Node::Operation { operator, .. } => {
+                let [first, second] = [evaluated[0], evaluated[1]];
+                // the operands keep their order, even for associative operators, since swapping
+                // them would change which NaN payload the result has
+                if first == registers[0] {
+                    self.arithmetic(operator, registers[0], registers[1]);
+                } else {
+                    self.arithmetic(operator, first, second);
+                    self.copy(registers[0], first);
+                }
+            }
+            Node::IfPositive { .. } => {
+                let [predicate, consequent, alternative] =
+                    [evaluated[0], evaluated[1], evaluated[2]];
+                let mask = emit::scratch(0);
+
+                // loading a single 0 zeroes every lane of an xmm register
+                let zero = self.constants.access(0.0);
+                if self.wide() {
+                    self.mov(asm::Move::BroadcastFromMemory {
+                        dest: mask,
+                        src: zero,
+                    });
+                } else {
+                    self.mov(asm::Move::FloatFromMemory {
+                        dest: mask,
+                        src: zero,
+                    });
+                }
+                let compare = if self.wide() {
+                    asm::Compare::WideCompareToMask {
+                        condition: asm::Condition::LessEqual,
+                        dest: mask,
+                        first: mask,
+                        value: predicate,
+                    }
+                } else if self.packed() {
+                    asm::Compare::PackedCompareToMask {
+                        condition: asm::Condition::LessEqual,
+                        dest: mask,
+                        value: predicate,
+                    }
+                } else {
+                    asm::Compare::CompareToMask {
+                        condition: asm::Condition::LessEqual,
+                        dest: mask,
+                        value: predicate,
+                    }
+                };
+                self.instructions.push(asm::Instruction::Compare(compare));
+
+                match self.if_positive {
+                    IfPositiveLowering::Blend if self.wide() => {
+                        self.mov(asm::Move::WideBlendWithMask {
+                            dest: registers[0],
+                            first: alternative,
+                            src: consequent,
+                            mask,
+                        });
+                    }
+                    IfPositiveLowering::Blend => {
+                        self.mov(asm::Move::BlendWithMask {
+                            dest: alternative,
+                            src: consequent,
+                        });
+                        self.copy(registers[0], alternative);
+                    }
+                    IfPositiveLowering::Branch | IfPositiveLowering::Mask => {
+                        self.logical(asm::Logical::And, consequent, mask);
+                        self.logical(asm::Logical::AndNot, mask, alternative);
+                        self.logical(asm::Logical::Or, mask, consequent);
+                        self.copy(registers[0], mask);
+                    }
+                }
+            }
+            Node::Variable(_) | Node::Number(_) => unreachable!("leaves are loaded above"),
+        }
+    }
+}
+
+/// Compiles `exprs` into a function following the same convention as `emit::emit_program`, where
+/// the `i`th variable is the `i`th element of the input and the `i`th output is `exprs[i]`.
+///
+/// Instead of allocating registers, each tree of the expressions is evaluated in turn, and trees
+/// of the same shape, like the repetitions of a `Repeat`, are evaluated `options.lanes` at a time
+/// with packed instructions.
+pub fn emit_exprs(exprs: &[expr::Expr], options: &Options) -> asm::Program {
+    emit_with_registers(exprs, options, &emit::allocatable())
+}
+
+fn emit_with_registers(
+    exprs: &[expr::Expr],
+    options: &Options,
+    registers: &[asm::Xmm],
+) -> asm::Program {
+    assert!(
+        [1, LANES, WIDE_LANES].contains(&options.lanes),
+        "can't evaluate {} lanes at once",
+        options.lanes
+    );
+    assert!(
+        options.lanes <= LANES || options.target.has_avx(),
+        "{} lanes need ymm registers, which {:?} doesn't have",
+        options.lanes,
+        options.target
+    );
+
+    let mut dag = Dag::new();
+    let roots: Vec<_> = exprs.iter().map(|expr| dag.add(expr)).collect();
+    let plan = Plan::new(&dag, &roots, options.lanes, registers.len());
+
+    let mut outputs: HashMap<NodeId, Vec<usize>> = HashMap::new();
+    for (index, root) in roots.iter().enumerate() {
+        outputs.entry(*root).or_default().push(index);
+    }
+
+    let mut emitter = Emitter {
+        dag: &dag,
+        plan: &plan,
+        if_positive: options.if_positive,
+        lanes: 1,
+        constants: ConstantPool::default(),
+        instructions: Vec::new(),
+    };
+
+    let stack_allocation = TryInto::<u32>::try_into(plan.slots.len()).unwrap() * 4;
+    emitter
+        .instructions
+        .push(asm::Instruction::ArithmeticOperation(
+            asm::Arithmetic::IntegerSubAssign(asm::IntegerAssign {
+                dest: asm::R::Rsp,
+                value: stack_allocation,
+            }),
+        ));
+
+    for group in &plan.groups {
+        emitter.lanes = group.len();
+        emitter.tree(group, registers, true);
+        let result = registers[0];
+
+        if plan.slots.contains_key(&group[0]) {
+            let slots: Vec<_> = group
+                .iter()
+                .map(|id| Address::Slot(plan.slots[id]))
+                .collect();
+            emitter.store(result, &slots);
+        }
+
+        // the first output of every lane is stored together, and any others one at a time
+        let lanes: Vec<_> = group
+            .iter()
+            .map(|id| outputs.get(id).map_or(&[][..], Vec::as_slice))
+            .collect();
+        let mut rest = Vec::new();
+        if lanes.iter().all(|outputs| !outputs.is_empty()) {
+            let first: Vec<_> = lanes
+                .iter()
+                .map(|outputs| Address::Output(outputs[0]))
+                .collect();
+            emitter.store(result, &first);
+            for (lane, outputs) in lanes.iter().enumerate() {
+                rest.extend(outputs[1..].iter().map(|output| (lane, *output)));
+            }
+        } else {
+            for (lane, outputs) in lanes.iter().enumerate() {
+                rest.extend(outputs.iter().map(|output| (lane, *output)));
+            }
+        }
+        for (lane, output) in rest {
+            emitter.store_lane(result, lane, Address::Output(output));
+        }
+        if emitter.wide() {
+            emitter.instructions.push(asm::Instruction::ZeroUpper);
+        }
+    }
+
+    emitter
+        .instructions
+        .push(asm::Instruction::ArithmeticOperation(
+            asm::Arithmetic::IntegerAddAssign(asm::IntegerAssign {
+                dest: asm::R::Rsp,
+                value: stack_allocation,
+            }),
+        ));
+    emitter.instructions.push(asm::Instruction::Return);
+
+    asm::Program {
+        instructions: emitter.instructions,
+        constants: emitter.constants.constants,
+        target: options.target,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::compile::assemble::Assemblable;
+    use crate::eval;
+    use crate::harness::generate::{random_network, random_value, spread_values, symbolic_outputs};
+    use crate::ir::bytes::Bytes;
+    use crate::ir::expr::Expr;
+    use crate::jit::executable::Function;
+    use crate::neurons::learning::layer;
+
+    fn compile(exprs: &[Expr], variables: usize, program: &asm::Program) -> Function {
+        let mut bytes = Bytes::new();
+        program.assemble(&mut bytes);
+        unsafe { Function::new(&bytes, variables, exprs.len()) }.unwrap()
+    }
+
+    fn assert_matches_tree(function: &Function, exprs: &[Expr], values: &[f32]) {
+        let env: HashMap<_, _> = values.iter().copied().enumerate().collect();
+        let native = function.call(values);
+        for (native, expr) in native.iter().zip(exprs) {
+            let expected = eval::expr::evaluate(expr, &env);
+            assert!(
+                native.to_bits() == expected.to_bits(),
+                "{:?} != {:?} on {:?}",
+                native,
+                expected,
+                values
+            );
+        }
+    }
+
+    fn layer_outputs(input: usize, output: usize) -> (Vec<Expr>, usize) {
+        symbolic_outputs(&layer(input, output))
+    }
+
+    fn count(program: &asm::Program, matches: fn(&asm::Instruction) -> bool) -> usize {
+        program
+            .instructions
+            .iter()
+            .filter(|instruction| matches(instruction))
+            .count()
+    }
+
+    #[test]
+    fn repetitions_are_packed() {
+        let (exprs, variables) = layer_outputs(8, 8);
+        let packed = emit_exprs(&exprs, &Options::default());
+        let scalar = emit_exprs(
+            &exprs,
+            &Options {
+                lanes: 1,
+                ..Options::default()
+            },
+        );
+
+        let is_scalar = |instruction: &asm::Instruction| {
+            matches!(
+                instruction,
+                asm::Instruction::ArithmeticOperation(asm::Arithmetic::FloatAssign(_))
+            )
+        };
+        let is_packed = |instruction: &asm::Instruction| {
+            matches!(
+                instruction,
+                asm::Instruction::ArithmeticOperation(asm::Arithmetic::PackedAssign(_))
+            )
+        };
+        assert_eq!(count(&packed, is_scalar), 0, "{:?}", packed);
+        assert_eq!(count(&packed, is_packed) * LANES, count(&scalar, is_scalar));
+        // the weights of each lane are gathered from separate repetitions, which still costs
+        // fewer instructions than evaluating the lanes one at a time
+        assert!(
+            packed.instructions.len() < scalar.instructions.len(),
+            "{:?}",
+            packed
+        );
+
+        for program in [&packed, &scalar] {
+            let function = compile(&exprs, variables, program);
+            for seed in 0..8 {
+                let values = spread_values(variables, seed);
+                assert_matches_tree(&function, &exprs, &values);
+            }
+        }
+    }
+
+    #[test]
+    fn scattered_lanes_are_gathered() {
+        let exprs: Vec<_> = (0..LANES)
+            .map(|lane| Expr::Variable(LANES - 1 - lane) * Expr::Number(lane as f32 + 0.5))
+            .collect();
+        let program = emit_exprs(&exprs, &Options::default());
+        assert_eq!(
+            count(&program, |instruction| matches!(
+                instruction,
+                asm::Instruction::Move(asm::Move::InterleaveLow { .. })
+            )),
+            4
+        );
+
+        let function = compile(&exprs, LANES, &program);
+        assert_matches_tree(&function, &exprs, &[1.0, -2.0, 3.5, f32::INFINITY]);
+    }
+
+    #[test]
+    fn wide_groups_match_tree() {
+        // 13 repetitions make a group of 8 trees, a group of 4 and a single tree
+        let (mut exprs, variables) = layer_outputs(3, 13);
+        // stored one lane at a time, and gathered from floats in the wrong order
+        exprs.swap(0, 9);
+        exprs.extend(
+            (0..WIDE_LANES).map(|lane| {
+                Expr::Variable(WIDE_LANES - 1 - lane) * Expr::Number(lane as f32 + 0.5)
+            }),
+        );
+
+        let is_scalar = |instruction: &asm::Instruction| {
+            matches!(
+                instruction,
+                asm::Instruction::ArithmeticOperation(asm::Arithmetic::FloatAssign(_))
+            )
+        };
+        let is_packed = |instruction: &asm::Instruction| {
+            matches!(
+                instruction,
+                asm::Instruction::ArithmeticOperation(asm::Arithmetic::PackedAssign(_))
+            )
+        };
+        let is_wide = |instruction: &asm::Instruction| {
+            matches!(
+                instruction,
+                asm::Instruction::ArithmeticOperation(asm::Arithmetic::WideOperation(_))
+            )
+        };
+
+        for if_positive in [IfPositiveLowering::Mask, IfPositiveLowering::Blend] {
+            let options = Options {
+                target: Target::Avx,
+                lanes: WIDE_LANES,
+                if_positive,
+            };
+            let wide = emit_exprs(&exprs, &options);
+            let scalar = emit_exprs(
+                &exprs,
+                &Options {
+                    lanes: 1,
+                    ..options
+                },
+            );
+            assert!(count(&wide, is_wide) > 0 && count(&wide, is_packed) > 0);
+            assert_eq!(
+                count(&wide, is_wide) * WIDE_LANES
+                    + count(&wide, is_packed) * LANES
+                    + count(&wide, is_scalar),
+                count(&scalar, is_scalar)
+            );
+
+            // SSE instructions never run while the high halves of the ymm registers are in use
+            let mut upper_in_use = false;
+            for instruction in &wide.instructions {
+                match instruction {
+                    asm::Instruction::ZeroUpper => upper_in_use = false,
+                    asm::Instruction::Move(asm::Move::LaneToMemory { .. }) => {}
+                    instruction if Target::required(instruction) == Target::Avx => {
+                        upper_in_use = true
+                    }
+                    instruction => {
+                        assert!(!upper_in_use, "{:?} follows ymm registers", instruction)
+                    }
+                }
+            }
+
+            if Target::detect().has_avx() {
+                let function = compile(&exprs, variables, &wide);
+                for seed in 0..8 {
+                    let values = spread_values(variables, seed);
+                    assert_matches_tree(&function, &exprs, &values);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_networks_match_tree() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..32 {
+            let (mut exprs, variables) = symbolic_outputs(&random_network(&mut rng));
+            // shuffled and repeated outputs are stored one lane at a time, and inputs as outputs
+            // are loaded without any arithmetic
+            exprs.reverse();
+            exprs.push(exprs[0].clone());
+            exprs.push(Expr::Variable(0));
+            let values: Vec<f32> = (0..variables).map(|_| random_value(&mut rng)).collect();
+
+            for lanes in [1, LANES] {
+                for if_positive in IfPositiveLowering::supported(Target::detect()) {
+                    let options = Options {
+                        target: Target::detect(),
+                        lanes,
+                        if_positive,
+                    };
+                    // three registers forces deep trees to be split
+                    let all = emit::allocatable();
+                    for registers in [&all[..], &all[..3]] {
+                        let program = emit_with_registers(&exprs, &options, registers);
+                        let function = compile(&exprs, variables, &program);
+                        assert_matches_tree(&function, &exprs, &values);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deep_trees_are_split() {
+        // a balanced tree of depth 5 needs 6 registers
+        let mut level: Vec<_> = (0..32).map(Expr::Variable).collect();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| pair[0].clone() - pair[1].clone())
+                .collect();
+        }
+
+        let mut dag = Dag::new();
+        let root = dag.add(&level[0]);
+        assert_eq!(Plan::new(&dag, &[root], 1, 6).groups.len(), 1);
+        let plan = Plan::new(&dag, &[root], 1, 3);
+        assert!(plan.groups.len() > 1);
+        assert!(plan.need.iter().all(|need| *need <= 3));
+
+        let program = emit_with_registers(&level, &Options::default(), &emit::allocatable()[..3]);
+        let function = compile(&level, 32, &program);
+        let values: Vec<_> = (0..32).map(|i| (i * i) as f32 / 7.0).collect();
+        assert_matches_tree(&function, &level, &values);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+
+use crate::ir::register::{Program, Register, Value};
+use crate::ir::ssa;
+
+/// A way in which a program is malformed, found by `verify` or `verify_ssa`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The statement at `statement` reads `register` before anything writes it
+    Undefined {
+        statement: usize,
+        register: Register,
+    },
+    /// The `Operation` or `MultiplyAdd` at `statement` modifies `register` in place before anything
+    /// writes it
+    Uninitialised {
+        statement: usize,
+        register: Register,
+    },
+    /// The statement at `statement` overwrites the input `register`
+    InputClobbered {
+        statement: usize,
+        register: Register,
+    },
+    /// The statement at `statement` writes `register` again, which SSA programs may not do
+    Redefined {
+        statement: usize,
+        register: Register,
+    },
+    /// The `output`th output reads `register`, which nothing writes
+    UndefinedOutput { output: usize, register: Register },
+}
+
+fn registers(values: impl IntoIterator<Item = Value>) -> impl Iterator<Item = Register> {
+    values.into_iter().filter_map(|value| match value {
+        Value::Register(register) => Some(register),
+        Value::Number(_) => None,
+    })
+}
+
+fn check(program: &Program, preserve_inputs: bool) -> Result<(), Error> {
+    let mut defined: HashSet<Register> = program.input.iter().copied().collect();
+
+    for (index, statement) in program.statements.iter().enumerate() {
+        for register in registers(statement.expr.operands()) {
+            if !defined.contains(&register) {
+                return Err(Error::Undefined {
+                    statement: index,
+                    register,
+                });
+            }
+        }
+
+        let destination = statement.destination;
+        if statement.expr.reads_destination() && !defined.contains(&destination) {
+            return Err(Error::Uninitialised {
+                statement: index,
+                register: destination,
+            });
+        }
+        if preserve_inputs && program.input.contains(&destination) {
+            return Err(Error::InputClobbered {
+                statement: index,
+                register: destination,
+            });
+        }
+        defined.insert(destination);
+    }
+
+    check_outputs(&program.output, &defined)
+}
+
+fn check_outputs(output: &[Value], defined: &HashSet<Register>) -> Result<(), Error> {
+    for (index, value) in output.iter().enumerate() {
+        if let Value::Register(register) = value {
+            if !defined.contains(register) {
+                return Err(Error::UndefinedOutput {
+                    output: index,
+                    register: *register,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every register is written before it is read, that every `Operation` and
+/// `MultiplyAdd` modifies a register that has been written, and that no input is ever overwritten,
+/// as holds for every program produced by `flatten` and `peephole`.
+pub fn verify(program: &Program) -> Result<(), Error> {
+    check(program, true)
+}
+
+/// Like `verify`, but allows inputs to be overwritten, since `register_alloc::realloc` hands an
+/// input's register to another value once the input is no longer read.
+pub fn verify_allocated(program: &Program) -> Result<(), Error> {
+    check(program, false)
+}
+
+/// Checks that every register of an SSA program is written exactly once, by a statement before
+/// any that read it, and that no input is written at all.
+pub fn verify_ssa(program: &ssa::Program) -> Result<(), Error> {
+    let mut defined: HashSet<Register> = program.input.iter().copied().collect();
+
+    for (index, statement) in program.statements.iter().enumerate() {
+        for register in registers(statement.expr.operands()) {
+            if !defined.contains(&register) {
+                return Err(Error::Undefined {
+                    statement: index,
+                    register,
+                });
+            }
+        }
+
+        let destination = statement.destination;
+        if program.input.contains(&destination) {
+            return Err(Error::InputClobbered {
+                statement: index,
+                register: destination,
+            });
+        }
+        if !defined.insert(destination) {
+            return Err(Error::Redefined {
+                statement: index,
+                register: destination,
+            });
+        }
+    }
+
+    check_outputs(&program.output, &defined)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::compile::{flatten, peephole, register_alloc};
+    use crate::eval;
+    use crate::harness::generate::{random_network, random_value};
+    use crate::ir::expr::{self, Operator};
+    use crate::ir::register::{Expr, Statement};
+    use crate::neurons::neuron::Neuron;
+
+    fn r(index: usize) -> Register {
+        Register { index }
+    }
+
+    fn program(statements: Vec<(usize, Expr)>, output: usize) -> Program {
+        Program {
+            input: vec![r(0), r(1)],
+            statements: statements
+                .into_iter()
+                .map(|(destination, expr)| Statement {
+                    destination: r(destination),
+                    expr,
+                })
+                .collect(),
+            output: vec![Value::Register(r(output))],
+        }
+    }
+
+    fn operation(operand: usize) -> Expr {
+        Expr::Operation {
+            operator: Operator::Add,
+            operand: Value::Register(r(operand)),
+        }
+    }
+
+    fn mov(source: usize) -> Expr {
+        Expr::Move(Value::Register(r(source)))
+    }
+
+    #[test]
+    fn malformed_programs() {
+        assert_eq!(
+            verify(&program(vec![(2, mov(0)), (2, operation(3))], 2)),
+            Err(Error::Undefined {
+                statement: 1,
+                register: r(3)
+            })
+        );
+        assert_eq!(
+            verify(&program(vec![(2, operation(1))], 2)),
+            Err(Error::Uninitialised {
+                statement: 0,
+                register: r(2)
+            })
+        );
+        assert_eq!(
+            verify(&program(vec![(0, operation(1))], 0)),
+            Err(Error::InputClobbered {
+                statement: 0,
+                register: r(0)
+            })
+        );
+        assert_eq!(
+            verify(&program(vec![(2, mov(0))], 3)),
+            Err(Error::UndefinedOutput {
+                output: 0,
+                register: r(3)
+            })
+        );
+
+        assert_eq!(
+            verify(&program(vec![(2, mov(0)), (2, operation(1))], 2)),
+            Ok(())
+        );
+        assert_eq!(
+            verify_allocated(&program(vec![(0, operation(1))], 0)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn malformed_ssa() {
+        let add = |first: usize, second: usize| ssa::Expr::Operation {
+            operator: Operator::Add,
+            operands: [Value::Register(r(first)), Value::Register(r(second))],
+        };
+        let program = |statements: Vec<(usize, ssa::Expr)>| ssa::Program {
+            input: vec![r(0), r(1)],
+            statements: statements
+                .into_iter()
+                .map(|(destination, expr)| ssa::Statement {
+                    destination: r(destination),
+                    expr,
+                })
+                .collect(),
+            output: vec![Value::Register(r(2))],
+        };
+
+        assert_eq!(verify_ssa(&program(vec![(2, add(0, 1))])), Ok(()));
+        assert_eq!(
+            verify_ssa(&program(vec![(2, add(0, 1)), (2, add(2, 1))])),
+            Err(Error::Redefined {
+                statement: 1,
+                register: r(2)
+            })
+        );
+        assert_eq!(
+            verify_ssa(&program(vec![(1, add(0, 0)), (2, add(0, 1))])),
+            Err(Error::InputClobbered {
+                statement: 0,
+                register: r(1)
+            })
+        );
+        assert_eq!(
+            verify_ssa(&program(vec![(2, add(0, 3))])),
+            Err(Error::Undefined {
+                statement: 0,
+                register: r(3)
+            })
+        );
+    }
+
+    #[test]
+    fn ssa_round_trip() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..32 {
+            let network = random_network(&mut rng);
+            let size = network.size();
+            let data: Vec<_> = (0..size.data).map(expr::Expr::Variable).collect();
+            let input: Vec<_> = (0..size.input)
+                .map(|i| expr::Expr::Variable(i + size.data))
+                .collect();
+            let outputs = network.evaluate(&input, &data);
+            let registers: Vec<_> = (0..size.data + size.input).map(r).collect();
+            let values: Vec<f32> = registers.iter().map(|_| random_value(&mut rng)).collect();
+            let env = |input: &[Register]| -> HashMap<_, _> {
+                input.iter().copied().zip(values.iter().copied()).collect()
+            };
+
+            let options = flatten::Options {
+                fuse_multiply_add: rng.gen(),
+            };
+            let mut program = flatten::to_program_with(&outputs, registers.clone(), &options);
+            assert_eq!(verify(&program), Ok(()), "{:?}", program);
+            if rng.gen() {
+                peephole::optimise(&mut program);
+                assert_eq!(verify(&program), Ok(()), "{:?}", program);
+            }
+            let expected = eval::register::evaluate(&program, env(&program.input));
+
+            let ssa = ssa::Program::from_register(&program);
+            assert_eq!(verify_ssa(&ssa), Ok(()), "{:?}", ssa);
+            let lowered = ssa.to_register();
+            assert_eq!(verify(&lowered), Ok(()), "{:?}", lowered);
+
+            register_alloc::realloc(&mut program);
+            assert_eq!(verify_allocated(&program), Ok(()), "{:?}", program);
+
+            for result in [
+                eval::register::evaluate(&lowered, env(&lowered.input)),
+                eval::register::evaluate(&program, env(&program.input)),
+            ] {
+                // lowering may swap the operands of associative operators, changing NaN payloads
+                assert!(
+                    result.iter().zip(&expected).all(|(result, expected)| {
+                        result.to_bits() == expected.to_bits()
+                            || (result.is_nan() && expected.is_nan())
+                    }),
+                    "{:?} != {:?}",
+                    result,
+                    expected
+                );
+            }
+        }
+    }
+}
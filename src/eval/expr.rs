@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
-use crate::ir::expr::{Expr, Operator};
+use crate::ir::dag::{Dag, Node, NodeId};
+use crate::ir::expr::Expr;
 
 pub type Env = HashMap<usize, f32>;
 
@@ -9,18 +10,7 @@ pub fn evaluate_rec(expr: &Expr, env: &Env) -> f32 {
         Expr::Operation { operator, operands } => {
             let first = evaluate_rec(&operands[0], env);
             let second = evaluate_rec(&operands[1], env);
-
-            println!(
-                "{:?} = {}, {:?} = {}",
-                operands[0], first, operands[1], second
-            );
-
-            match operator {
-                Operator::Add => first + second,
-                Operator::Subtract => first - second,
-                Operator::Multiply => first * second,
-                Operator::Divide => first / second,
-            }
+            operator.apply(first, second)
         }
         Expr::Variable(variable) => env[variable],
         Expr::Number(number) => *number,
@@ -35,6 +25,50 @@ pub fn evaluate_rec(expr: &Expr, env: &Env) -> f32 {
 }
 
 pub fn evaluate(expr: &Expr, env: &Env) -> f32 {
-    println!("evaluating expr with env {:?}", env);
     evaluate_rec(expr, env)
 }
+
+/// Like `evaluate` on each of `exprs`, but rounds a sum with a product as an operand once, the
+/// way `flatten` does when fusing multiply adds. A product is only fused into a sum if nothing
+/// else reads it, counting structurally equal subexpressions of all of `exprs` as one, and the
+/// second operand is taken as the product if both can be.
+pub fn evaluate_fused(exprs: &[Expr], env: &Env) -> Vec<f32> {
+    let mut dag = Dag::new();
+    let roots: Vec<_> = exprs.iter().map(|expr| dag.add(expr)).collect();
+    let uses = dag.uses(&roots);
+
+    // every child comes before its parent, so its value is known
+    let mut values: Vec<f32> = Vec::with_capacity(dag.len());
+    for index in 0..dag.len() {
+        let id = NodeId { index };
+        let value = |id: NodeId| values[id.index];
+        let fused = dag.multiply_add(id, |product| uses[product.index] == 1);
+        let result = match (fused, dag.node(id)) {
+            (Some((addend, [first, second])), _) => {
+                value(first).mul_add(value(second), value(addend))
+            }
+            (None, Node::Operation { operator, operands }) => {
+                operator.apply(value(operands[0]), value(operands[1]))
+            }
+            (None, Node::Variable(variable)) => env[&variable],
+            (None, Node::Number(bits)) => f32::from_bits(bits),
+            (
+                None,
+                Node::IfPositive {
+                    predicate,
+                    consequent,
+                    alternative,
+                },
+            ) => {
+                if value(predicate) >= 0.0 {
+                    value(consequent)
+                } else {
+                    value(alternative)
+                }
+            }
+        };
+        values.push(result);
+    }
+
+    roots.iter().map(|root| values[root.index]).collect()
+}
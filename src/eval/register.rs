@@ -1,14 +1,10 @@
 use std::collections::{hash_map::Entry, HashMap};
 
-use crate::ir::{
-    expr::Operator,
-    register::{Expr, Program, Register, Value},
-};
+use crate::ir::register::{Expr, Program, Register, Value};
 
 pub type Env = HashMap<Register, f32>;
 
-pub fn evaluate(program: &Program, mut env: Env) -> f32 {
-    //println!("evaluating reg with env {:?}", env);
+pub fn evaluate(program: &Program, mut env: Env) -> Vec<f32> {
     for statement in &program.statements {
         match statement.expr {
             Expr::Move(value) => {
@@ -18,12 +14,14 @@ pub fn evaluate(program: &Program, mut env: Env) -> f32 {
                 let first = evaluate_value(&operand, &env);
                 let entry = env.entry(statement.destination);
                 assert!(matches!(entry, Entry::Occupied(..)));
-                entry.and_modify(|dest| match operator {
-                    Operator::Add => *dest += first,
-                    Operator::Subtract => *dest -= first,
-                    Operator::Multiply => *dest *= first,
-                    Operator::Divide => *dest /= first,
-                });
+                entry.and_modify(|dest| *dest = operator.apply(*dest, first));
+            }
+            Expr::MultiplyAdd { first, second } => {
+                let first = evaluate_value(&first, &env);
+                let second = evaluate_value(&second, &env);
+                let entry = env.entry(statement.destination);
+                assert!(matches!(entry, Entry::Occupied(..)));
+                entry.and_modify(|dest| *dest = first.mul_add(second, *dest));
             }
             Expr::IfPositive {
                 predicate,
@@ -40,7 +38,11 @@ pub fn evaluate(program: &Program, mut env: Env) -> f32 {
         }
     }
 
-    evaluate_value(&program.output, &env)
+    program
+        .output
+        .iter()
+        .map(|value| evaluate_value(value, &env))
+        .collect()
 }
 
 fn evaluate_value(value: &Value, env: &Env) -> f32 {
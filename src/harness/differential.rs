@@ -0,0 +1,477 @@
+use std::fmt;
+use std::rc::Rc;
+
+use rand::Rng;
+
+use crate::compile::assemble::Assemblable;
+use crate::compile::colouring::GraphColouring;
+use crate::compile::emit::{self, IfPositiveLowering, Options};
+use crate::compile::register_alloc::{Allocator, LinearScan};
+use crate::compile::{flatten, peephole, register_alloc, vectorise, verify};
+use crate::eval;
+use crate::ir::bytes::Bytes;
+use crate::ir::dag;
+use crate::ir::expr::{Expr, IfPositive};
+use crate::ir::register::{self, Register};
+use crate::ir::ssa;
+use crate::ir::target::Target;
+use crate::jit::executable::Function;
+
+use super::generate::{random_case, Case};
+
+/// One of the ways an expression can be evaluated
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Path {
+    /// `eval::expr::evaluate`
+    Tree,
+    /// `eval::expr::evaluate` after `dag::eliminate_common_subexpressions`
+    Shared,
+    /// `eval::register::evaluate` on the flattened program
+    Register,
+    /// `eval::register::evaluate` after `peephole::optimise`
+    Optimised,
+    /// `eval::register::evaluate` after `peephole::optimise` and `register_alloc::realloc`
+    Reallocated,
+    /// `eval::register::evaluate` on the reallocated program after converting it to SSA form,
+    /// checking it with `verify::verify_ssa`, and converting it back
+    Ssa,
+    /// The compiled program, given `registers` xmm registers to allocate by linear scan, or by
+    /// graph colouring if `colouring` is set
+    Native {
+        registers: usize,
+        colouring: bool,
+        options: Options,
+    },
+    /// `vectorise::emit_exprs`, which evaluates the expression a tree at a time rather than
+    /// allocating registers
+    Vectorised { if_positive: IfPositiveLowering },
+    /// `eval::expr::evaluate_fused`
+    FusedTree,
+    /// `eval::register::evaluate` on the program flattened with fused multiply adds
+    FusedRegister,
+    /// `eval::register::evaluate` on the fused program after `peephole::optimise` and
+    /// `register_alloc::realloc`
+    FusedReallocated,
+    /// The compiled fused program, allocated like `Native`
+    FusedNative {
+        registers: usize,
+        colouring: bool,
+        options: Options,
+    },
+}
+
+impl Path {
+    /// Whether the path fuses multiply adds, which rounds differently to the paths that don't
+    pub fn fused(&self) -> bool {
+        matches!(
+            self,
+            Path::FusedTree
+                | Path::FusedRegister
+                | Path::FusedReallocated
+                | Path::FusedNative { .. }
+        )
+    }
+}
+
+/// A case on which the evaluation paths don't all produce the same bits
+pub struct Mismatch {
+    pub case: Case,
+    pub results: Vec<(Path, Vec<f32>)>,
+}
+
+/// Evaluates every output of `case` along every path the current cpu supports
+pub fn evaluate(case: &Case) -> Vec<(Path, Vec<f32>)> {
+    let tree_env = case.input.iter().copied().enumerate().collect();
+    let registers: Vec<_> = (0..case.input.len())
+        .map(|index| Register { index })
+        .collect();
+    let register_env = |registers: &[Register]| {
+        registers
+            .iter()
+            .copied()
+            .zip(case.input.iter().copied())
+            .collect()
+    };
+
+    let tree = case
+        .exprs
+        .iter()
+        .map(|expr| eval::expr::evaluate(expr, &tree_env))
+        .collect();
+    let mut results = vec![(Path::Tree, tree)];
+    let shared = case
+        .exprs
+        .iter()
+        .map(|expr| {
+            let shared = dag::eliminate_common_subexpressions(expr);
+            eval::expr::evaluate(&shared, &tree_env)
+        })
+        .collect();
+    results.push((Path::Shared, shared));
+
+    let mut program = flatten::to_program(&case.exprs, registers.clone());
+    results.push((
+        Path::Register,
+        eval::register::evaluate(&program, register_env(&registers)),
+    ));
+
+    peephole::optimise(&mut program);
+    results.push((
+        Path::Optimised,
+        eval::register::evaluate(&program, register_env(&registers)),
+    ));
+
+    register_alloc::realloc(&mut program);
+    results.push((
+        Path::Reallocated,
+        eval::register::evaluate(&program, register_env(&program.input)),
+    ));
+
+    let ssa = ssa::Program::from_register(&program);
+    verify::verify_ssa(&ssa).expect("conversion to SSA form produced an invalid program");
+    let round_trip = ssa.to_register();
+    results.push((
+        Path::Ssa,
+        eval::register::evaluate(&round_trip, register_env(&round_trip.input)),
+    ));
+
+    let targets = Target::supported();
+    results.extend(native(
+        &program,
+        &case.input,
+        &targets,
+        |registers, colouring, options| Path::Native {
+            registers,
+            colouring,
+            options,
+        },
+    ));
+
+    let target = Target::detect();
+    for if_positive in IfPositiveLowering::supported(target) {
+        let options = vectorise::Options {
+            target,
+            if_positive,
+            ..vectorise::Options::default()
+        };
+        let mut bytes = Bytes::new();
+        vectorise::emit_exprs(&case.exprs, &options).assemble(&mut bytes);
+        let function =
+            unsafe { Function::new(&bytes, case.input.len(), case.exprs.len()) }.unwrap();
+        let path = Path::Vectorised { if_positive };
+        results.push((path, function.call(&case.input)));
+    }
+
+    results.push((
+        Path::FusedTree,
+        eval::expr::evaluate_fused(&case.exprs, &tree_env),
+    ));
+
+    let options = flatten::Options {
+        fuse_multiply_add: true,
+    };
+    let mut program = flatten::to_program_with(&case.exprs, registers.clone(), &options);
+    results.push((
+        Path::FusedRegister,
+        eval::register::evaluate(&program, register_env(&registers)),
+    ));
+
+    peephole::optimise(&mut program);
+    register_alloc::realloc(&mut program);
+    results.push((
+        Path::FusedReallocated,
+        eval::register::evaluate(&program, register_env(&program.input)),
+    ));
+
+    let fused: Vec<_> = targets
+        .into_iter()
+        .filter(|target| target.has_fma())
+        .collect();
+    results.extend(native(
+        &program,
+        &case.input,
+        &fused,
+        |registers, colouring, options| Path::FusedNative {
+            registers,
+            colouring,
+            options,
+        },
+    ));
+
+    results
+}
+
+/// Compiles and runs `program` with every allocator and set of options for each of `targets`, on
+/// a few register budgets
+fn native(
+    program: &register::Program,
+    input: &[f32],
+    targets: &[Target],
+    path: impl Fn(usize, bool, Options) -> Path,
+) -> Vec<(Path, Vec<f32>)> {
+    let mut results = Vec::new();
+    let all = emit::allocatable();
+    // no registers puts everything on the stack, and a few registers forces spilling
+    let allocators: [(bool, &dyn Allocator); 2] =
+        [(false, &LinearScan), (true, &GraphColouring::default())];
+    for available in [&all[..], &all[..0], &all[..3]] {
+        for (colouring, allocator) in allocators {
+            let assignment = allocator.assign(program, available);
+            let supported = targets
+                .iter()
+                .flat_map(|target| Options::supported(*target));
+            for options in supported {
+                let mut bytes = Bytes::new();
+                emit::emit_program(program, &assignment, &options).assemble(&mut bytes);
+                let function =
+                    unsafe { Function::new(&bytes, program.input.len(), program.output.len()) }
+                        .unwrap();
+
+                let path = path(available.len(), colouring, options);
+                results.push((path, function.call(input)));
+            }
+        }
+    }
+    results
+}
+
+/// Whether every output of every result has the same bits as the others that fuse multiply adds,
+/// or the others that don't
+fn agree(results: &[(Path, Vec<f32>)]) -> bool {
+    [false, true].into_iter().all(|fused| {
+        let mut group = results.iter().filter(|(path, _)| path.fused() == fused);
+        let Some((_, first)) = group.next() else {
+            return true;
+        };
+        group.all(|(_, values)| {
+            values
+                .iter()
+                .zip(first)
+                .all(|(value, first)| value.to_bits() == first.to_bits())
+        })
+    })
+}
+
+/// Evaluates `case` along every path, returning the agreed upon outputs
+pub fn check(case: &Case) -> Result<Vec<f32>, Mismatch> {
+    let mut results = evaluate(case);
+    if agree(&results) {
+        Ok(results.swap_remove(0).1)
+    } else {
+        Err(Mismatch {
+            case: case.clone(),
+            results,
+        })
+    }
+}
+
+/// Checks `cases` random networks, returning the first mismatch after minimising it
+pub fn run(rng: &mut impl Rng, cases: usize) -> Result<(), Mismatch> {
+    for _ in 0..cases {
+        let case = random_case(rng);
+        if check(&case).is_err() {
+            let minimal = minimise(case, |case| check(case).is_err());
+            return Err(check(&minimal).unwrap_err());
+        }
+    }
+    Ok(())
+}
+
+fn size(expr: &Expr) -> usize {
+    match expr {
+        Expr::Operation { operands, .. } => 1 + size(&operands[0]) + size(&operands[1]),
+        Expr::IfPositive(if_positive) => {
+            1 + size(&if_positive.predicate)
+                + size(&if_positive.consequent)
+                + size(&if_positive.alternative)
+        }
+        Expr::Variable(_) | Expr::Number(_) => 1,
+    }
+}
+
+/// The `index`th subexpression of `exprs`, counting in preorder through each expression in turn
+fn subexpression(exprs: &mut [Expr], index: usize) -> &mut Expr {
+    fn find<'a>(expr: &'a mut Expr, index: &mut usize) -> Option<&'a mut Expr> {
+        if *index == 0 {
+            return Some(expr);
+        }
+        *index -= 1;
+        match expr {
+            Expr::Operation { operands, .. } => {
+                let [first, second] = Rc::make_mut(operands);
+                find(first, index).or_else(|| find(second, index))
+            }
+            Expr::IfPositive(if_positive) => {
+                let IfPositive {
+                    predicate,
+                    consequent,
+                    alternative,
+                } = Rc::make_mut(if_positive);
+                find(predicate, index)
+                    .or_else(|| find(consequent, index))
+                    .or_else(|| find(alternative, index))
+            }
+            Expr::Variable(_) | Expr::Number(_) => None,
+        }
+    }
+
+    let mut index = index;
+    exprs
+        .iter_mut()
+        .find_map(|expr| find(expr, &mut index))
+        .expect("subexpression index out of range")
+}
+
+/// Smaller expressions that could stand in for `expr`: its children, then its value
+fn replacements(expr: &Expr, input: &[f32]) -> Vec<Expr> {
+    let env = input.iter().copied().enumerate().collect();
+    match expr {
+        Expr::Operation { operands, .. } => vec![
+            operands[0].clone(),
+            operands[1].clone(),
+            Expr::Number(eval::expr::evaluate(expr, &env)),
+        ],
+        Expr::IfPositive(if_positive) => vec![
+            if_positive.consequent.clone(),
+            if_positive.alternative.clone(),
+            if_positive.predicate.clone(),
+            Expr::Number(eval::expr::evaluate(expr, &env)),
+        ],
+        Expr::Variable(_) | Expr::Number(_) => Vec::new(),
+    }
+}
+
+/// Greedily drops outputs of `case` and replaces its subexpressions with smaller ones for as long
+/// as `fails` still holds
+pub fn minimise(mut case: Case, fails: impl Fn(&Case) -> bool) -> Case {
+    let mut output = 0;
+    while output < case.exprs.len() && case.exprs.len() > 1 {
+        let mut candidate = case.clone();
+        candidate.exprs.remove(output);
+        if fails(&candidate) {
+            case = candidate;
+        } else {
+            output += 1;
+        }
+    }
+
+    let mut index = 0;
+    while index < case.exprs.iter().map(size).sum() {
+        let mut shrunk = false;
+        for replacement in replacements(subexpression(&mut case.exprs, index), &case.input) {
+            let mut candidate = case.clone();
+            *subexpression(&mut candidate.exprs, index) = replacement;
+            if fails(&candidate) {
+                case = candidate;
+                shrunk = true;
+                break;
+            }
+        }
+        // a replacement may enable further replacements at the same position
+        if !shrunk {
+            index += 1;
+        }
+    }
+    case
+}
+
+impl fmt::Debug for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for expr in &self.case.exprs {
+            writeln!(f, "{:?}", expr)?;
+        }
+        writeln!(f, "with input {:?}", self.case.input)?;
+        for (path, values) in &self.results {
+            let values: Vec<_> = values
+                .iter()
+                .map(|value| format!("{} ({:#010x})", value, value.to_bits()))
+                .collect();
+            writeln!(f, "\t{:?} = {}", path, values.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::ir::expr::Operator;
+    use crate::math::number::Number;
+
+    #[test]
+    fn random_networks_agree() {
+        let mut rng = StdRng::seed_from_u64(0);
+        if let Err(mismatch) = run(&mut rng, 64) {
+            panic!("evaluation paths disagree on\n{:?}", mismatch);
+        }
+    }
+
+    #[test]
+    fn special_values_agree() {
+        let x = || Expr::Variable(0);
+        let y = || Expr::Variable(1);
+        let expr = (x() * y() - y() / x()).if_positive(x() + y(), x() / y());
+        for input in [
+            [0.0, -0.0],
+            [f32::INFINITY, 0.0],
+            [f32::NAN, 1.0],
+            [-0.0, f32::NEG_INFINITY],
+            [f32::MAX, f32::MAX],
+        ] {
+            let case = Case {
+                exprs: vec![expr.clone()],
+                input: input.to_vec(),
+            };
+            if let Err(mismatch) = check(&case) {
+                panic!("evaluation paths disagree on\n{:?}", mismatch);
+            }
+        }
+    }
+
+    #[test]
+    fn outputs_sharing_a_product_agree() {
+        let x = || Expr::Variable(0);
+        let product = || (x() + Expr::Number(1.0)) * x();
+        let case = Case {
+            exprs: vec![product() - Expr::Number(2.0), Expr::Number(3.0) + product()],
+            input: vec![3.0],
+        };
+        match check(&case) {
+            Ok(outputs) => assert_eq!(outputs, [10.0, 15.0]),
+            Err(mismatch) => panic!("evaluation paths disagree on\n{:?}", mismatch),
+        }
+    }
+
+    fn contains_division(expr: &Expr) -> bool {
+        match expr {
+            Expr::Operation { operator, operands } => {
+                *operator == Operator::Divide
+                    || contains_division(&operands[0])
+                    || contains_division(&operands[1])
+            }
+            Expr::IfPositive(if_positive) => {
+                contains_division(&if_positive.predicate)
+                    || contains_division(&if_positive.consequent)
+                    || contains_division(&if_positive.alternative)
+            }
+            Expr::Variable(_) | Expr::Number(_) => false,
+        }
+    }
+
+    #[test]
+    fn minimise_keeps_failure() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let case = random_case(&mut rng);
+        let any_division = |case: &Case| case.exprs.iter().any(contains_division);
+        // every network contains a rectified linear unit, which halves its negative inputs
+        assert!(any_division(&case));
+
+        let minimal = minimise(case, any_division);
+        // the smallest expression containing a division is `a / b`, with two leaves
+        assert_eq!(minimal.exprs.len(), 1, "{:?}", minimal.exprs);
+        assert_eq!(size(&minimal.exprs[0]), 3, "{:?}", minimal.exprs);
+    }
+}
@@ -0,0 +1,109 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::ir::expr::Expr;
+use crate::math::number::Number;
+use crate::math::vector::{Vector, VectorView};
+use crate::neurons::learning::layer;
+use crate::neurons::neuron::{Dimensions, Neuron};
+
+/// A composition of layers whose widths are only known at runtime, so that networks of random
+/// shapes can be built.
+pub struct Network {
+    /// `widths[0]` is the number of inputs, and every following width is the output of a layer
+    pub widths: Vec<usize>,
+}
+
+impl Neuron for Network {
+    fn evaluate<T: Number>(&self, input: VectorView<T>, data: VectorView<T>) -> Vector<T> {
+        let mut values = input.to_vec();
+        let mut data = data;
+
+        for pair in self.widths.windows(2) {
+            let layer = layer(pair[0], pair[1]);
+            let (local, rest) = data.split_at(layer.size().data);
+            values = layer.evaluate(&values, local);
+            data = rest;
+        }
+
+        values
+    }
+
+    fn size(&self) -> Dimensions {
+        Dimensions {
+            data: self
+                .widths
+                .windows(2)
+                .map(|pair| layer(pair[0], pair[1]).size().data)
+                .sum(),
+            input: self.widths[0],
+            output: *self.widths.last().unwrap(),
+        }
+    }
+}
+
+/// Expressions over the variables `0..input.len()`, which are compiled together as the outputs of
+/// one program, along with the value of each variable
+#[derive(Clone, Debug)]
+pub struct Case {
+    pub exprs: Vec<Expr>,
+    pub input: Vec<f32>,
+}
+
+/// Values that are likely to expose differences in rounding, signed zeros and comparisons
+const SPECIAL: [f32; 7] = [
+    0.0,
+    -0.0,
+    1.0,
+    f32::MIN_POSITIVE,
+    f32::MAX,
+    f32::INFINITY,
+    f32::NEG_INFINITY,
+];
+
+pub fn random_network(rng: &mut impl Rng) -> Network {
+    let layers = rng.gen_range(1..=3);
+    Network {
+        widths: (0..=layers).map(|_| rng.gen_range(1..=4)).collect(),
+    }
+}
+
+/// A value for a variable, usually small but occasionally special
+pub fn random_value(rng: &mut impl Rng) -> f32 {
+    if rng.gen_ratio(1, 16) {
+        *SPECIAL.choose(rng).unwrap()
+    } else {
+        rng.gen_range(-2.0..2.0)
+    }
+}
+
+/// The outputs of `neuron` as expressions with its data in the variables before its input, along
+/// with the number of variables
+pub fn symbolic_outputs(neuron: &impl Neuron) -> (Vec<Expr>, usize) {
+    let size = neuron.size();
+    let data: Vec<_> = (0..size.data).map(Expr::Variable).collect();
+    let input: Vec<_> = (0..size.input)
+        .map(|i| Expr::Variable(i + size.data))
+        .collect();
+    (neuron.evaluate(&input, &data), size.data + size.input)
+}
+
+/// Values spread over `[-1, 1)` without a random number generator, different for each `seed`, for
+/// tests that need the same inputs on every run
+#[cfg(test)]
+pub fn spread_values(count: usize, seed: usize) -> Vec<f32> {
+    (0..count)
+        .map(|i| ((i * 7 + seed * 13) % 23) as f32 / 11.5 - 1.0)
+        .collect()
+}
+
+/// Every output of a random network, with its data in the variables before its input
+pub fn random_case(rng: &mut impl Rng) -> Case {
+    let network = random_network(rng);
+    let (exprs, variables) = symbolic_outputs(&network);
+
+    Case {
+        exprs,
+        input: (0..variables).map(|_| random_value(rng)).collect(),
+    }
+}
@@ -0,0 +1,2 @@
+pub mod differential;
+pub mod generate;
@@ -3,10 +3,15 @@ use std::fmt;
 use crate::bounded::Bounded;
 
 use super::expr::Operator;
+use super::target::Target;
 
 pub type Xmm = Bounded<0, 15>;
 
-#[derive(Copy, Clone)]
+/// A 256 bit register, whose low half is the xmm register with the same number. Requires AVX.
+pub type Ymm = Xmm;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum R {
     /// Does not need to be saved. Holds return value of program.
     Rax,
@@ -27,18 +32,32 @@ pub enum R {
     /// Does not need to be saved.
     RLow(Bounded<8, 11>),
     /// Needs to be saved.
-    RHigh(Bounded<11, 15>),
+    RHigh(Bounded<12, 15>),
 }
 
-/// Most x64 registers under linux calling convention
-pub enum Register {
-    /// Does not need to be saved.
-    Xmm(Xmm),
-    /// Sometimes needs to be saved.
-    R(R),
+impl R {
+    /// The 4 bit number used to encode the register, where the high bit lives in a REX prefix.
+    pub fn number(self) -> u8 {
+        match self {
+            R::Rax => 0,
+            R::Rcx => 1,
+            R::Rdx => 2,
+            R::Rbx => 3,
+            R::Rsp => 4,
+            R::Rbp => 5,
+            R::Rsi => 6,
+            R::Rdi => 7,
+            R::RLow(reg) => i64::from(reg) as u8,
+            R::RHigh(reg) => i64::from(reg) as u8,
+        }
+    }
+
+    pub fn is_callee_saved(self) -> bool {
+        matches!(self, R::Rbx | R::Rbp | R::Rsp | R::RHigh(_))
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum ScaleFactor {
     S1,
     S2,
@@ -46,17 +65,28 @@ pub enum ScaleFactor {
     S8,
 }
 
+#[derive(PartialEq, Eq)]
 pub struct Index {
     pub index: R,
     pub scale: ScaleFactor,
 }
 
+#[derive(PartialEq, Eq)]
+pub enum Base {
+    R(R),
+    /// The `n`th entry of the program's constant pool, addressed relative to %rip.
+    /// Can't be combined with an index.
+    Constant(usize),
+}
+
+#[derive(PartialEq, Eq)]
 pub struct Memory {
     pub displacement: i32,
-    pub base: R,
+    pub base: Base,
     pub index: Option<Index>,
 }
 
+#[derive(PartialEq, Eq)]
 pub enum Move {
     /// movss
     FloatFromMemory {
@@ -85,50 +115,283 @@ pub enum Move {
         dest: Memory,
         src: R,
     },
+    /// blendvps
+    /// Copies `src` into `dest` wherever the mask in %xmm0 has its sign bit set. Requires SSE4.1.
+    BlendWithMask {
+        dest: Xmm,
+        src: Xmm,
+    },
+    /// movups
+    PackedFromMemory {
+        dest: Xmm,
+        src: Memory,
+    },
+    /// movups
+    PackedToMemory {
+        dest: Memory,
+        src: Xmm,
+    },
+    /// movaps
+    PackedToPacked {
+        dest: Xmm,
+        src: Xmm,
+    },
+    /// shufps
+    /// Fills the low two lanes of `dest` with lanes of `dest` and the high two with lanes of
+    /// `src`, each chosen by 2 bits of `selector`, starting from the lowest.
+    Shuffle {
+        dest: Xmm,
+        src: Xmm,
+        selector: u8,
+    },
+    /// unpcklps
+    /// Interleaves the low two lanes of `dest` and `src`, `dest = [dest0, src0, dest1, src1]`
+    InterleaveLow {
+        dest: Xmm,
+        src: Xmm,
+    },
+    /// movlhps
+    /// Copies the low two lanes of `src` into the high two lanes of `dest`
+    LowToHigh {
+        dest: Xmm,
+        src: Xmm,
+    },
+    /// vmovups
+    WideFromMemory {
+        dest: Ymm,
+        src: Memory,
+    },
+    /// vmovups
+    WideToMemory {
+        dest: Memory,
+        src: Ymm,
+    },
+    /// vmovaps
+    WideToWide {
+        dest: Ymm,
+        src: Ymm,
+    },
+    /// vbroadcastss
+    /// Copies the float at `src` into every lane of `dest`
+    BroadcastFromMemory {
+        dest: Ymm,
+        src: Memory,
+    },
+    /// vinsertps
+    /// Copies `first` into `dest`, replacing lane `lane` with the float at `src`. Zeroes the high
+    /// half of the ymm register.
+    InsertFromMemory {
+        dest: Xmm,
+        first: Xmm,
+        src: Memory,
+        lane: u8,
+    },
+    /// vinsertf128
+    /// Copies the low half of `first` into `dest`, and `src` into the high half
+    XmmToHigh {
+        dest: Ymm,
+        first: Ymm,
+        src: Xmm,
+    },
+    /// vblendvps
+    /// Copies `first` into `dest`, except wherever `mask` has its sign bit set, where the lanes
+    /// are taken from `src`
+    WideBlendWithMask {
+        dest: Ymm,
+        first: Ymm,
+        src: Ymm,
+        mask: Ymm,
+    },
+    /// vextractf128
+    /// Copies the high four lanes of `src` into `dest`
+    HighToXmm {
+        dest: Xmm,
+        src: Ymm,
+    },
+    /// vextractps
+    /// Stores the float in lane `lane` of `src`, which is less than 4
+    LaneToMemory {
+        dest: Memory,
+        src: Xmm,
+        lane: u8,
+    },
+}
+
+/// The source operand of an SSE instruction, which may be read straight from memory
+#[derive(PartialEq, Eq)]
+pub enum Operand {
+    Xmm(Xmm),
+    Memory(Memory),
 }
 
 // addss, subss, mulss, divss
+#[derive(PartialEq, Eq)]
 pub struct FloatAssign {
     pub operator: Operator,
     pub dest: Xmm,
+    pub value: Operand,
+}
+
+/// vaddss, vsubss, vmulss, vdivss
+/// `dest = first operator value`, leaving `first` unchanged. Requires AVX.
+#[derive(PartialEq, Eq)]
+pub struct FloatOperation {
+    pub operator: Operator,
+    pub dest: Xmm,
+    pub first: Xmm,
+    pub value: Operand,
+}
+
+/// vfmadd231ss
+/// `dest = dest + first * value`, rounded once. Requires FMA.
+#[derive(PartialEq, Eq)]
+pub struct FusedMultiplyAdd {
+    pub dest: Xmm,
+    pub first: Xmm,
+    pub value: Operand,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Logical {
+    /// andps
+    And,
+    /// andnps, `dest = !dest & value`
+    AndNot,
+    /// orps
+    Or,
+}
+
+// andps, andnps, orps
+#[derive(PartialEq, Eq)]
+pub struct LogicalAssign {
+    pub operator: Logical,
+    pub dest: Xmm,
     pub value: Xmm,
 }
 
+/// vandps, vandnps, vorps on ymm registers
+/// `dest = first operator value`, where `AndNot` negates `first`. Requires AVX.
+#[derive(PartialEq, Eq)]
+pub struct LogicalOperation {
+    pub operator: Logical,
+    pub dest: Ymm,
+    pub first: Ymm,
+    pub value: Ymm,
+}
+
+#[derive(PartialEq, Eq)]
 pub struct IntegerAssign {
     pub dest: R,
     pub value: u32,
 }
 
+#[derive(PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Arithmetic {
     FloatAssign(FloatAssign),
+    /// addps, subps, mulps, divps
+    /// A memory `value` must be 16 byte aligned.
+    PackedAssign(FloatAssign),
+    FloatOperation(FloatOperation),
+    FusedMultiplyAdd(FusedMultiplyAdd),
+    /// vaddps, vsubps, vmulps, vdivps
+    /// Like `FloatOperation`, but on every lane of ymm registers. A memory `value` holds 8 floats.
+    WideOperation(FloatOperation),
+    LogicalAssign(LogicalAssign),
+    WideLogical(LogicalOperation),
     IntegerAddAssign(IntegerAssign),
     IntegerSubAssign(IntegerAssign),
 }
 
+/// The predicates of `cmpss`, in the order of their immediate encoding
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Equal,
+    LessThan,
+    LessEqual,
+    Unordered,
+    NotEqual,
+    NotLessThan,
+    NotLessEqual,
+    Ordered,
+}
+
+#[derive(PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Compare {
     /// comiss
     CompareFloats { first: Xmm, second: Xmm },
+    /// cmpss
+    /// Sets `dest` to all ones if `dest` `condition` `value`, and to all zeros otherwise
+    CompareToMask {
+        condition: Condition,
+        dest: Xmm,
+        value: Xmm,
+    },
+    /// cmpps
+    /// Like `CompareToMask`, but on every lane
+    PackedCompareToMask {
+        condition: Condition,
+        dest: Xmm,
+        value: Xmm,
+    },
+    /// vcmpps
+    /// Sets each lane of `dest` to all ones if `first` `condition` `value` in that lane, and to
+    /// all zeros otherwise. Requires AVX.
+    WideCompareToMask {
+        condition: Condition,
+        dest: Ymm,
+        first: Ymm,
+        value: Ymm,
+    },
 }
 
+/// A position in a program, marked by an `Instruction::Label`
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Label {
+    pub index: usize,
+}
+
+#[derive(PartialEq, Eq)]
 pub enum Jump {
     /// jmp
-    /// Unconditionally jump `offset` *instructions*, not bytes
-    Unconditional { offset: i32 },
+    Unconditional { target: Label },
     /// jae
-    /// Conditionally jump `offset` *instructions*, not bytes
-    AboveEqual { offset: i32 },
+    AboveEqual { target: Label },
+}
+
+#[derive(PartialEq, Eq)]
+pub enum Stack {
+    /// push
+    Push(R),
+    /// pop
+    Pop(R),
 }
 
+#[derive(PartialEq, Eq)]
 pub enum Instruction {
     Move(Move),
     ArithmeticOperation(Arithmetic),
     Compare(Compare),
     Jump(Jump),
+    Stack(Stack),
+    /// ret
+    Return,
+    /// vzeroupper
+    /// Zeroes the high half of every ymm register, so that following SSE instructions don't have
+    /// to preserve it. Requires AVX.
+    ZeroUpper,
+    /// Marks the position of a label, does not produce any code
+    Label(Label),
 }
 
 #[derive(Debug)]
 pub struct Program {
     pub instructions: Vec<Instruction>,
+    /// Read-only data placed after the instructions, addressed with `Base::Constant`
+    pub constants: Vec<f32>,
+    /// The least capable cpu the program may run on, which has every instruction in it
+    pub target: Target,
 }
 
 impl fmt::Debug for Instruction {
@@ -138,6 +401,10 @@ impl fmt::Debug for Instruction {
             Instruction::ArithmeticOperation(arithmetic) => arithmetic.fmt(f),
             Instruction::Compare(compare) => compare.fmt(f),
             Instruction::Jump(jump) => jump.fmt(f),
+            Instruction::Stack(stack) => stack.fmt(f),
+            Instruction::Return => write!(f, "return"),
+            Instruction::ZeroUpper => write!(f, "zero upper"),
+            Instruction::Label(label) => write!(f, "{:?}:", label),
         }
     }
 }
@@ -146,24 +413,116 @@ impl fmt::Debug for Move {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Move::FloatFromMemory { dest, src } => {
-                write!(f, "%xmm{:?} = {:?}", Into::<i64>::into(*dest), src)
+                write!(f, "%xmm{:?} = {:?}", i64::from(*dest), src)
             }
             Move::FloatToMemory { dest, src } => {
-                write!(f, "{:?} = %xmm{:?}", dest, Into::<i64>::into(*src))
+                write!(f, "{:?} = %xmm{:?}", dest, i64::from(*src))
             }
             Move::FloatToFloat { dest, src } => {
-                write!(
-                    f,
-                    "%xmm{:?} = %xmm{:?}",
-                    Into::<i64>::into(*dest),
-                    Into::<i64>::into(*src)
-                )
+                write!(f, "%xmm{:?} = %xmm{:?}", i64::from(*dest), i64::from(*src))
             }
             Move::IntegerFromConstant { dest, src } => write!(f, "{:?} = {}", dest, src),
             Move::FloatFromInteger { dest, src } => {
-                write!(f, "%xmm{:?} = {:?}", Into::<i64>::into(*dest), src)
+                write!(f, "%xmm{:?} = {:?}", i64::from(*dest), src)
             }
             Move::IntegerToMemory { dest, src } => write!(f, "{:?} = {:?}", dest, src),
+            Move::BlendWithMask { dest, src } => write!(
+                f,
+                "%xmm{:?} = %xmm{:?} where %xmm0",
+                i64::from(*dest),
+                i64::from(*src)
+            ),
+            Move::PackedFromMemory { dest, src } => {
+                write!(f, "%xmm{:?} = packed {:?}", i64::from(*dest), src)
+            }
+            Move::PackedToMemory { dest, src } => {
+                write!(f, "{:?} = packed %xmm{:?}", dest, i64::from(*src))
+            }
+            Move::PackedToPacked { dest, src } => write!(
+                f,
+                "%xmm{:?} = packed %xmm{:?}",
+                i64::from(*dest),
+                i64::from(*src)
+            ),
+            Move::Shuffle {
+                dest,
+                src,
+                selector,
+            } => write!(
+                f,
+                "%xmm{:?} = shuffle %xmm{:?}, %xmm{:?} by {:#04x}",
+                i64::from(*dest),
+                i64::from(*dest),
+                i64::from(*src),
+                selector
+            ),
+            Move::InterleaveLow { dest, src } => write!(
+                f,
+                "%xmm{:?} = interleave %xmm{:?}, %xmm{:?}",
+                i64::from(*dest),
+                i64::from(*dest),
+                i64::from(*src)
+            ),
+            Move::LowToHigh { dest, src } => write!(
+                f,
+                "%xmm{:?} high = %xmm{:?} low",
+                i64::from(*dest),
+                i64::from(*src)
+            ),
+            Move::WideFromMemory { dest, src } => {
+                write!(f, "%ymm{:?} = {:?}", i64::from(*dest), src)
+            }
+            Move::WideToMemory { dest, src } => {
+                write!(f, "{:?} = %ymm{:?}", dest, i64::from(*src))
+            }
+            Move::WideToWide { dest, src } => {
+                write!(f, "%ymm{:?} = %ymm{:?}", i64::from(*dest), i64::from(*src))
+            }
+            Move::BroadcastFromMemory { dest, src } => {
+                write!(f, "%ymm{:?} = broadcast {:?}", i64::from(*dest), src)
+            }
+            Move::InsertFromMemory {
+                dest,
+                first,
+                src,
+                lane,
+            } => write!(
+                f,
+                "%xmm{:?} = %xmm{:?} with lane {} = {:?}",
+                i64::from(*dest),
+                i64::from(*first),
+                lane,
+                src
+            ),
+            Move::XmmToHigh { dest, first, src } => write!(
+                f,
+                "%ymm{:?} = %ymm{:?} with high = %xmm{:?}",
+                i64::from(*dest),
+                i64::from(*first),
+                i64::from(*src)
+            ),
+            Move::WideBlendWithMask {
+                dest,
+                first,
+                src,
+                mask,
+            } => write!(
+                f,
+                "%ymm{:?} = %ymm{:?} where %ymm{:?} else %ymm{:?}",
+                i64::from(*dest),
+                i64::from(*src),
+                i64::from(*mask),
+                i64::from(*first)
+            ),
+            Move::HighToXmm { dest, src } => write!(
+                f,
+                "%xmm{:?} = %ymm{:?} high",
+                i64::from(*dest),
+                i64::from(*src)
+            ),
+            Move::LaneToMemory { dest, src, lane } => {
+                write!(f, "{:?} = %xmm{:?} lane {}", dest, i64::from(*src), lane)
+            }
         }
     }
 }
@@ -181,6 +540,15 @@ impl fmt::Debug for Memory {
     }
 }
 
+impl fmt::Debug for Base {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Base::R(register) => register.fmt(f),
+            Base::Constant(index) => write!(f, "%rip + constant {}", index),
+        }
+    }
+}
+
 impl fmt::Debug for R {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let str = match self {
@@ -205,9 +573,9 @@ impl fmt::Debug for R {
     }
 }
 
-impl Into<u8> for ScaleFactor {
-    fn into(self) -> u8 {
-        match self {
+impl From<ScaleFactor> for u8 {
+    fn from(scale: ScaleFactor) -> Self {
+        match scale {
             ScaleFactor::S1 => 1,
             ScaleFactor::S2 => 2,
             ScaleFactor::S4 => 4,
@@ -227,6 +595,69 @@ impl fmt::Debug for Arithmetic {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Arithmetic::FloatAssign(float) => float.fmt(f),
+            Arithmetic::PackedAssign(packed) => write!(f, "packed {:?}", packed),
+            Arithmetic::FloatOperation(FloatOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => write!(
+                f,
+                "%xmm{:?} = %xmm{:?} {:?} {:?}",
+                i64::from(*dest),
+                i64::from(*first),
+                operator,
+                value
+            ),
+            Arithmetic::FusedMultiplyAdd(FusedMultiplyAdd { dest, first, value }) => write!(
+                f,
+                "%xmm{:?} += %xmm{:?} * {:?}",
+                i64::from(*dest),
+                i64::from(*first),
+                value
+            ),
+            Arithmetic::WideOperation(FloatOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => {
+                write!(
+                    f,
+                    "%ymm{:?} = %ymm{:?} {:?} ",
+                    i64::from(*dest),
+                    i64::from(*first),
+                    operator
+                )?;
+                match value {
+                    Operand::Xmm(value) => write!(f, "%ymm{:?}", i64::from(*value)),
+                    Operand::Memory(value) => value.fmt(f),
+                }
+            }
+            Arithmetic::WideLogical(LogicalOperation {
+                operator,
+                dest,
+                first,
+                value,
+            }) => write!(
+                f,
+                "%ymm{:?} = %ymm{:?} {:?} %ymm{:?}",
+                i64::from(*dest),
+                i64::from(*first),
+                operator,
+                i64::from(*value)
+            ),
+            Arithmetic::LogicalAssign(LogicalAssign {
+                operator,
+                dest,
+                value,
+            }) => write!(
+                f,
+                "%xmm{:?} {:?}= %xmm{:?}",
+                i64::from(*dest),
+                operator,
+                i64::from(*value)
+            ),
             Arithmetic::IntegerAddAssign(IntegerAssign { dest, value }) => {
                 write!(f, "{:?} += {}", dest, value)
             }
@@ -239,18 +670,67 @@ impl fmt::Debug for Arithmetic {
 
 impl fmt::Debug for FloatAssign {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?} {:?}= {:?}", self.dest, self.operator, self.value)
+        write!(
+            f,
+            "%xmm{:?} {:?}= {:?}",
+            i64::from(self.dest),
+            self.operator,
+            self.value
+        )
+    }
+}
+
+impl fmt::Debug for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Xmm(xmm) => write!(f, "%xmm{:?}", i64::from(*xmm)),
+            Operand::Memory(memory) => memory.fmt(f),
+        }
     }
 }
 
 impl fmt::Debug for Compare {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Compare::CompareFloats { first, second } => write!(
+            Compare::CompareFloats { first, second } => {
+                write!(f, "{:?} vs {:?}", i64::from(*first), i64::from(*second))
+            }
+            Compare::CompareToMask {
+                condition,
+                dest,
+                value,
+            } => write!(
+                f,
+                "%xmm{:?} = %xmm{:?} {:?} %xmm{:?}",
+                i64::from(*dest),
+                i64::from(*dest),
+                condition,
+                i64::from(*value)
+            ),
+            Compare::PackedCompareToMask {
+                condition,
+                dest,
+                value,
+            } => write!(
                 f,
-                "{:?} vs {:?}",
-                Into::<i64>::into(*first),
-                Into::<i64>::into(*second)
+                "%xmm{:?} = packed %xmm{:?} {:?} %xmm{:?}",
+                i64::from(*dest),
+                i64::from(*dest),
+                condition,
+                i64::from(*value)
+            ),
+            Compare::WideCompareToMask {
+                condition,
+                dest,
+                first,
+                value,
+            } => write!(
+                f,
+                "%ymm{:?} = %ymm{:?} {:?} %ymm{:?}",
+                i64::from(*dest),
+                i64::from(*first),
+                condition,
+                i64::from(*value)
             ),
         }
     }
@@ -259,8 +739,50 @@ impl fmt::Debug for Compare {
 impl fmt::Debug for Jump {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Jump::Unconditional { offset } => write!(f, "jump {}", offset),
-            Jump::AboveEqual { offset } => write!(f, "if above, jump {}", offset),
+            Jump::Unconditional { target } => write!(f, "jump {:?}", target),
+            Jump::AboveEqual { target } => write!(f, "if above, jump {:?}", target),
         }
     }
 }
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stack::Push(register) => write!(f, "push {:?}", register),
+            Stack::Pop(register) => write!(f, "pop {:?}", register),
+        }
+    }
+}
+
+impl fmt::Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ".L{}", self.index)
+    }
+}
+
+impl fmt::Debug for Logical {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            Logical::And => "&",
+            Logical::AndNot => "&!",
+            Logical::Or => "|",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl fmt::Debug for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            Condition::Equal => "==",
+            Condition::LessThan => "<",
+            Condition::LessEqual => "<=",
+            Condition::Unordered => "unordered",
+            Condition::NotEqual => "!=",
+            Condition::NotLessThan => "!<",
+            Condition::NotLessEqual => "!<=",
+            Condition::Ordered => "ordered",
+        };
+        write!(f, "{}", str)
+    }
+}
@@ -1,22 +1,107 @@
 use arrayvec::ArrayVec;
 
+#[derive(Default)]
 pub struct Bytes {
     bytes: Vec<u8>,
+    /// The offset the constant pool will be placed at
+    constants: usize,
 }
 
 pub struct InstructionBuilder {
     legacy_prefix: Option<ArrayVec<u8, 4>>,
+    /// The W, R, X and B bits, which go in a REX prefix, or in the VEX prefix if there is one
+    rex: u8,
+    vex: Option<Vex>,
     opcode: ArrayVec<u8, 4>,
     mod_reg_rm: Option<u8>,
     sib: Option<u8>,
     displacement: Option<ArrayVec<u8, 8>>,
+    /// The offset into the constant pool of a %rip relative displacement
+    constant: Option<usize>,
     immediate: Option<ArrayVec<u8, 8>>,
 }
 
+/// The opcode escape bytes implied by a VEX prefix
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpcodeMap {
+    /// `0F`
+    Escape,
+    /// `0F 38`
+    Escape38,
+    /// `0F 3A`
+    Escape3A,
+}
+
+/// The legacy prefix implied by a VEX prefix
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImpliedPrefix {
+    None,
+    /// `66`
+    OperandSize,
+    /// `F3`
+    Repeat,
+    /// `F2`
+    RepeatNotZero,
+}
+
+/// The fields of a VEX prefix other than the REX bits
+#[derive(Copy, Clone)]
+struct Vex {
+    map: OpcodeMap,
+    prefix: ImpliedPrefix,
+    /// The extra source register, 4 bits
+    vvvv: u8,
+    /// Selects 256 bit vectors
+    l: bool,
+}
+
+impl Vex {
+    /// The 2 byte form can only encode the R bit and the `0F` map
+    fn bytes(self, rex: u8) -> ArrayVec<u8, 3> {
+        let [w, r, x, b] = [3, 2, 1, 0].map(|bit| rex & (1 << bit) != 0);
+        let pp = match self.prefix {
+            ImpliedPrefix::None => 0b00,
+            ImpliedPrefix::OperandSize => 0b01,
+            ImpliedPrefix::Repeat => 0b10,
+            ImpliedPrefix::RepeatNotZero => 0b11,
+        };
+        // R, X, B and vvvv are stored inverted
+        let last = (u8::from(w) << 7) | ((!self.vvvv & 0b1111) << 3) | (u8::from(self.l) << 2) | pp;
+
+        if !w && !x && !b && self.map == OpcodeMap::Escape {
+            to_arrayvec([0xc5, (u8::from(!r) << 7) | (last & 0b0111_1111)])
+        } else {
+            let mmmmm = match self.map {
+                OpcodeMap::Escape => 0b00001,
+                OpcodeMap::Escape38 => 0b00010,
+                OpcodeMap::Escape3A => 0b00011,
+            };
+            let middle = (u8::from(!r) << 7) | (u8::from(!x) << 6) | (u8::from(!b) << 5) | mmmmm;
+            to_arrayvec([0xc4, middle, last])
+        }
+    }
+}
+
 impl Bytes {
-    fn push(&mut self, instruction: InstructionBuilder) -> &mut Self {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, mut instruction: InstructionBuilder) -> &mut Self {
+        if let Some(offset) = instruction.constant {
+            // %rip relative displacements are measured from the end of the instruction
+            let end = self.bytes.len() + instruction.len();
+            let displacement = (self.constants + offset) as i64 - end as i64;
+            instruction.displacement(i32::try_from(displacement).unwrap().to_le_bytes());
+        }
+
         self.bytes
             .extend(instruction.legacy_prefix.iter().flatten());
+        match instruction.vex {
+            Some(vex) => self.bytes.extend(vex.bytes(instruction.rex)),
+            None if instruction.rex != 0 => self.bytes.push(0x40 | instruction.rex),
+            None => {}
+        }
         self.bytes.extend(instruction.opcode);
         self.bytes.extend(instruction.mod_reg_rm);
         self.bytes.extend(instruction.sib);
@@ -24,6 +109,32 @@ impl Bytes {
         self.bytes.extend(instruction.immediate.iter().flatten());
         self
     }
+
+    /// Appends raw data that isn't an instruction
+    pub fn data(&mut self, data: impl IntoIterator<Item = u8>) -> &mut Self {
+        self.bytes.extend(data);
+        self
+    }
+
+    /// Sets the offset the constant pool will be placed at, which %rip relative instructions
+    /// pushed afterwards refer to
+    pub fn constants_at(&mut self, offset: usize) -> &mut Self {
+        self.constants = offset;
+        self
+    }
+
+    /// The offset of the constant pool
+    pub fn constants(&self) -> usize {
+        self.constants
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
 }
 
 fn to_arrayvec<const N: usize>(elements: impl IntoIterator<Item = u8>) -> ArrayVec<u8, N> {
@@ -38,14 +149,36 @@ impl InstructionBuilder {
     pub fn new(opcode: impl IntoIterator<Item = u8>) -> Self {
         Self {
             legacy_prefix: None,
+            rex: 0,
+            vex: None,
             opcode: to_arrayvec(opcode),
             mod_reg_rm: None,
             sib: None,
             displacement: None,
+            constant: None,
             immediate: None,
         }
     }
 
+    fn len(&self) -> usize {
+        self.legacy_prefix.as_ref().map_or(0, |prefix| prefix.len())
+            + match self.vex {
+                Some(vex) => vex.bytes(self.rex).len(),
+                None => usize::from(self.rex != 0),
+            }
+            + self.opcode.len()
+            + usize::from(self.mod_reg_rm.is_some())
+            + usize::from(self.sib.is_some())
+            + self
+                .displacement
+                .as_ref()
+                .map_or(0, |displacement| displacement.len())
+            + self
+                .immediate
+                .as_ref()
+                .map_or(0, |immediate| immediate.len())
+    }
+
     /// mod is 2 bits
     /// reg is 3 bits
     /// rm is 3 bits
@@ -54,31 +187,73 @@ impl InstructionBuilder {
         assert!(reg <= 0b111);
         assert!(rm <= 0b111);
 
-        let byte = mod_ << 6 + reg << 3 + rm;
+        let byte = (mod_ << 6) | (reg << 3) | rm;
 
         self.mod_reg_rm = Some(byte);
         self
     }
 
-    /// scale is 2 bytes
-    /// index is 3 bytes
-    /// base is 3 bytes
+    /// scale is 2 bits
+    /// index is 3 bits
+    /// base is 3 bits
     pub fn sib(&mut self, scale: u8, index: u8, base: u8) -> &mut Self {
         assert!(scale <= 0b11);
         assert!(index <= 0b111);
         assert!(base <= 0b111);
 
-        let byte = scale << 6 + index << 3 + base;
+        let byte = (scale << 6) | (index << 3) | base;
 
         self.sib = Some(byte);
         self
     }
 
+    /// Sets the REX prefix, which is only emitted if at least one of the bits is set.
+    /// w selects 64 bit operands
+    /// r extends the reg field of ModR/M
+    /// x extends the index field of SIB
+    /// b extends the rm field of ModR/M, the base field of SIB or the opcode register
+    ///
+    /// If the instruction has a VEX prefix, the bits are stored in it instead.
+    pub fn rex(&mut self, w: bool, r: bool, x: bool, b: bool) -> &mut Self {
+        self.rex = (u8::from(w) << 3) | (u8::from(r) << 2) | (u8::from(x) << 1) | u8::from(b);
+        self
+    }
+
+    /// Replaces the REX prefix and the opcode escape bytes with a VEX prefix, using the 2 byte form
+    /// when the REX bits allow it.
+    /// map is the escape bytes the opcode would otherwise start with
+    /// prefix is the legacy prefix the instruction would otherwise have
+    /// vvvv is an extra source register, 4 bits, or 0 if the instruction has none
+    /// l selects 256 bit vectors
+    pub fn vex(&mut self, map: OpcodeMap, prefix: ImpliedPrefix, vvvv: u8, l: bool) -> &mut Self {
+        assert!(vvvv <= 0b1111);
+        assert!(
+            self.legacy_prefix.is_none(),
+            "VEX instructions can't have legacy prefixes"
+        );
+
+        self.vex = Some(Vex {
+            map,
+            prefix,
+            vvvv,
+            l,
+        });
+        self
+    }
+
     pub fn displacement(&mut self, displacement: impl IntoIterator<Item = u8>) -> &mut Self {
         self.displacement = Some(to_arrayvec(displacement));
         self
     }
 
+    /// Sets a 32 bit displacement pointing `offset` bytes into the constant pool, relative to the
+    /// end of the instruction
+    pub fn constant(&mut self, offset: usize) -> &mut Self {
+        self.constant = Some(offset);
+        self.displacement = Some(to_arrayvec([0; 4]));
+        self
+    }
+
     pub fn immediate(&mut self, immediate: impl IntoIterator<Item = u8>) -> &mut Self {
         self.immediate = Some(to_arrayvec(immediate));
         self
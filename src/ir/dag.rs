@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use super::expr::{Expr, Identity, IfPositive, Operator};
+
+/// The index of a node in a `Dag`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId {
+    pub index: usize,
+}
+
+/// A node of an expression whose children are shared through their ids
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Node {
+    Operation {
+        operator: Operator,
+        operands: [NodeId; 2],
+    },
+    Variable(usize),
+    /// The bits of the number, so that `0` and `-0` are distinct nodes
+    Number(u32),
+    IfPositive {
+        predicate: NodeId,
+        consequent: NodeId,
+        alternative: NodeId,
+    },
+}
+
+/// Hash-consed expression nodes: every structurally distinct subexpression is stored exactly once,
+/// and every node comes after its children.
+#[derive(Default)]
+pub struct Dag {
+    nodes: Vec<Node>,
+    ids: HashMap<Node, NodeId>,
+}
+
+impl Node {
+    pub fn children(&self) -> Vec<NodeId> {
+        match self {
+            Node::Operation { operands, .. } => operands.to_vec(),
+            Node::IfPositive {
+                predicate,
+                consequent,
+                alternative,
+            } => vec![*predicate, *consequent, *alternative],
+            Node::Variable(_) | Node::Number(_) => Vec::new(),
+        }
+    }
+}
+
+impl Dag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node(&self, id: NodeId) -> Node {
+        self.nodes[id.index]
+    }
+
+    /// The id of `node`, adding it if no equal node exists yet
+    pub fn insert(&mut self, node: Node) -> NodeId {
+        if let Some(id) = self.ids.get(&node) {
+            return *id;
+        }
+        let id = NodeId {
+            index: self.nodes.len(),
+        };
+        self.nodes.push(node);
+        self.ids.insert(node, id);
+        id
+    }
+
+    /// Adds `expr` to the dag, merging every subexpression with any equal one already present.
+    /// Subexpressions shared through an `Rc` are only visited once.
+    pub fn add(&mut self, expr: &Expr) -> NodeId {
+        self.add_memoised(expr, &mut HashMap::new())
+    }
+
+    fn add_memoised(&mut self, expr: &Expr, visited: &mut HashMap<Identity, NodeId>) -> NodeId {
+        let identity = expr.identity();
+        if let Some(id) = identity.and_then(|identity| visited.get(&identity)) {
+            return *id;
+        }
+
+        let node = match expr {
+            Expr::Operation { operator, operands } => Node::Operation {
+                operator: *operator,
+                operands: [
+                    self.add_memoised(&operands[0], visited),
+                    self.add_memoised(&operands[1], visited),
+                ],
+            },
+            Expr::Variable(index) => Node::Variable(*index),
+            Expr::Number(number) => Node::Number(number.to_bits()),
+            Expr::IfPositive(if_positive) => Node::IfPositive {
+                predicate: self.add_memoised(&if_positive.predicate, visited),
+                consequent: self.add_memoised(&if_positive.consequent, visited),
+                alternative: self.add_memoised(&if_positive.alternative, visited),
+            },
+        };
+
+        let id = self.insert(node);
+        if let Some(identity) = identity {
+            visited.insert(identity, id);
+        }
+        id
+    }
+
+    /// Rebuilds the expression at `root`, sharing every node that is used more than once
+    pub fn to_expr(&self, root: NodeId) -> Expr {
+        let mut exprs: Vec<Option<Expr>> = vec![None; self.nodes.len()];
+        for index in 0..=root.index {
+            let expr = |id: NodeId| exprs[id.index].clone().unwrap();
+            let built = match self.nodes[index] {
+                Node::Operation { operator, operands } => Expr::Operation {
+                    operator,
+                    operands: Rc::new([expr(operands[0]), expr(operands[1])]),
+                },
+                Node::Variable(index) => Expr::Variable(index),
+                Node::Number(bits) => Expr::Number(f32::from_bits(bits)),
+                Node::IfPositive {
+                    predicate,
+                    consequent,
+                    alternative,
+                } => Expr::IfPositive(Rc::new(IfPositive {
+                    predicate: expr(predicate),
+                    consequent: expr(consequent),
+                    alternative: expr(alternative),
+                })),
+            };
+            exprs[index] = Some(built);
+        }
+        exprs[root.index].take().unwrap()
+    }
+
+    /// The number of times each node is used as a child of a node reachable from `roots`, with
+    /// every root counting as one use.
+    pub fn uses(&self, roots: &[NodeId]) -> Vec<usize> {
+        let mut uses = vec![0; self.nodes.len()];
+        let mut reachable = vec![false; self.nodes.len()];
+        for root in roots {
+            uses[root.index] += 1;
+            reachable[root.index] = true;
+        }
+        // children always come before their parents
+        for index in (0..self.nodes.len()).rev() {
+            if reachable[index] {
+                for child in self.nodes[index].children() {
+                    uses[child.index] += 1;
+                    reachable[child.index] = true;
+                }
+            }
+        }
+        uses
+    }
+
+    /// The addend and factors of `id` if it is a sum with a `fusable` product as an operand,
+    /// preferring the second operand as the product
+    pub fn multiply_add(
+        &self,
+        id: NodeId,
+        fusable: impl Fn(NodeId) -> bool,
+    ) -> Option<(NodeId, [NodeId; 2])> {
+        let Node::Operation {
+            operator: Operator::Add,
+            operands: [first, second],
+        } = self.node(id)
+        else {
+            return None;
+        };
+        [(first, second), (second, first)]
+            .into_iter()
+            .filter(|(_, product)| fusable(*product))
+            .find_map(|(addend, product)| match self.node(product) {
+                Node::Operation {
+                    operator: Operator::Multiply,
+                    operands,
+                } => Some((addend, operands)),
+                _ => None,
+            })
+    }
+}
+
+/// Merges structurally equal subexpressions of `expr`, so that each is shared rather than repeated
+pub fn eliminate_common_subexpressions(expr: &Expr) -> Expr {
+    let mut dag = Dag::new();
+    let root = dag.add(expr);
+    dag.to_expr(root)
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}", self.index)
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Operation { operator, operands } => {
+                write!(f, "{:?} {:?} {:?}", operands[0], operator, operands[1])
+            }
+            Node::Variable(index) => write!(f, "%{}", index),
+            Node::Number(bits) => write!(f, "{}", f32::from_bits(*bits)),
+            Node::IfPositive {
+                predicate,
+                consequent,
+                alternative,
+            } => write!(
+                f,
+                "if {:?} then {:?} else {:?}",
+                predicate, consequent, alternative
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for Dag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.nodes
+                    .iter()
+                    .enumerate()
+                    .map(|(index, node)| (NodeId { index }, node)),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::neurons::{learning::layer, neuron::Neuron};
+
+    #[test]
+    fn equal_subexpressions_merge() {
+        let x = || Expr::Variable(0);
+        let y = || Expr::Variable(1);
+        // built separately, so nothing is shared through an `Rc`
+        let expr = (x() * y()) + (x() * y()) / (y() * x());
+
+        let mut dag = Dag::new();
+        let root = dag.add(&expr);
+        // x, y, x * y, y * x, (x * y) / (y * x) and the sum
+        assert_eq!(dag.len(), 6);
+        assert_eq!(dag.uses(&[root])[dag.add(&(x() * y())).index], 2);
+
+        // signed zeros are different numbers
+        dag.add(&(Expr::Number(0.0) + Expr::Number(-0.0)));
+        assert_eq!(dag.len(), 9);
+    }
+
+    #[test]
+    fn deep_networks_stay_small() {
+        let mut neuron_nodes = Vec::new();
+        for depth in 1..=6 {
+            let mut values: Vec<_> = (0..3).map(Expr::Variable).collect();
+            let mut data = (3..).map(Expr::Variable);
+            for _ in 0..depth {
+                let layer = layer(3, 3);
+                let local: Vec<_> = data.by_ref().take(layer.size().data).collect();
+                values = layer.evaluate(&values, &local);
+            }
+
+            let mut dag = Dag::new();
+            for value in &values {
+                dag.add(value);
+            }
+            neuron_nodes.push(dag.len());
+        }
+
+        // every layer adds the same number of nodes
+        let growth: Vec<_> = neuron_nodes.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(
+            growth.windows(2).all(|w| w[0] == w[1]),
+            "{:?}",
+            neuron_nodes
+        );
+    }
+
+    #[test]
+    fn round_trip_shares_nodes() {
+        let x = || Expr::Variable(0);
+        let expr = (x() + Expr::Number(1.0)) * (x() + Expr::Number(1.0));
+        let shared = eliminate_common_subexpressions(&expr);
+
+        let Expr::Operation { operands, .. } = &shared else {
+            panic!("expected an operation, got {:?}", shared);
+        };
+        assert!(operands[0].identity() == operands[1].identity());
+    }
+}
@@ -1,9 +1,10 @@
 use std::fmt;
 use std::ops::{Add, Div, Mul, Rem, Sub};
+use std::rc::Rc;
 
 use crate::math::number::Number;
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Operator {
     Add,
     Subtract,
@@ -14,6 +15,15 @@ impl Operator {
     pub fn is_associative(&self) -> bool {
         *self == Self::Add || *self == Self::Multiply
     }
+
+    pub fn apply(&self, first: f32, second: f32) -> f32 {
+        match self {
+            Operator::Add => first + second,
+            Operator::Subtract => first - second,
+            Operator::Multiply => first * second,
+            Operator::Divide => first / second,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -27,16 +37,36 @@ pub struct IfPositive {
 pub enum Expr {
     Operation {
         operator: Operator,
-        operands: Box<[Expr; 2]>,
+        operands: Rc<[Expr; 2]>,
     },
     Variable(usize),
     Number(f32),
-    IfPositive(Box<IfPositive>),
+    IfPositive(Rc<IfPositive>),
+}
+
+/// Identifies a node of an expression by where it lives, so that subexpressions shared through an
+/// `Rc` are recognised without comparing them. Numbers have no identity.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    Operation(*const [Expr; 2]),
+    IfPositive(*const IfPositive),
+    Variable(usize),
+}
+
+impl Expr {
+    pub fn identity(&self) -> Option<Identity> {
+        match self {
+            Expr::Operation { operands, .. } => Some(Identity::Operation(Rc::as_ptr(operands))),
+            Expr::IfPositive(if_positive) => Some(Identity::IfPositive(Rc::as_ptr(if_positive))),
+            Expr::Variable(index) => Some(Identity::Variable(*index)),
+            Expr::Number(_) => None,
+        }
+    }
 }
 
 impl Number for Expr {
     fn if_positive(self, consequent: Self, alternative: Self) -> Self {
-        Expr::IfPositive(Box::new(IfPositive {
+        Expr::IfPositive(Rc::new(IfPositive {
             predicate: self,
             consequent,
             alternative,
@@ -56,7 +86,7 @@ impl Add<Expr> for Expr {
     fn add(self, rhs: Expr) -> Self::Output {
         Expr::Operation {
             operator: Operator::Add,
-            operands: Box::new([self, rhs]),
+            operands: Rc::new([self, rhs]),
         }
     }
 }
@@ -67,7 +97,7 @@ impl Sub<Expr> for Expr {
     fn sub(self, rhs: Expr) -> Self::Output {
         Expr::Operation {
             operator: Operator::Subtract,
-            operands: Box::new([self, rhs]),
+            operands: Rc::new([self, rhs]),
         }
     }
 }
@@ -78,7 +108,7 @@ impl Mul<Expr> for Expr {
     fn mul(self, rhs: Expr) -> Self::Output {
         Expr::Operation {
             operator: Operator::Multiply,
-            operands: Box::new([self, rhs]),
+            operands: Rc::new([self, rhs]),
         }
     }
 }
@@ -89,7 +119,7 @@ impl Div<Expr> for Expr {
     fn div(self, rhs: Expr) -> Self::Output {
         Expr::Operation {
             operator: Operator::Divide,
-            operands: Box::new([self, rhs]),
+            operands: Rc::new([self, rhs]),
         }
     }
 }
@@ -136,11 +166,3 @@ impl fmt::Debug for IfPositive {
         )
     }
 }
-
-pub(crate) fn evaluate(
-    expr: &Expr,
-    values: &[f64],
-    index_env: &std::collections::HashMap<usize, f64>,
-) -> f32 {
-    todo!()
-}
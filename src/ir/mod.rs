@@ -1,4 +1,7 @@
 pub mod asm;
 pub mod bytes;
+pub mod dag;
 pub mod expr;
 pub mod register;
+pub mod ssa;
+pub mod target;
@@ -6,7 +6,8 @@ use super::expr;
 pub struct Program {
     pub input: Vec<Register>,
     pub statements: Vec<Statement>,
-    pub output: Value,
+    /// The values written to the output buffer, in order
+    pub output: Vec<Value>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -31,6 +32,11 @@ pub enum Expr {
         operator: expr::Operator,
         operand: Value,
     },
+    /// Adds `first * second` to the destination, rounding once, as `f32::mul_add` does
+    MultiplyAdd {
+        first: Value,
+        second: Value,
+    },
     IfPositive {
         predicate: Value,
         consequent: Value,
@@ -38,6 +44,28 @@ pub enum Expr {
     },
 }
 
+impl Expr {
+    /// The values read by the expression, not including the destination read by an `Operation`
+    /// or a `MultiplyAdd`
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Expr::Move(value) => vec![*value],
+            Expr::Operation { operand, .. } => vec![*operand],
+            Expr::MultiplyAdd { first, second } => vec![*first, *second],
+            Expr::IfPositive {
+                predicate,
+                consequent,
+                alternative,
+            } => vec![*predicate, *consequent, *alternative],
+        }
+    }
+
+    /// Whether the expression modifies its destination in place, rather than overwriting it
+    pub fn reads_destination(&self) -> bool {
+        matches!(self, Expr::Operation { .. } | Expr::MultiplyAdd { .. })
+    }
+}
+
 impl fmt::Debug for Register {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "%{}", self.index)
@@ -64,6 +92,9 @@ impl fmt::Debug for Expr {
         match self {
             Expr::Move(value) => write!(f, "{:?}", value),
             Expr::Operation { operator, operand } => write!(f, "{:?} {:?}", operator, operand),
+            Expr::MultiplyAdd { first, second } => {
+                write!(f, "MultiplyAdd {:?} {:?}", first, second)
+            }
             Expr::IfPositive {
                 predicate,
                 consequent,
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::expr::Operator;
+use super::register::{self, Register, Value};
+
+/// A register program in static single assignment form: every register is written by exactly one
+/// statement, and inputs are never written.
+#[derive(Debug)]
+pub struct Program {
+    pub input: Vec<Register>,
+    pub statements: Vec<Statement>,
+    pub output: Vec<Value>,
+}
+
+pub struct Statement {
+    pub destination: Register,
+    pub expr: Expr,
+}
+
+/// Unlike `register::Expr`, nothing reads its destination, so there is no need for moves
+pub enum Expr {
+    Operation {
+        operator: Operator,
+        operands: [Value; 2],
+    },
+    /// `addend + first * second`, rounded once
+    MultiplyAdd {
+        addend: Value,
+        first: Value,
+        second: Value,
+    },
+    IfPositive {
+        predicate: Value,
+        consequent: Value,
+        alternative: Value,
+    },
+}
+
+impl Expr {
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Expr::Operation { operands, .. } => operands.to_vec(),
+            Expr::MultiplyAdd {
+                addend,
+                first,
+                second,
+            } => vec![*addend, *first, *second],
+            Expr::IfPositive {
+                predicate,
+                consequent,
+                alternative,
+            } => vec![*predicate, *consequent, *alternative],
+        }
+    }
+}
+
+struct RegisterSource {
+    unused: usize,
+}
+
+impl RegisterSource {
+    fn fresh(&mut self) -> Register {
+        let index = self.unused;
+        self.unused += 1;
+        Register { index }
+    }
+}
+
+fn registers(values: impl IntoIterator<Item = Value>) -> impl Iterator<Item = Register> {
+    values.into_iter().filter_map(|value| match value {
+        Value::Register(register) => Some(register),
+        Value::Number(_) => None,
+    })
+}
+
+impl Program {
+    /// Renames every definition of `program` to a fresh register, dropping moves by reading their
+    /// source directly. `program` must pass `compile::verify::verify_allocated`.
+    pub fn from_register(program: &register::Program) -> Self {
+        let unused = program
+            .input
+            .iter()
+            .chain(
+                program
+                    .statements
+                    .iter()
+                    .map(|statement| &statement.destination),
+            )
+            .map(|register| register.index + 1)
+            .max()
+            .unwrap_or(0);
+        let mut registers = RegisterSource { unused };
+
+        // the value each register of `program` holds at the current statement
+        let mut current: HashMap<Register, Value> = program
+            .input
+            .iter()
+            .map(|register| (*register, Value::Register(*register)))
+            .collect();
+        let read = |current: &HashMap<Register, Value>, value: Value| match value {
+            Value::Register(register) => *current
+                .get(&register)
+                .unwrap_or_else(|| panic!("{:?} is read before it is written", register)),
+            Value::Number(_) => value,
+        };
+
+        let mut statements = Vec::new();
+        for statement in &program.statements {
+            let expr = match statement.expr {
+                register::Expr::Move(value) => {
+                    let value = read(&current, value);
+                    current.insert(statement.destination, value);
+                    continue;
+                }
+                register::Expr::Operation { operator, operand } => Expr::Operation {
+                    operator,
+                    operands: [
+                        read(&current, Value::Register(statement.destination)),
+                        read(&current, operand),
+                    ],
+                },
+                register::Expr::MultiplyAdd { first, second } => Expr::MultiplyAdd {
+                    addend: read(&current, Value::Register(statement.destination)),
+                    first: read(&current, first),
+                    second: read(&current, second),
+                },
+                register::Expr::IfPositive {
+                    predicate,
+                    consequent,
+                    alternative,
+                } => Expr::IfPositive {
+                    predicate: read(&current, predicate),
+                    consequent: read(&current, consequent),
+                    alternative: read(&current, alternative),
+                },
+            };
+
+            let destination = registers.fresh();
+            current.insert(statement.destination, Value::Register(destination));
+            statements.push(Statement { destination, expr });
+        }
+
+        Self {
+            input: program.input.clone(),
+            output: program
+                .output
+                .iter()
+                .map(|value| read(&current, *value))
+                .collect(),
+            statements,
+        }
+    }
+
+    /// Lowers the program to two-address form. An operation is done in place on its first operand
+    /// (or its second, if the operator is associative) when that operand isn't an input and
+    /// nothing reads it afterwards, and on a copy of it otherwise. A multiply add is done in place
+    /// on its addend in the same way.
+    pub fn to_register(&self) -> register::Program {
+        // the index of the last statement reading each register, with outputs read after them all
+        let mut last_use = HashMap::new();
+        for (index, statement) in self.statements.iter().enumerate() {
+            for register in registers(statement.expr.operands()) {
+                last_use.insert(register, index);
+            }
+        }
+        for register in registers(self.output.iter().copied()) {
+            last_use.insert(register, self.statements.len());
+        }
+
+        // the register each ssa register ended up in
+        let mut names: HashMap<Register, Register> = HashMap::new();
+        let name = |names: &HashMap<Register, Register>, value: Value| match value {
+            Value::Register(register) => {
+                Value::Register(names.get(&register).copied().unwrap_or(register))
+            }
+            Value::Number(_) => value,
+        };
+        let dies = |value: Value, index: usize| match value {
+            Value::Register(register) => {
+                !self.input.contains(&register) && last_use.get(&register) == Some(&index)
+            }
+            Value::Number(_) => false,
+        };
+
+        let mut statements = Vec::new();
+        for (index, statement) in self.statements.iter().enumerate() {
+            match statement.expr {
+                Expr::Operation {
+                    operator,
+                    operands: [a, b],
+                } => {
+                    let (destination, operand) = if dies(a, index) {
+                        (name(&names, a), b)
+                    } else if operator.is_associative() && dies(b, index) {
+                        (name(&names, b), a)
+                    } else {
+                        statements.push(register::Statement {
+                            destination: statement.destination,
+                            expr: register::Expr::Move(name(&names, a)),
+                        });
+                        (Value::Register(statement.destination), b)
+                    };
+                    let Value::Register(destination) = destination else {
+                        unreachable!("only registers die");
+                    };
+
+                    statements.push(register::Statement {
+                        destination,
+                        expr: register::Expr::Operation {
+                            operator,
+                            operand: name(&names, operand),
+                        },
+                    });
+                    names.insert(statement.destination, destination);
+                }
+                Expr::MultiplyAdd {
+                    addend,
+                    first,
+                    second,
+                } => {
+                    let destination = if dies(addend, index) {
+                        name(&names, addend)
+                    } else {
+                        statements.push(register::Statement {
+                            destination: statement.destination,
+                            expr: register::Expr::Move(name(&names, addend)),
+                        });
+                        Value::Register(statement.destination)
+                    };
+                    let Value::Register(destination) = destination else {
+                        unreachable!("only registers die");
+                    };
+
+                    statements.push(register::Statement {
+                        destination,
+                        expr: register::Expr::MultiplyAdd {
+                            first: name(&names, first),
+                            second: name(&names, second),
+                        },
+                    });
+                    names.insert(statement.destination, destination);
+                }
+                Expr::IfPositive {
+                    predicate,
+                    consequent,
+                    alternative,
+                } => statements.push(register::Statement {
+                    destination: statement.destination,
+                    expr: register::Expr::IfPositive {
+                        predicate: name(&names, predicate),
+                        consequent: name(&names, consequent),
+                        alternative: name(&names, alternative),
+                    },
+                }),
+            }
+        }
+
+        register::Program {
+            input: self.input.clone(),
+            statements,
+            output: self
+                .output
+                .iter()
+                .map(|value| name(&names, *value))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} = {:?}", self.destination, self.expr)
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Operation { operator, operands } => {
+                write!(f, "{:?} {:?} {:?}", operands[0], operator, operands[1])
+            }
+            Expr::MultiplyAdd {
+                addend,
+                first,
+                second,
+            } => write!(f, "{:?} + {:?} * {:?}", addend, first, second),
+            Expr::IfPositive {
+                predicate,
+                consequent,
+                alternative,
+            } => write!(
+                f,
+                "if {:?} >= 0 then {:?} else {:?}",
+                predicate, consequent, alternative
+            ),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use super::asm::{Arithmetic, Compare, Instruction, Move};
+
+/// The instruction set extensions emitted code may use, from least to most capable. Each tier
+/// includes every extension of the tiers before it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Target {
+    /// Every x86-64 cpu has SSE2
+    #[default]
+    Sse2,
+    /// Adds `blendvps`
+    Sse41,
+    /// Adds three operand VEX encoded arithmetic
+    Avx,
+    /// Adds fused multiply adds
+    Avx2Fma,
+    /// Detected separately, but an alias for `Avx2Fma` for now, since nothing is emitted with an
+    /// EVEX prefix
+    Avx512,
+}
+
+impl Target {
+    /// Every tier, from least to most capable
+    pub const ALL: [Target; 5] = [
+        Target::Sse2,
+        Target::Sse41,
+        Target::Avx,
+        Target::Avx2Fma,
+        Target::Avx512,
+    ];
+
+    /// The most capable tier the current cpu supports. `is_x86_feature_detected` reads the
+    /// extensions with CPUID, and also checks that the operating system saves the wider registers
+    /// that AVX needs.
+    pub fn detect() -> Target {
+        let fma = is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma");
+        if fma && is_x86_feature_detected!("avx512f") {
+            Target::Avx512
+        } else if fma {
+            Target::Avx2Fma
+        } else if is_x86_feature_detected!("avx") {
+            Target::Avx
+        } else if is_x86_feature_detected!("sse4.1") {
+            Target::Sse41
+        } else {
+            Target::Sse2
+        }
+    }
+
+    /// The tiers the current cpu can execute
+    pub fn supported() -> Vec<Target> {
+        let detected = Target::detect();
+        Target::ALL
+            .into_iter()
+            .filter(|target| *target <= detected)
+            .collect()
+    }
+
+    pub fn has_sse41(self) -> bool {
+        self >= Target::Sse41
+    }
+
+    pub fn has_avx(self) -> bool {
+        self >= Target::Avx
+    }
+
+    pub fn has_fma(self) -> bool {
+        self >= Target::Avx2Fma
+    }
+
+    /// The least capable tier that has `instruction`
+    pub fn required(instruction: &Instruction) -> Target {
+        match instruction {
+            Instruction::Move(Move::BlendWithMask { .. }) => Target::Sse41,
+            Instruction::ArithmeticOperation(
+                Arithmetic::FloatOperation(_)
+                | Arithmetic::WideOperation(_)
+                | Arithmetic::WideLogical(_),
+            )
+            | Instruction::Move(
+                Move::WideFromMemory { .. }
+                | Move::WideToMemory { .. }
+                | Move::WideToWide { .. }
+                | Move::BroadcastFromMemory { .. }
+                | Move::InsertFromMemory { .. }
+                | Move::XmmToHigh { .. }
+                | Move::WideBlendWithMask { .. }
+                | Move::HighToXmm { .. }
+                | Move::LaneToMemory { .. },
+            )
+            | Instruction::Compare(Compare::WideCompareToMask { .. })
+            | Instruction::ZeroUpper => Target::Avx,
+            Instruction::ArithmeticOperation(Arithmetic::FusedMultiplyAdd(_)) => Target::Avx2Fma,
+            _ => Target::Sse2,
+        }
+    }
+
+    pub fn allows(self, instruction: &Instruction) -> bool {
+        Target::required(instruction) <= self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::asm::{FloatAssign, FloatOperation, FusedMultiplyAdd, Operand, Xmm};
+    use crate::ir::expr::Operator;
+
+    fn xmm(index: i64) -> Xmm {
+        index.try_into().unwrap()
+    }
+
+    #[test]
+    fn tiers_are_ordered() {
+        assert!(Target::ALL.windows(2).all(|pair| pair[0] < pair[1]));
+        for target in Target::ALL {
+            assert_eq!(target.has_sse41(), target >= Target::Sse41);
+            assert_eq!(target.has_avx(), target >= Target::Avx);
+            assert_eq!(target.has_fma(), target >= Target::Avx2Fma);
+        }
+
+        // every cpu supports the lowest tiers, up to the one it has
+        let supported = Target::supported();
+        assert_eq!(supported, Target::ALL[..supported.len()]);
+        assert_eq!(supported.last(), Some(&Target::detect()));
+    }
+
+    #[test]
+    fn tiers_allow_instructions() {
+        let operation = |operator| FloatOperation {
+            operator,
+            dest: xmm(0),
+            first: xmm(1),
+            value: Operand::Xmm(xmm(2)),
+        };
+        let instructions = [
+            (
+                Instruction::ArithmeticOperation(Arithmetic::FloatAssign(FloatAssign {
+                    operator: Operator::Add,
+                    dest: xmm(0),
+                    value: Operand::Xmm(xmm(1)),
+                })),
+                Target::Sse2,
+            ),
+            (
+                Instruction::Move(Move::BlendWithMask {
+                    dest: xmm(1),
+                    src: xmm(2),
+                }),
+                Target::Sse41,
+            ),
+            (
+                Instruction::ArithmeticOperation(Arithmetic::FloatOperation(operation(
+                    Operator::Multiply,
+                ))),
+                Target::Avx,
+            ),
+            (
+                Instruction::ArithmeticOperation(Arithmetic::WideOperation(operation(
+                    Operator::Divide,
+                ))),
+                Target::Avx,
+            ),
+            (Instruction::ZeroUpper, Target::Avx),
+            (
+                Instruction::ArithmeticOperation(Arithmetic::FusedMultiplyAdd(FusedMultiplyAdd {
+                    dest: xmm(0),
+                    first: xmm(1),
+                    value: Operand::Xmm(xmm(2)),
+                })),
+                Target::Avx2Fma,
+            ),
+        ];
+
+        for (instruction, required) in &instructions {
+            assert_eq!(
+                Target::required(instruction),
+                *required,
+                "{:?}",
+                instruction
+            );
+            for target in Target::ALL {
+                assert_eq!(target.allows(instruction), target >= *required);
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::io;
+
+use crate::compile::assemble::Assemblable;
+use crate::compile::emit::{self, Options};
+use crate::compile::simplify::{simplify, Rules};
+use crate::compile::{flatten, peephole, register_alloc, vectorise, verify};
+use crate::ir::bytes::Bytes;
+use crate::ir::expr::Expr;
+use crate::ir::register::Register;
+use crate::ir::target::Target;
+
+use super::executable::Function;
+
+/// Compiles `exprs` over the variables `0..variables` into a native function taking them in order
+/// and writing the value of each expression in order, using whatever the current cpu has
+pub fn compile(exprs: &[Expr], variables: usize) -> io::Result<Function> {
+    let exprs: Vec<_> = exprs
+        .iter()
+        .map(|expr| simplify(expr, &Rules::default()))
+        .collect();
+    let registers = (0..variables).map(|index| Register { index }).collect();
+    let mut program = flatten::to_program(&exprs, registers);
+    peephole::optimise(&mut program);
+    debug_assert_eq!(verify::verify(&program), Ok(()));
+    register_alloc::realloc(&mut program);
+    debug_assert_eq!(verify::verify_allocated(&program), Ok(()));
+
+    let assignment = register_alloc::assign(&program, &emit::allocatable());
+    let options = Options::for_target(Target::detect());
+    let assembly = emit::emit_program(&program, &assignment, &options);
+    let mut bytes = Bytes::new();
+    assembly.assemble(&mut bytes);
+
+    // SAFETY: emitted programs follow `Signature`, and read only their inputs and write only their
+    // outputs
+    unsafe { Function::new(&bytes, variables, exprs.len()) }
+}
+
+/// Like `compile`, but evaluates the expressions a tree at a time with `vectorise::emit_exprs`,
+/// packing trees of the same shape into the lanes of packed instructions
+pub fn compile_vectorised(
+    exprs: &[Expr],
+    variables: usize,
+    options: &vectorise::Options,
+) -> io::Result<Function> {
+    let exprs: Vec<_> = exprs
+        .iter()
+        .map(|expr| simplify(expr, &Rules::default()))
+        .collect();
+    let assembly = vectorise::emit_exprs(&exprs, options);
+    let mut bytes = Bytes::new();
+    assembly.assemble(&mut bytes);
+
+    // SAFETY: as for `compile`
+    unsafe { Function::new(&bytes, variables, exprs.len()) }
+}
@@ -0,0 +1,176 @@
+use std::{io, ptr};
+
+use crate::ir::bytes::Bytes;
+
+/// A region of anonymous memory holding machine code that may be executed.
+/// The mapping is writable only while the code is being copied in, and is unmapped on drop.
+pub struct Executable {
+    pointer: *mut libc::c_void,
+    length: usize,
+}
+
+/// The signature of an assembled program: a pointer to the input vector and a pointer to the
+/// buffer the outputs are stored to.
+pub type Signature = extern "C" fn(*const f32, *mut f32);
+
+/// Machine code following [`Signature`], along with the number of inputs it reads and outputs it
+/// writes.
+pub struct Function {
+    executable: Executable,
+    inputs: usize,
+    outputs: usize,
+}
+
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    size.try_into().expect("invalid page size")
+}
+
+impl Executable {
+    pub fn new(bytes: &Bytes) -> io::Result<Self> {
+        let page_size = page_size();
+        let length = bytes.len().max(1).div_ceil(page_size) * page_size;
+
+        // SAFETY: an anonymous private mapping does not alias any existing memory
+        let pointer = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if pointer == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let executable = Self { pointer, length };
+
+        // SAFETY: the mapping is writable and at least `bytes.len()` long
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_slice().as_ptr(), pointer.cast::<u8>(), bytes.len());
+        }
+
+        // SAFETY: `pointer` and `length` describe exactly the mapping created above
+        if unsafe { libc::mprotect(pointer, length, libc::PROT_READ | libc::PROT_EXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(executable)
+    }
+
+    /// The address of the first byte of code
+    pub fn entry(&self) -> *const u8 {
+        self.pointer.cast()
+    }
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        // SAFETY: `pointer` and `length` describe a mapping owned by `self`
+        unsafe {
+            libc::munmap(self.pointer, self.length);
+        }
+    }
+}
+
+impl Function {
+    /// # Safety
+    /// `bytes` must be a complete function following the System V calling convention with the type
+    /// [`Signature`], which reads at most `inputs` floats from its first argument and writes at
+    /// most `outputs` floats to its second.
+    pub unsafe fn new(bytes: &Bytes, inputs: usize, outputs: usize) -> io::Result<Self> {
+        Ok(Self {
+            executable: Executable::new(bytes)?,
+            inputs,
+            outputs,
+        })
+    }
+
+    pub fn pointer(&self) -> Signature {
+        // SAFETY: guaranteed by the contract of `Function::new`
+        unsafe { std::mem::transmute::<*const u8, Signature>(self.executable.entry()) }
+    }
+
+    pub fn call(&self, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![f32::NAN; self.outputs];
+        self.call_into(input, &mut output);
+        output
+    }
+
+    /// Calls the function, writing its outputs to `output` rather than allocating a new buffer
+    pub fn call_into(&self, input: &[f32], output: &mut [f32]) {
+        assert_eq!(
+            input.len(),
+            self.inputs,
+            "function called with the wrong number of inputs"
+        );
+        assert_eq!(
+            output.len(),
+            self.outputs,
+            "function called with the wrong number of outputs"
+        );
+        (self.pointer())(input.as_ptr(), output.as_mut_ptr())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compile::assemble::Assemblable;
+    use crate::ir::asm::{
+        Arithmetic, Base, FloatAssign, Instruction, Memory, Move, Operand, Program, R,
+    };
+    use crate::ir::expr::Operator;
+    use crate::ir::target::Target;
+
+    #[test]
+    fn add_inputs() {
+        let first = 0.try_into().unwrap();
+        let second = 1.try_into().unwrap();
+        let load = |dest, displacement| {
+            Instruction::Move(Move::FloatFromMemory {
+                dest,
+                src: Memory {
+                    displacement,
+                    base: Base::R(R::Rdi),
+                    index: None,
+                },
+            })
+        };
+        let program = Program {
+            instructions: vec![
+                load(first, 0),
+                load(second, 4),
+                Instruction::ArithmeticOperation(Arithmetic::FloatAssign(FloatAssign {
+                    operator: Operator::Add,
+                    dest: first,
+                    value: Operand::Xmm(second),
+                })),
+                Instruction::Move(Move::FloatToMemory {
+                    dest: Memory {
+                        displacement: 0,
+                        base: Base::R(R::Rsi),
+                        index: None,
+                    },
+                    src: first,
+                }),
+                Instruction::Return,
+            ],
+            constants: Vec::new(),
+            target: Target::default(),
+        };
+
+        let mut bytes = Bytes::new();
+        program.assemble(&mut bytes);
+
+        let function = unsafe { Function::new(&bytes, 2, 1) }.unwrap();
+
+        assert_eq!(function.call(&[1.5, 2.25]), [3.75]);
+        assert_eq!(function.call(&[-1.0, 0.5]), [-0.5]);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod compile;
+pub mod executable;
+pub mod training;
@@ -0,0 +1,215 @@
+use std::{io, slice};
+
+use crate::autodiff::reverse::gradient;
+use crate::ir::expr::Expr;
+use crate::math::number::Number;
+use crate::math::vector::{squared_mag, sub, VectorView};
+use crate::neurons::neuron::Neuron;
+
+use super::compile::compile;
+use super::executable::Function;
+
+/// The sum of the squared differences between `output` and `target`
+pub fn squared_error<T: Number>(output: VectorView<T>, target: VectorView<T>) -> T {
+    squared_mag(&sub(output, target))
+}
+
+/// The loss of a neuron and the updated value of each of its parameters after one step of
+/// gradient descent, as expressions over the variables laid out by `TrainingStep::variables`.
+struct Expressions {
+    loss: Expr,
+    updates: Vec<Expr>,
+}
+
+impl Expressions {
+    fn new(
+        neuron: &impl Neuron,
+        loss: impl Fn(VectorView<Expr>, VectorView<Expr>) -> Expr,
+    ) -> Self {
+        let size = neuron.size();
+        let variable = |offset: usize| move |index| Expr::Variable(offset + index);
+
+        let data: Vec<_> = (0..size.data).map(variable(0)).collect();
+        let input: Vec<_> = (0..size.input).map(variable(size.data)).collect();
+        let target: Vec<_> = (0..size.output)
+            .map(variable(size.data + size.input))
+            .collect();
+        let learning_rate = Expr::Variable(size.data + size.input + size.output);
+
+        let loss = loss(&neuron.evaluate(&input, &data), &target);
+        let parameters: Vec<_> = (0..size.data).collect();
+        let updates = gradient(&loss, &parameters)
+            .into_iter()
+            .zip(data)
+            .map(|(derivative, parameter)| parameter - learning_rate.clone() * derivative)
+            .collect();
+
+        Self { loss, updates }
+    }
+}
+
+/// One step of gradient descent on a neuron, compiled to native code.
+///
+/// The forward and backward passes are differentiated symbolically once, and the loss and every
+/// updated parameter are compiled into a single function, so that the work they share is done once.
+pub struct TrainingStep {
+    data: usize,
+    input: usize,
+    output: usize,
+    loss: Function,
+    /// Writes the loss, followed by the updated value of each parameter
+    step: Function,
+}
+
+impl TrainingStep {
+    pub fn new(
+        neuron: &impl Neuron,
+        loss: impl Fn(VectorView<Expr>, VectorView<Expr>) -> Expr,
+    ) -> io::Result<Self> {
+        let size = neuron.size();
+        let variables = size.data + size.input + size.output + 1;
+        let expressions = Expressions::new(neuron, loss);
+        let mut outputs = vec![expressions.loss.clone()];
+        outputs.extend(expressions.updates);
+
+        Ok(Self {
+            data: size.data,
+            input: size.input,
+            output: size.output,
+            loss: compile(slice::from_ref(&expressions.loss), variables)?,
+            step: compile(&outputs, variables)?,
+        })
+    }
+
+    /// The inputs of the compiled functions: the data, then the input, target and learning rate
+    fn variables(
+        &self,
+        data: &[f32],
+        input: &[f32],
+        target: &[f32],
+        learning_rate: f32,
+    ) -> Vec<f32> {
+        assert_eq!(data.len(), self.data, "wrong number of parameters");
+        assert_eq!(input.len(), self.input, "wrong number of inputs");
+        assert_eq!(target.len(), self.output, "wrong number of targets");
+
+        let mut variables = Vec::with_capacity(self.data + self.input + self.output + 1);
+        variables.extend_from_slice(data);
+        variables.extend_from_slice(input);
+        variables.extend_from_slice(target);
+        variables.push(learning_rate);
+        variables
+    }
+
+    /// The loss of the neuron with parameters `data` on one example
+    pub fn loss(&self, data: &[f32], input: &[f32], target: &[f32]) -> f32 {
+        self.loss.call(&self.variables(data, input, target, 0.0))[0]
+    }
+
+    /// Updates `data` by one step of gradient descent on one example, returning the loss before
+    /// the update.
+    pub fn step(&self, data: &mut [f32], input: &[f32], target: &[f32], learning_rate: f32) -> f32 {
+        let variables = self.variables(data, input, target, learning_rate);
+        let output = self.step.call(&variables);
+        data.copy_from_slice(&output[1..]);
+        output[0]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::autodiff::dual;
+    use crate::eval;
+    use crate::harness::generate::spread_values;
+    use crate::neurons::learning::layer;
+
+    #[test]
+    fn step_matches_expressions() {
+        let neuron = layer(2, 2).compose(layer(2, 1));
+        let step = TrainingStep::new(&neuron, squared_error).unwrap();
+        let expressions = Expressions::new(&neuron, squared_error);
+
+        let mut data = spread_values(neuron.size().data, 0);
+        let (input, target, learning_rate) = ([0.5, -0.75], [0.25], 0.125);
+        let env = step
+            .variables(&data, &input, &target, learning_rate)
+            .into_iter()
+            .enumerate()
+            .collect();
+
+        let loss = step.step(&mut data, &input, &target, learning_rate);
+
+        assert_eq!(
+            loss.to_bits(),
+            eval::expr::evaluate(&expressions.loss, &env).to_bits()
+        );
+        for (parameter, update) in data.iter().zip(&expressions.updates) {
+            assert_eq!(
+                parameter.to_bits(),
+                eval::expr::evaluate(update, &env).to_bits()
+            );
+        }
+    }
+
+    #[test]
+    fn step_matches_dual_numbers() {
+        let neuron = layer(2, 2).compose(layer(2, 1));
+        let step = TrainingStep::new(&neuron, squared_error).unwrap();
+
+        let mut data = spread_values(neuron.size().data, 0);
+        let (input, target, learning_rate) = ([0.5, -0.75], [0.25], 0.125);
+
+        let gradient = dual::gradient(&data, |data| {
+            let input = input.map(dual::Duals::from);
+            let target = target.map(dual::Duals::from);
+            squared_error(&neuron.evaluate(&input, data), &target)
+        });
+        let expected: Vec<_> = data
+            .iter()
+            .zip(&gradient)
+            .map(|(parameter, derivative)| parameter - learning_rate * derivative)
+            .collect();
+
+        step.step(&mut data, &input, &target, learning_rate);
+
+        for (actual, expected) in data.iter().zip(&expected) {
+            assert!(
+                (actual - expected).abs() <= 1e-5,
+                "{} != {}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn training_reduces_loss() {
+        let neuron = layer(1, 1);
+        let step = TrainingStep::new(&neuron, squared_error).unwrap();
+        // y = 2x + 1, on inputs where the rectifier passes it through unchanged
+        let examples: Vec<_> = (0..8)
+            .map(|i| {
+                let x = i as f32 / 4.0;
+                ([x], [2.0 * x + 1.0])
+            })
+            .collect();
+        let total_loss = |data: &[f32]| -> f32 {
+            examples
+                .iter()
+                .map(|(input, target)| step.loss(data, input, target))
+                .sum()
+        };
+
+        let mut data = vec![0.5, 0.5];
+        let initial = total_loss(&data);
+        for _ in 0..200 {
+            for (input, target) in &examples {
+                step.step(&mut data, input, target, 0.05);
+            }
+        }
+
+        assert!(total_loss(&data) < initial / 100.0);
+        assert!((data[0] - 2.0).abs() < 0.05 && (data[1] - 1.0).abs() < 0.05);
+    }
+}
@@ -1,22 +1,34 @@
+mod autodiff;
 mod bounded;
 mod compile;
 mod eval;
+mod harness;
 mod ir;
+mod jit;
 mod math;
 mod neurons;
 
+use compile::assemble::Assemblable;
+use compile::colouring::GraphColouring;
+use compile::register_alloc::{Allocator, LinearScan};
+use ir::bytes::Bytes;
 use ir::expr::Expr;
 use ir::register::Register;
+use ir::target::Target;
+use jit::executable::Function;
+use jit::training::{squared_error, TrainingStep};
+use math::number::Number;
 use math::vector::*;
 use neurons::neuron::Neuron;
 
-use eval::{expr, register};
+use eval::register;
 
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use rand::Rng;
 
 use std::collections::HashMap;
+use std::time::Instant;
 
 use crate::neurons::learning::layer;
 
@@ -25,31 +37,6 @@ pub struct Example {
     target_output: Vector<f32>,
 }
 
-fn error(example: &Example, neuron: &impl Neuron, data: VectorView<f32>) -> f32 {
-    squared_mag(&sub(
-        &neuron.evaluate(&example.input, data),
-        &example.target_output,
-    ))
-}
-
-const EPSILON: f32 = 0.0001;
-
-fn grad(example: &Example, neuron: &impl Neuron, data: &mut Vector<f32>) -> Vector<f32> {
-    let mut grad = Vec::new();
-    let base_error = error(example, neuron, data);
-    let len = data.len();
-    for i in 0..len {
-        data[i] += EPSILON;
-
-        let derivative = (error(example, neuron, data) - base_error) / EPSILON;
-
-        grad.push(derivative);
-
-        data[i] -= EPSILON;
-    }
-    grad
-}
-
 fn train(
     examples: &[Example],
     neuron: &impl Neuron,
@@ -60,20 +47,121 @@ fn train(
     let mut data: Vec<_> = (0..neuron.size().data)
         .map(|_| rng.gen_range(-1.0..1.0))
         .collect();
+    let step = TrainingStep::new(neuron, squared_error).unwrap();
+    let mean_loss = |data: &[f32]| {
+        examples
+            .iter()
+            .map(|example| step.loss(data, &example.input, &example.target_output))
+            .sum::<f32>()
+            / examples.len() as f32
+    };
+    let initial_loss = mean_loss(&data);
 
-    for iter in 0..iterations {
+    for _ in 0..iterations {
         let example = examples.choose(&mut rng).unwrap();
-        let grad = grad(&example, neuron, &mut data);
-        data = sub(&data, &mul(&grad, learning_rate));
+        step.step(
+            &mut data,
+            &example.input,
+            &example.target_output,
+            learning_rate,
+        );
     }
 
+    println!(
+        "mean loss {} before training, {} after {} steps",
+        initial_loss,
+        mean_loss(&data),
+        iterations
+    );
     data
 }
 
+/// The squared error of `neuron` with parameters `data` on `example`, in any kind of number
+fn loss<T: Number>(neuron: &impl Neuron, example: &Example, data: VectorView<T>) -> T {
+    let numbers = |values: &[f32]| {
+        values
+            .iter()
+            .map(|value| T::from(*value))
+            .collect::<Vec<_>>()
+    };
+    squared_error(
+        &neuron.evaluate(&numbers(&example.input), data),
+        &numbers(&example.target_output),
+    )
+}
+
+/// Times a wide layer compiled with allocated registers, a tree at a time, packed, and packed into
+/// ymm registers if the cpu has AVX
+fn benchmark_layer(rng: &mut impl Rng) {
+    let neuron = layer(64, 64);
+    let size = neuron.size();
+    let variables = size.data + size.input;
+    let exprs = neuron.evaluate(
+        &(size.data..variables)
+            .map(Expr::Variable)
+            .collect::<Vec<_>>(),
+        &(0..size.data).map(Expr::Variable).collect::<Vec<_>>(),
+    );
+    let input: Vec<f32> = (0..variables).map(|_| rng.gen_range(-1.0..1.0)).collect();
+
+    let options = |lanes| compile::vectorise::Options {
+        lanes,
+        ..compile::vectorise::Options::default()
+    };
+    let mut functions = vec![
+        (
+            "allocated",
+            jit::compile::compile(&exprs, variables).unwrap(),
+        ),
+        (
+            "tree at a time",
+            jit::compile::compile_vectorised(&exprs, variables, &options(1)).unwrap(),
+        ),
+        (
+            "packed",
+            jit::compile::compile_vectorised(
+                &exprs,
+                variables,
+                &options(compile::vectorise::LANES),
+            )
+            .unwrap(),
+        ),
+    ];
+    let target = Target::detect();
+    if target.has_avx() {
+        let options = compile::vectorise::Options {
+            target,
+            ..options(compile::vectorise::WIDE_LANES)
+        };
+        functions.push((
+            "wide",
+            jit::compile::compile_vectorised(&exprs, variables, &options).unwrap(),
+        ));
+    }
+
+    let mut output = vec![0.0; size.output];
+    let iterations = 2000;
+    let mut baseline = None;
+    for (name, function) in &functions {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            function.call_into(&input, &mut output);
+        }
+        let elapsed = start.elapsed() / iterations;
+        let baseline = *baseline.get_or_insert(elapsed);
+        println!(
+            "layer(64, 64) {}: {:?} per call, {:.2}x",
+            name,
+            elapsed,
+            baseline.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
+
 fn main() {
     let mut rng = thread_rng();
-    let neuron = layer(1, 1);
-    let data: Vec<_> = (0..neuron.size().data).map(|i| Expr::Variable(i)).collect();
+    let neuron = layer(2, 2);
+    let data: Vec<_> = (0..neuron.size().data).map(Expr::Variable).collect();
     let input: Vec<_> = (0..neuron.size().input)
         .map(|i| Expr::Variable(i + neuron.size().data))
         .collect();
@@ -91,19 +179,25 @@ fn main() {
         .map(|(index, value)| (Register { index: *index }, *value))
         .collect();
 
-    let expr = neuron.evaluate(&input, &data).pop().unwrap();
-    let original_value = eval::expr::evaluate(&expr, &index_env);
+    let outputs = neuron.evaluate(&input, &data);
+    let original_value: Vec<_> = outputs
+        .iter()
+        .map(|expr| eval::expr::evaluate(expr, &index_env))
+        .collect();
     let mut program =
-        compile::flatten::to_program(&expr, register_env.clone().into_keys().collect());
+        compile::flatten::to_program(&outputs, register_env.clone().into_keys().collect());
     let old_value = register::evaluate(&program, register_env.clone());
-    println!("{:#?} = {}", program, old_value);
-    println!("{:?} = {}", expr, original_value);
+    println!("{:#?} = {:?}", program, old_value);
+    println!("{:?} = {:?}", outputs, original_value);
 
     assert_eq!(
         old_value, original_value,
         "transformation from expression tree to register instructions failed"
     );
 
+    let statistics = compile::peephole::optimise(&mut program);
+    println!("peephole: {:?}", statistics);
+
     let old_input = program.input.clone();
     let registers = compile::register_alloc::realloc(&mut program);
 
@@ -117,7 +211,7 @@ fn main() {
     println!("{:#?}", program);
     let new_value = register::evaluate(&program, new_register_env);
     println!(
-        "\t= {}, {} registers, {} instructions",
+        "\t= {:?}, {} registers, {} instructions",
         new_value,
         registers,
         program.statements.len()
@@ -126,9 +220,106 @@ fn main() {
     assert!(registers < 50);
     assert_eq!(old_value, new_value, "register allocation failed");
 
-    let assembly = compile::emit::emit_program(&program, registers as u32);
+    let allocatable = compile::emit::allocatable();
+    let allocators: [(&str, &dyn Allocator); 2] = [
+        ("linear scan", &LinearScan),
+        ("graph colouring", &GraphColouring::default()),
+    ];
+    for budget in [allocatable.len(), 4, 2] {
+        for (name, allocator) in allocators {
+            let assignment = allocator.assign(&program, &allocatable[..budget]);
+            println!(
+                "{} with {} xmm registers: {} used, {} spilled, {} spilled uses",
+                name,
+                budget,
+                assignment.registers(),
+                assignment.stack_slots,
+                assignment.spilled_uses
+            );
+        }
+    }
+    let assignment = compile::register_alloc::assign(&program, &allocatable);
+    let assembly =
+        compile::emit::emit_program(&program, &assignment, &compile::emit::Options::default());
+
+    print!(
+        "{}",
+        compile::print::print(&assembly, "neuron", compile::print::Syntax::Att)
+    );
+
+    let mut bytes = Bytes::new();
+    assembly.assemble(&mut bytes);
+    print!(
+        "{}",
+        compile::disassemble::listing(bytes.as_slice(), bytes.constants())
+    );
+    let decoded =
+        compile::disassemble::decode_program(bytes.as_slice(), bytes.constants()).unwrap();
+    let mut reassembled = Bytes::new();
+    decoded.assemble(&mut reassembled);
+    assert_eq!(
+        reassembled.as_slice(),
+        bytes.as_slice(),
+        "disassembly failed"
+    );
+    let function =
+        unsafe { Function::new(&bytes, program.input.len(), program.output.len()) }.unwrap();
+    let native_input: Vec<_> = old_input.iter().map(|reg| register_env[reg]).collect();
+    let native_value = function.call(&native_input);
+    println!("\t= {:?} natively, {} bytes", native_value, bytes.len());
+
+    assert_eq!(old_value, native_value, "native code generation failed");
 
-    println!("{:#?}", assembly);
+    benchmark_layer(&mut rng);
+
+    if let Err(mismatch) = harness::differential::run(&mut rng, 16) {
+        panic!("evaluation paths disagree on\n{:?}", mismatch);
+    }
+    println!("16 random networks agree on every evaluation path");
+
+    // half the sum and half the difference of the inputs
+    let examples: Vec<_> = (0..64)
+        .map(|_| {
+            let input: Vec<f32> = (0..neuron.size().input)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect();
+            let target_output = vec![(input[0] + input[1]) / 2.0, (input[0] - input[1]) / 2.0];
+            Example {
+                input,
+                target_output,
+            }
+        })
+        .collect();
+    let trained = train(&examples, &neuron, 0.1, 1000);
+
+    let example = &examples[0];
+    let forward = autodiff::dual::gradient(&trained, |data| loss(&neuron, example, data));
+    let parameters: Vec<_> = (0..neuron.size().data).collect();
+    let trained_env: HashMap<_, _> = trained.iter().copied().enumerate().collect();
+    let reverse: Vec<_> = autodiff::reverse::gradient(&loss(&neuron, example, &data), &parameters)
+        .iter()
+        .map(|derivative| eval::expr::evaluate(derivative, &trained_env))
+        .collect();
+    let along = autodiff::dual::directional_derivative(&trained, &forward, |data| {
+        loss(&neuron, example, data)
+    });
+    println!("gradient {:?}, {} along itself", forward, along);
+
+    // the two modes round differently
+    let close = |first: f32, second: f32| {
+        (first - second).abs() <= 1e-4 * first.abs().max(second.abs()).max(1.0)
+    };
+    assert!(
+        forward
+            .iter()
+            .zip(&reverse)
+            .all(|(forward, reverse)| close(*forward, *reverse)),
+        "forward and reverse mode gradients disagree"
+    );
+    assert!(
+        close(along, squared_mag(&forward)),
+        "directional derivative disagrees with the gradient"
+    );
 }
 
 #[cfg(test)]
@@ -143,7 +334,7 @@ mod test {
     fn reg_alloc_identity() {
         let mut rng = thread_rng();
         let neuron = layer(1, 4).compose(layer(4, 4)).compose(layer(4, 1));
-        let data: Vec<_> = (0..neuron.size().data).map(|i| Expr::Variable(i)).collect();
+        let data: Vec<_> = (0..neuron.size().data).map(Expr::Variable).collect();
         let input: Vec<_> = (0..neuron.size().input)
             .map(|i| Expr::Variable(i + neuron.size().data))
             .collect();
@@ -161,13 +352,16 @@ mod test {
             .map(|(index, value)| (Register { index: *index }, *value))
             .collect();
 
-        let expr = neuron.evaluate(&input, &data).pop().unwrap();
-        let original_value = eval::expr::evaluate(&expr, &index_env);
+        let outputs = neuron.evaluate(&input, &data);
+        let original_value: Vec<_> = outputs
+            .iter()
+            .map(|expr| eval::expr::evaluate(expr, &index_env))
+            .collect();
         let mut program =
-            compile::flatten::to_program(&expr, register_env.clone().into_keys().collect());
+            compile::flatten::to_program(&outputs, register_env.clone().into_keys().collect());
         let old_value = register::evaluate(&program, register_env.clone());
-        println!("{:#?} = {}", program, old_value);
-        println!("{:?} = {}", expr, original_value);
+        println!("{:#?} = {:?}", program, old_value);
+        println!("{:?} = {:?}", outputs, original_value);
 
         assert_eq!(
             old_value, original_value,
@@ -186,7 +380,7 @@ mod test {
 
         println!("{:#?}", program);
         let new_value = register::evaluate(&program, new_register_env);
-        println!("\t= {}", new_value);
+        println!("\t= {:?}", new_value);
 
         assert!(registers < 50);
         assert_eq!(old_value, new_value, "register allocation failed");
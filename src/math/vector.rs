@@ -16,7 +16,3 @@ pub fn sub<A: Number>(a: VectorView<A>, b: VectorView<A>) -> Vector<A> {
         .map(|(x, y)| x.clone() - y.clone())
         .collect()
 }
-
-pub fn mul<A: Number>(a: VectorView<A>, b: A) -> Vector<A> {
-    a.iter().map(|x| x.clone() * b.clone()).collect()
-}
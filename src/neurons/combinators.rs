@@ -37,6 +37,7 @@ impl<A: Neuron, B: Neuron> Neuron for Compose<A, B> {
     }
 }
 
+/// `neuron` evaluated `repetitions` times on the same input, each with its own data
 pub struct Repeat<A> {
     pub neuron: A,
     pub repetitions: usize,